@@ -1,26 +1,218 @@
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    net::SocketAddr,
+};
 
-use crate::Environment;
+use crate::{
+    config::Config,
+    proxy_client::{ConnectionState, ProxyConnectionEvent},
+    Environment, RequestBody,
+};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        Extension, WebSocketUpgrade,
+        ConnectInfo, Extension, WebSocketUpgrade,
     },
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
 use futures::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
+use models::protocol::AuthFailedReason;
 use portable_pty::{native_pty_system, CommandBuilder, PtyPair, PtySize};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use tokio::sync::{
+    broadcast,
+    mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+};
+use url::Url;
 
 const PORTALBOX_TERM_CMD_PREFIX: &str = "__portalbox_term_cmd";
+const PORTALBOX_TERM_CMD_JSON_PREFIX: &str = "__portalbox_term_cmd:";
+// `__portalbox_term_cmd_resize:COLSxROWS`, superseded by the json format
+const PORTALBOX_TERM_CMD_LEGACY_RESIZE_PREFIX: &str = "__portalbox_term_cmd_resize:";
+// Pty reads queued up while sending are merged into frames of up to this size
+const TERM_MAX_FRAME_BYTES: usize = 64 * 1024;
+const TERM_SIZE_RANGE: std::ops::RangeInclusive<u16> = 1..=1000;
+pub const LOCAL_SECRET_HEADER: &str = "x-portalbox-local-secret";
+
+pub fn routes(config: &Config) -> Router<RequestBody> {
+    let router = Router::new()
+        .route("/status", get(handle_status))
+        .route("/apps-status", get(handle_apps_status))
+        .route("/signout", post(handle_signout))
+        .route("/proxy-events", get(handle_proxy_events));
+
+    if config.terminal_enabled {
+        router.route("/term-ws", get(handle_term_ws))
+    } else {
+        router
+    }
+}
+
+// Served outside `/api`, for probes that only care whether the tunnel is usable
+pub fn health_routes() -> Router<RequestBody> {
+    Router::new().route("/readyz", get(handle_readyz))
+}
+
+async fn handle_readyz(Extension(env): Extension<Environment>) -> impl IntoResponse {
+    let state = env.proxy_connection_state.lock().await;
+    let auth_failure = env.proxy_auth_failure.lock().await;
+
+    readiness(&state, auth_failure.as_ref())
+}
+
+// 503 until a connection is established with an accepted hello, and again once hellos get
+// rejected, e.g. a revoked token, even if an older connection is still up
+fn readiness(
+    state: &ConnectionState,
+    auth_failure: Option<&AuthFailedReason>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let ready = state.is_ready();
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = serde_json::json!({
+        "ready": ready,
+        "proxy_status": state.status(),
+        "auth": state.auth(),
+        "auth_failure": auth_failure.map(|val| val.to_string()),
+    });
+
+    (code, Json(body))
+}
+
+async fn handle_apps_status(Extension(env): Extension<Environment>) -> impl IntoResponse {
+    Json(env.apps_status.get())
+}
+
+// A new one each start, only readable by who can read the home dir. Remote users reach the
+// dashboard through the home proxy, which connects from loopback too, so the peer address alone
+// doesn't tell them apart from local commands.
+pub async fn write_local_secret(config: &Config) -> Result<SecretString, anyhow::Error> {
+    let secret = uuid::Uuid::new_v4().simple().to_string();
+    crate::utils::write_atomic(config.local_secret_file_path(), &secret).await?;
+    Ok(SecretString::new(secret))
+}
+
+fn is_local_request(peer: SocketAddr, headers: &HeaderMap, local_secret: &SecretString) -> bool {
+    let secret = headers
+        .get(LOCAL_SECRET_HEADER)
+        .and_then(|val| val.to_str().ok());
 
-pub fn routes() -> Router {
-    Router::new().route("/term-ws", get(handle_term_ws))
+    peer.ip().is_loopback() && secret == Some(local_secret.expose_secret().as_str())
+}
+
+#[derive(Deserialize)]
+struct SignOut {
+    server_url: Url,
+}
+
+// Sent by `portalbox reset credentials --server`, only this instance's own server is affected
+async fn handle_signout(
+    Extension(env): Extension<Environment>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(form): Json<SignOut>,
+) -> axum::response::Response {
+    if !is_local_request(peer, &headers, &env.local_secret) {
+        tracing::warn!(%peer, "Rejected signout not sent by a local portalbox command");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let signed_out = form.server_url == env.config.server_url();
+    if signed_out {
+        *env.existing_credential.lock().await = None;
+        env.proxy_services.lock().await.tear_down_all();
+        tracing::info!(server_url = %form.server_url, "Signed out");
+    }
+
+    Json(serde_json::json!({ "signed_out": signed_out })).into_response()
+}
+
+async fn handle_status(Extension(env): Extension<Environment>) -> impl IntoResponse {
+    let state = env.proxy_connection_state.lock().await;
+    let (services, connections) = {
+        let services = env.proxy_services.lock().await;
+        (services.hostnames(), services.connections())
+    };
+
+    let service_status: serde_json::Map<_, _> = state
+        .services()
+        .map(|(hostname, val)| {
+            let status = serde_json::json!({
+                "proxy_status": val.status(),
+                "auth": val.auth(),
+                "latency_ms": val.average_rtt().map(|val| val.as_millis() as u64),
+            });
+            (hostname.clone(), status)
+        })
+        .collect();
+
+    Json(serde_json::json!({
+        "proxy_status": state.status(),
+        "auth": state.auth(),
+        "latency_ms": state.average_rtt().map(|val| val.as_millis() as u64),
+        "services": services,
+        "connections": connections,
+        "service_status": service_status,
+    }))
+}
+
+async fn handle_proxy_events(
+    Extension(env): Extension<Environment>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let receiver = env.proxy_events.subscribe();
+    ws.on_upgrade(|socket| forward_proxy_events(socket, receiver))
+}
+
+async fn forward_proxy_events(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<ProxyConnectionEvent>,
+) {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let msg = match proxy_event_message(event) {
+                    Some(val) => val,
+                    None => break,
+                };
+                if socket.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                if !matches!(msg, Some(Ok(_))) {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = socket.close().await;
+}
+
+// `None` ends the stream, a subscriber that fell behind is disconnected rather than slowing the proxy
+fn proxy_event_message(
+    event: Result<ProxyConnectionEvent, broadcast::error::RecvError>,
+) -> Option<Message> {
+    match event {
+        Ok(event) => serde_json::to_string(&event).ok().map(Message::Text),
+        Err(broadcast::error::RecvError::Lagged(missed)) => {
+            tracing::warn!(missed, "Proxy events subscriber fell behind, disconnecting");
+            None
+        }
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
 }
 
 async fn handle_term_ws(
@@ -73,11 +265,14 @@ async fn handle_socket(socket: WebSocket, env: Environment) {
     let mut pty_reader = pair.master.try_clone_reader().unwrap();
     let pty_writer = pair.master.try_clone_writer().unwrap();
 
-    let (pty_read_sender, pty_read_receiver) = unbounded_channel();
+    // Bounded so a slow websocket blocks the reader thread instead of buffering without limit
+    let pending_frames = env.config.terminal_pending_frames.max(1);
+    let (pty_read_sender, pty_read_receiver) = channel(pending_frames);
 
+    let buffer_size = env.config.terminal_read_buffer_bytes.max(1);
     std::thread::spawn(move || {
         // TODO: end the thread once the ws connection ends
-        let mut buffer = [0; 4 * 1024];
+        let mut buffer = vec![0; buffer_size];
         loop {
             let n = match pty_reader.read(&mut buffer) {
                 Ok(val) => val,
@@ -91,7 +286,7 @@ async fn handle_socket(socket: WebSocket, env: Environment) {
             }
 
             let data = buffer[..n].to_vec();
-            let send = pty_read_sender.send(data);
+            let send = pty_read_sender.blocking_send(data);
             if let Err(e) = send {
                 tracing::error!(?e, "Pty sending error, ending");
                 break;
@@ -102,7 +297,7 @@ async fn handle_socket(socket: WebSocket, env: Environment) {
 
     let (ws_outgoing, ws_incoming) = socket.split();
 
-    let (ws_msg_sender, ws_msg_receiver) = unbounded_channel();
+    let (ws_msg_sender, ws_msg_receiver) = channel(pending_frames);
 
     let (portalbox_cmd_sender, portalbox_cmd_receiver) = unbounded_channel();
 
@@ -135,7 +330,7 @@ async fn handle_websocket_incoming(
     mut incoming: SplitStream<WebSocket>,
     mut pty_writer: Box<dyn Write + Send>,
     portalbox_cmd_sender: UnboundedSender<String>,
-    ws_msg_sender: UnboundedSender<Message>,
+    ws_msg_sender: Sender<Message>,
 ) -> Result<(), anyhow::Error> {
     while let Some(Ok(msg)) = incoming.next().await {
         match msg {
@@ -150,7 +345,7 @@ async fn handle_websocket_incoming(
                 pty_writer.write_all(&data)?;
             }
             Message::Ping(data) => {
-                let _ = ws_msg_sender.send(Message::Pong(data));
+                let _ = ws_msg_sender.send(Message::Pong(data)).await;
             }
             Message::Pong(data) => {
                 tracing::debug!(?data, "Pong");
@@ -165,19 +360,35 @@ async fn handle_websocket_incoming(
 }
 
 async fn handle_pty_incoming(
-    mut pty_read_receiver: UnboundedReceiver<Vec<u8>>,
-    ws_msg_sender: UnboundedSender<Message>,
+    mut pty_read_receiver: Receiver<Vec<u8>>,
+    ws_msg_sender: Sender<Message>,
 ) -> Result<(), anyhow::Error> {
     while let Some(data) = pty_read_receiver.recv().await {
+        let data = coalesce_pending(data, &mut pty_read_receiver, TERM_MAX_FRAME_BYTES);
         let msg = Message::Binary(data);
-        let _sent = ws_msg_sender.send(msg)?;
+        let _sent = ws_msg_sender.send(msg).await?;
     }
 
     Ok(())
 }
 
+fn coalesce_pending(
+    mut data: Vec<u8>,
+    receiver: &mut Receiver<Vec<u8>>,
+    max_bytes: usize,
+) -> Vec<u8> {
+    while data.len() < max_bytes {
+        match receiver.try_recv() {
+            Ok(more) => data.extend_from_slice(&more),
+            Err(_) => break,
+        }
+    }
+
+    data
+}
+
 async fn handle_ws_msg_send(
-    mut ws_msg_receiver: UnboundedReceiver<Message>,
+    mut ws_msg_receiver: Receiver<Message>,
     mut ws_outgoing: SplitSink<WebSocket, Message>,
 ) -> Result<(), anyhow::Error> {
     while let Some(msg) = ws_msg_receiver.recv().await {
@@ -220,8 +431,26 @@ async fn handle_portalbox_cmds(
 }
 
 fn parse_portalbox_cmd(cmd: &str) -> Result<PortalBoxCmd, anyhow::Error> {
-    let size = cmd.trim_start_matches("__portalbox_term_cmd_resize:");
+    let ret = if let Some(json) = cmd.strip_prefix(PORTALBOX_TERM_CMD_JSON_PREFIX) {
+        serde_json::from_str(json)?
+    } else if let Some(size) = cmd.strip_prefix(PORTALBOX_TERM_CMD_LEGACY_RESIZE_PREFIX) {
+        parse_legacy_resize(size)?
+    } else {
+        anyhow::bail!("Unknown command {cmd}");
+    };
 
+    match ret {
+        PortalBoxCmd::Resize { cols, rows } => {
+            if !TERM_SIZE_RANGE.contains(&cols) || !TERM_SIZE_RANGE.contains(&rows) {
+                anyhow::bail!("Terminal size out of range - {cols}x{rows}");
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+fn parse_legacy_resize(size: &str) -> Result<PortalBoxCmd, anyhow::Error> {
     let mut sizes = size.split('x');
     let cols = sizes
         .next()
@@ -237,7 +466,186 @@ fn parse_portalbox_cmd(cmd: &str) -> Result<PortalBoxCmd, anyhow::Error> {
     Ok(ret)
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 enum PortalBoxCmd {
     Resize { cols: u16, rows: u16 },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_term_ws_disabled() {
+        use axum::{
+            body::Body,
+            http::{Request, StatusCode},
+        };
+        use tower::ServiceExt;
+
+        let request = || {
+            let body = http_body::Limited::new(Body::empty(), 1024);
+            Request::get("/term-ws").body(body).unwrap()
+        };
+
+        let config = Config::default();
+        let response = routes(&config).oneshot(request()).await.unwrap();
+        assert_ne!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = Config {
+            terminal_enabled: false,
+            ..Default::default()
+        };
+        let response = routes(&config).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_is_local_request() {
+        let secret = SecretString::new("s3cret".into());
+        let loopback: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let remote: SocketAddr = "192.168.1.20:50000".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_local_request(loopback, &headers, &secret));
+
+        headers.insert(LOCAL_SECRET_HEADER, "s3cret".parse().unwrap());
+        assert!(is_local_request(loopback, &headers, &secret));
+        assert!(!is_local_request(remote, &headers, &secret));
+
+        headers.insert(LOCAL_SECRET_HEADER, "guess".parse().unwrap());
+        assert!(!is_local_request(loopback, &headers, &secret));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let state = ConnectionState::default();
+        let (code, Json(body)) = readiness(&state, None);
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["auth"], "unknown");
+        assert!(body["auth_failure"].is_null());
+
+        let reason = AuthFailedReason {
+            code: models::protocol::AuthFailedCode::BoxSuspended,
+            message: None,
+        };
+        let (code, Json(body)) = readiness(&state, Some(&reason));
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["auth_failure"], reason.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_events() {
+        let (sender, mut receiver) = broadcast::channel(2);
+        sender
+            .send(ProxyConnectionEvent::PingRtt {
+                hostname: "box-home.portalbox.app".into(),
+                rtt_ms: 42,
+            })
+            .unwrap();
+
+        let msg = proxy_event_message(receiver.recv().await).unwrap();
+        let json: serde_json::Value = match msg {
+            Message::Text(val) => serde_json::from_str(&val).unwrap(),
+            _ => panic!("Expecting a text message"),
+        };
+        assert_eq!(json["type"], "ping_rtt");
+        assert_eq!(json["hostname"], "box-home.portalbox.app");
+        assert_eq!(json["rtt_ms"], 42);
+
+        // Falling behind disconnects, the sender never waits
+        for _ in 0..3 {
+            sender
+                .send(ProxyConnectionEvent::Established {
+                    hostname: "box-home.portalbox.app".into(),
+                })
+                .unwrap();
+        }
+        assert!(proxy_event_message(receiver.recv().await).is_none());
+    }
+
+    #[test]
+    fn test_coalesce_pending() {
+        let (sender, mut receiver) = channel(10);
+        for _ in 0..10 {
+            sender.try_send(vec![1; 4]).unwrap();
+        }
+
+        let first = receiver.try_recv().unwrap();
+        let data = coalesce_pending(first, &mut receiver, 16);
+        assert_eq!(data.len(), 16);
+
+        let first = receiver.try_recv().unwrap();
+        let data = coalesce_pending(first, &mut receiver, 1024);
+        assert_eq!(data.len(), 24);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pty_backpressure() {
+        let (pty_read_sender, pty_read_receiver) = channel(2);
+        let (ws_msg_sender, mut ws_msg_receiver) = channel(2);
+
+        let reader = std::thread::spawn(move || {
+            for _ in 0..100 {
+                if pty_read_sender.blocking_send(vec![0; 64 * 1024]).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(handle_pty_incoming(pty_read_receiver, ws_msg_sender));
+
+        // Nothing consumes the websocket side, the reader must stall rather than finish
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!reader.is_finished());
+
+        let mut received = 0;
+        while let Some(Message::Binary(data)) = ws_msg_receiver.recv().await {
+            received += data.len();
+        }
+        assert_eq!(received, 100 * 64 * 1024);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_json_cmd() {
+        let cmd =
+            parse_portalbox_cmd(r#"__portalbox_term_cmd:{"type":"resize","cols":80,"rows":24}"#);
+        assert_eq!(cmd.unwrap(), PortalBoxCmd::Resize { cols: 80, rows: 24 });
+    }
+
+    #[test]
+    fn test_parse_legacy_cmd() {
+        let cmd = parse_portalbox_cmd("__portalbox_term_cmd_resize:120x40");
+        assert_eq!(
+            cmd.unwrap(),
+            PortalBoxCmd::Resize {
+                cols: 120,
+                rows: 40
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_cmd() {
+        let malformed = [
+            "__portalbox_term_cmd:",
+            "__portalbox_term_cmd:{not json}",
+            r#"__portalbox_term_cmd:{"type":"move","cols":80,"rows":24}"#,
+            r#"__portalbox_term_cmd:{"type":"resize","cols":80}"#,
+            r#"__portalbox_term_cmd:{"type":"resize","cols":-1,"rows":24}"#,
+            r#"__portalbox_term_cmd:{"type":"resize","cols":0,"rows":24}"#,
+            r#"__portalbox_term_cmd:{"type":"resize","cols":80,"rows":1001}"#,
+            "__portalbox_term_cmd_resize:80",
+            "__portalbox_term_cmd_resize:80xabc",
+            "__portalbox_term_cmd_resize:0x24",
+            "__portalbox_term_cmd_other",
+        ];
+
+        for cmd in malformed {
+            assert!(parse_portalbox_cmd(cmd).is_err(), "{cmd}");
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppsPhase {
+    #[default]
+    Idle,
+    Checking,
+    Downloading,
+    Extracting,
+}
+
+// What `fetch_or_update_apps` is doing, for a dashboard opened mid-update
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AppsStatus {
+    pub phase: AppsPhase,
+    pub version: Option<String>,
+    // Of the current phase, none while checking or when the size is unknown
+    pub percent: Option<u8>,
+}
+
+// Updated from the blocking extraction as well, hence not a tokio mutex
+#[derive(Debug, Clone, Default)]
+pub struct SharedAppsStatus(Arc<Mutex<AppsStatus>>);
+
+impl SharedAppsStatus {
+    pub fn get(&self) -> AppsStatus {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set_phase(&self, phase: AppsPhase, version: Option<&semver::Version>) {
+        *self.0.lock().unwrap() = AppsStatus {
+            phase,
+            version: version.map(|val| val.to_string()),
+            percent: None,
+        };
+    }
+
+    pub fn set_progress(&self, done: u64, total: u64) {
+        self.0.lock().unwrap().percent = percent(done, total);
+    }
+}
+
+// `None` when the total is unknown
+fn percent(done: u64, total: u64) -> Option<u8> {
+    if total == 0 {
+        return None;
+    }
+
+    Some((done.min(total) * 100 / total) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apps_status() {
+        let status = SharedAppsStatus::default();
+        assert_eq!(status.get().phase, AppsPhase::Idle);
+
+        let version = semver::Version::new(1, 66, 0);
+        status.set_phase(AppsPhase::Downloading, Some(&version));
+        status.set_progress(83, 100);
+        let ret = serde_json::to_value(status.get()).unwrap();
+        assert_eq!(
+            ret,
+            serde_json::json!({"phase": "downloading", "version": "1.66.0", "percent": 83})
+        );
+
+        // A new phase starts over
+        status.set_phase(AppsPhase::Extracting, Some(&version));
+        assert_eq!(status.get().percent, None);
+
+        status.set_progress(5, 0);
+        assert_eq!(status.get().percent, None);
+        assert_eq!(percent(200, 100), Some(100));
+    }
+}
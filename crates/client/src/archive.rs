@@ -0,0 +1,214 @@
+use std::{
+    io::Read,
+    path::{Component, Path},
+};
+
+use tar::EntryType;
+
+// Extracts a .tar.gz into `dest`, refusing the whole archive if any entry would land outside it.
+// `on_progress` gets the compressed bytes extracted so far and the archive size
+pub fn unpack_tar_gz(
+    path: &Path,
+    dest: &Path,
+    on_progress: &dyn Fn(u64, u64),
+) -> Result<(), anyhow::Error> {
+    // Validate everything before writing anything, the archive is downloaded from the network
+    let mut archive = open_tar_gz(std::fs::File::open(path)?);
+    for entry in archive.entries()? {
+        validate_entry(&entry?)?;
+    }
+
+    std::fs::create_dir_all(dest)?;
+
+    let tar_gz = std::fs::File::open(path)?;
+    let total = tar_gz.metadata()?.len();
+    let mut archive = open_tar_gz(ProgressReader {
+        inner: tar_gz,
+        read: 0,
+        total,
+        on_progress,
+    });
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // `unpack_in` additionally refuses to write through symlinks pointing outside `dest`
+        if !entry.unpack_in(dest)? {
+            return Err(anyhow::anyhow!(
+                "Archive entry {} escapes the extraction dir",
+                entry.path()?.display()
+            ));
+        }
+    }
+    // The gzip trailer may be left unread
+    on_progress(total, total);
+
+    Ok(())
+}
+
+fn open_tar_gz<R: Read>(tar_gz: R) -> tar::Archive<flate2::read::GzDecoder<R>> {
+    let tar = flate2::read::GzDecoder::new(tar_gz);
+    tar::Archive::new(tar)
+}
+
+struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    total: u64,
+    on_progress: &'a dyn Fn(u64, u64),
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let ret = self.inner.read(buf)?;
+        if ret > 0 {
+            self.read += ret as u64;
+            (self.on_progress)(self.read, self.total);
+        }
+        Ok(ret)
+    }
+}
+
+fn validate_entry<R: std::io::Read>(entry: &tar::Entry<R>) -> Result<(), anyhow::Error> {
+    let entry_path = entry.path()?;
+    if !is_contained(&entry_path) {
+        return Err(anyhow::anyhow!(
+            "Archive entry {} escapes the extraction dir",
+            entry_path.display()
+        ));
+    }
+
+    let link_target = match entry.link_name()? {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    let resolved = match entry.header().entry_type() {
+        // Symlinks are relative to the directory containing them
+        EntryType::Symlink => entry_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(&link_target),
+        // Hard links are relative to the archive root
+        _ => link_target.to_path_buf(),
+    };
+
+    if !is_contained(&resolved) {
+        return Err(anyhow::anyhow!(
+            "Archive link {} -> {} escapes the extraction dir",
+            entry_path.display(),
+            link_target.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// Lexically checks a relative path never climbs above its starting dir
+fn is_contained(path: &Path) -> bool {
+    let mut depth = 0usize;
+
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(val) => depth = val,
+                None => return false,
+            },
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `tar::Header::set_path` rejects `..`, so the raw name bytes are written directly
+    fn write_tar_gz(path: &Path, entries: &[(&str, EntryType, Option<&str>)]) {
+        let file = std::fs::File::create(path).unwrap();
+        let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+
+        for (name, entry_type, link) in entries {
+            let mut header = tar::Header::new_old();
+            let raw_name = &mut header.as_old_mut().name;
+            raw_name[..name.len()].copy_from_slice(name.as_bytes());
+            if let Some(link) = link {
+                let raw_link = &mut header.as_old_mut().linkname;
+                raw_link[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, std::io::empty()).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_is_contained() {
+        assert!(is_contained(Path::new("vscode/bin/code")));
+        assert!(is_contained(Path::new("vscode/../vscode/bin")));
+        assert!(!is_contained(Path::new("../evil")));
+        assert!(!is_contained(Path::new("vscode/../../evil")));
+        assert!(!is_contained(Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_unpack_refuses_malicious_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let dest = dir.join("apps");
+
+        let cases: &[&[(&str, EntryType, Option<&str>)]] = &[
+            &[
+                ("vscode/readme", EntryType::Regular, None),
+                ("../evil", EntryType::Regular, None),
+            ],
+            &[("vscode/link", EntryType::Symlink, Some("../../evil"))],
+            &[("vscode/link", EntryType::Symlink, Some("/etc"))],
+            &[("vscode/link", EntryType::Link, Some("../evil"))],
+        ];
+
+        for entries in cases {
+            let tar_gz_path = dir.join("malicious.tar.gz");
+            write_tar_gz(&tar_gz_path, entries);
+
+            assert!(unpack_tar_gz(&tar_gz_path, &dest, &|_, _| {}).is_err());
+            assert!(!dir.join("evil").exists());
+            assert!(!dest.join("vscode").exists());
+        }
+    }
+
+    #[test]
+    fn test_unpack_valid_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let dest = dir.join("apps");
+
+        let tar_gz_path = dir.join("valid.tar.gz");
+        write_tar_gz(
+            &tar_gz_path,
+            &[
+                ("vscode/readme", EntryType::Regular, None),
+                ("vscode/link", EntryType::Symlink, Some("readme")),
+            ],
+        );
+
+        let progress = std::cell::RefCell::new(vec![]);
+        unpack_tar_gz(&tar_gz_path, &dest, &|done, total| {
+            progress.borrow_mut().push((done, total))
+        })
+        .unwrap();
+        assert!(dest.join("vscode/readme").exists());
+
+        let progress = progress.into_inner();
+        let size = std::fs::metadata(&tar_gz_path).unwrap().len();
+        assert!(progress.windows(2).all(|val| val[0].0 <= val[1].0));
+        assert_eq!(progress.last(), Some(&(size, size)));
+    }
+}
@@ -0,0 +1,32 @@
+use crate::config::Config;
+
+pub fn dashboard_url(config: &Config) -> String {
+    format!("http://localhost:{}", config.local_home_service_port)
+}
+
+// Best effort, headless servers just get the url printed
+pub fn open_dashboard(config: &Config) -> Result<(), anyhow::Error> {
+    let url = dashboard_url(config);
+
+    if !has_display() {
+        println!("No browser available, the dashboard is at {url}");
+        return Ok(());
+    }
+
+    if let Err(e) = webbrowser::open(&url) {
+        tracing::debug!(?e, "Failed to open browser");
+        println!("Can't open a browser, the dashboard is at {url}");
+    }
+
+    Ok(())
+}
+
+fn has_display() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "windows", target_os = "macos"))] {
+            true
+        } else {
+            std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use models::{AppInfo, AppsResult};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config::Config;
+
+// Last known good values, used to start without waiting on the network
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ProxyAddressCache {
+    proxy_address: SocketAddr,
+}
+
+// The server's last apps result, laid out like `AppsResult` so files saved before `fetched_at`
+// still load
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AppsCache {
+    fetched_at: Option<DateTime<Utc>>,
+    vscode: AppInfo,
+}
+
+pub async fn load_apps(config: &Config) -> Result<AppsResult, anyhow::Error> {
+    let cache: AppsCache = load(config.apps_cache_file_path()).await?;
+    Ok(AppsResult {
+        vscode: cache.vscode,
+    })
+}
+
+pub async fn save_apps(config: &Config, apps: &AppsResult) -> Result<(), anyhow::Error> {
+    let cache = AppsCache {
+        fetched_at: Some(Utc::now()),
+        vscode: apps.vscode.clone(),
+    };
+    save(config.apps_cache_file_path(), &cache).await
+}
+
+// `None` unless the saved result is for `os_arch` and was fetched within `ttl`
+pub async fn load_fresh_apps(config: &Config, os_arch: &str, ttl: Duration) -> Option<AppsResult> {
+    let cache: AppsCache = load(config.apps_cache_file_path()).await.ok()?;
+    if cache.vscode.os_arch != os_arch {
+        return None;
+    }
+
+    let age = Utc::now()
+        .signed_duration_since(cache.fetched_at?)
+        .to_std()
+        .ok()?;
+    (age < ttl).then(|| AppsResult {
+        vscode: cache.vscode,
+    })
+}
+
+pub async fn load_proxy_address(config: &Config) -> Result<SocketAddr, anyhow::Error> {
+    let cache: ProxyAddressCache = load(config.proxy_address_cache_file_path()).await?;
+    Ok(cache.proxy_address)
+}
+
+pub async fn save_proxy_address(
+    config: &Config,
+    proxy_address: SocketAddr,
+) -> Result<(), anyhow::Error> {
+    let cache = ProxyAddressCache { proxy_address };
+    save(config.proxy_address_cache_file_path(), &cache).await
+}
+
+pub fn file_paths(config: &Config) -> [PathBuf; 2] {
+    [
+        config.apps_cache_file_path(),
+        config.proxy_address_cache_file_path(),
+    ]
+}
+
+pub async fn delete(config: &Config) -> Result<(), anyhow::Error> {
+    for filepath in file_paths(config) {
+        if filepath.exists() {
+            tokio::fs::remove_file(filepath).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn load<T: DeserializeOwned, P: AsRef<Path>>(filepath: P) -> Result<T, anyhow::Error> {
+    let file_content = tokio::fs::read_to_string(filepath).await?;
+    let ret = toml::from_str(&file_content)?;
+    Ok(ret)
+}
+
+async fn save<T: Serialize, P: AsRef<Path>>(filepath: P, value: &T) -> Result<(), anyhow::Error> {
+    let contents = toml::to_string_pretty(value)?;
+    crate::utils::write_atomic(filepath, contents).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::AppInfo;
+
+    fn apps(os_arch: &str, version: &str) -> AppsResult {
+        AppsResult {
+            vscode: AppInfo {
+                os_arch: os_arch.into(),
+                latest_version: semver::Version::parse(version).unwrap(),
+                download_link: "http://127.0.0.1:1/vscode.tar.gz".into(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fresh_apps() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            ..Default::default()
+        };
+        let ttl = Duration::from_secs(60);
+
+        assert!(load_fresh_apps(&config, "linux-x64", ttl).await.is_none());
+
+        // Saved before results had a fetch time
+        let contents = toml::to_string_pretty(&apps("linux-x64", "1.0.0")).unwrap();
+        std::fs::write(config.apps_cache_file_path(), contents).unwrap();
+        let ret = load_apps(&config).await.unwrap();
+        assert_eq!(ret.vscode.latest_version.to_string(), "1.0.0");
+        assert!(load_fresh_apps(&config, "linux-x64", ttl).await.is_none());
+
+        save_apps(&config, &apps("linux-x64", "1.2.3"))
+            .await
+            .unwrap();
+        let ret = load_fresh_apps(&config, "linux-x64", ttl).await.unwrap();
+        assert_eq!(ret.vscode.latest_version.to_string(), "1.2.3");
+        let ret = load_apps(&config).await.unwrap();
+        assert_eq!(ret.vscode.latest_version.to_string(), "1.2.3");
+        assert!(load_fresh_apps(&config, "darwin-arm64", ttl)
+            .await
+            .is_none());
+
+        // Expired
+        assert!(load_fresh_apps(&config, "linux-x64", Duration::ZERO)
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use axum::{
+    body::{boxed, Empty},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+// Images, fonts and versioned npm packages rarely change between releases
+const LONG_LIVED_CACHE_CONTROL: &str = "public, max-age=604800";
+// Everything else may be revalidated with ETag/Last-Modified on every use
+const REVALIDATE_CACHE_CONTROL: &str = "no-cache";
+
+const LONG_LIVED_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "woff", "woff2", "ttf",
+];
+
+pub async fn set_cache_headers<B>(request: Request<B>, next: Next<B>) -> Response {
+    let cache_control = cache_control_for(request.uri().path());
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH).cloned();
+
+    let mut response = next.run(request).await;
+
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let etag = weak_etag(&response);
+
+    if let (Some(etag), Some(if_none_match)) = (&etag, &if_none_match) {
+        if etag_matches(if_none_match, etag) {
+            let mut not_modified = Response::new(boxed(Empty::new()));
+            *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+
+            let headers = not_modified.headers_mut();
+            headers.insert(header::ETAG, etag.clone());
+            headers.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(cache_control),
+            );
+            if let Some(last_modified) = response.headers().get(header::LAST_MODIFIED) {
+                headers.insert(header::LAST_MODIFIED, last_modified.clone());
+            }
+
+            return not_modified;
+        }
+    }
+
+    let headers = response.headers_mut();
+    if let Some(etag) = etag {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+
+    response
+}
+
+fn cache_control_for(path: &str) -> &'static str {
+    let long_lived_extension = match path.rsplit_once('.') {
+        Some((_, ext)) => LONG_LIVED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()),
+        None => false,
+    };
+    let long_lived = long_lived_extension || path.starts_with("/node_modules/");
+
+    if long_lived {
+        LONG_LIVED_CACHE_CONTROL
+    } else {
+        REVALIDATE_CACHE_CONTROL
+    }
+}
+
+// Derived from the file's size and modification time, which is what ServeDir knows about
+fn weak_etag(response: &Response) -> Option<HeaderValue> {
+    let last_modified = response.headers().get(header::LAST_MODIFIED)?;
+    let content_length = response.headers().get(header::CONTENT_LENGTH)?;
+
+    let mut hasher = DefaultHasher::new();
+    last_modified.as_bytes().hash(&mut hasher);
+    content_length.as_bytes().hash(&mut hasher);
+
+    let etag = format!("W/\"{:016x}\"", hasher.finish());
+    HeaderValue::from_str(&etag).ok()
+}
+
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let if_none_match = match if_none_match.to_str() {
+        Ok(val) => val,
+        Err(_) => return false,
+    };
+
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|val| val.trim().trim_start_matches("W/") == etag_value(etag))
+}
+
+fn etag_value(etag: &HeaderValue) -> &str {
+    etag.to_str().unwrap_or_default().trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_control_for() {
+        assert_eq!(cache_control_for("/portal.png"), LONG_LIVED_CACHE_CONTROL);
+        assert_eq!(
+            cache_control_for("/node_modules/xterm/lib/xterm.js"),
+            LONG_LIVED_CACHE_CONTROL
+        );
+        assert_eq!(cache_control_for("/custom.css"), REVALIDATE_CACHE_CONTROL);
+        assert_eq!(cache_control_for("/custom.js"), REVALIDATE_CACHE_CONTROL);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = HeaderValue::from_static("W/\"abc\"");
+
+        assert!(etag_matches(&HeaderValue::from_static("W/\"abc\""), &etag));
+        assert!(etag_matches(&HeaderValue::from_static("\"abc\""), &etag));
+        assert!(etag_matches(
+            &HeaderValue::from_static("\"xyz\", W/\"abc\""),
+            &etag
+        ));
+        assert!(etag_matches(&HeaderValue::from_static("*"), &etag));
+        assert!(!etag_matches(&HeaderValue::from_static("W/\"xyz\""), &etag));
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use url::Url;
 
 #[derive(Debug, Parser)]
 #[clap(name = "portalbox")]
@@ -11,32 +12,171 @@ pub struct Cli {
     /// Custom config file location
     #[clap(long, global = true)]
     pub config_file: Option<PathBuf>,
+    /// Skip all network calls, using the previously downloaded vscode
+    #[clap(long, global = true)]
+    pub offline: bool,
+    /// Switch to this server profile, remembered for the next runs
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
+    /// Use this server instead of the configured one
+    #[clap(long, global = true)]
+    pub server_url: Option<Url>,
+    /// Log every proxy protocol message, without tokens or payloads
+    #[clap(long, global = true)]
+    pub trace_protocol: bool,
+    /// No progress bars or status output, errors and logs are still printed
+    #[clap(long, short, global = true)]
+    pub quiet: bool,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Start the portalbox client
-    Start,
+    Start {
+        /// Report what updating vscode would do, without downloading anything, then exit
+        #[clap(long)]
+        dry_run_apps: bool,
+        /// Run the startup checks without serving anything, then exit
+        #[clap(long)]
+        check: bool,
+        /// Open the dashboard in the browser once started
+        #[clap(long)]
+        open: bool,
+        /// Run in the background, writing a pid file to the home dir
+        #[clap(long)]
+        detach: bool,
+        /// Drop buffered telemetry on exit instead of sending it
+        #[clap(long)]
+        no_flush_telemetry: bool,
+        /// Run only the dashboard, terminal and proxy, without vscode
+        #[clap(long)]
+        no_vscode: bool,
+        /// Serve the dashboard's static files from this dir
+        #[clap(long)]
+        wwwroot_dir: Option<PathBuf>,
+        /// Render the dashboard with the templates in this dir
+        #[clap(long)]
+        templates_dir: Option<PathBuf>,
+    },
+    /// Show whether portalbox is running in the background
+    Status,
+    /// Show the logs of portalbox running in the background
+    Logs,
+    /// Stop portalbox running in the background
+    Stop,
+    /// Open the dashboard in the browser
+    Open,
     /// Create a tunnel usable by ssh ProxyCommand
     Tunnel { host: String },
+    /// Check the proxy server is reachable and the saved credential is accepted
+    Ping,
+    /// Show which account the saved credential belongs to
+    Whoami,
+    /// Sign in without the dashboard, reading the password from PORTALBOX_PASSWORD or stdin
+    Signin {
+        #[clap(long)]
+        email: String,
+    },
+    /// Sign in as a guest without the dashboard, printing the access code and home url
+    SigninGuest,
+    /// Show internals useful when diagnosing problems
+    Debug(Debug),
+    /// Manage vscode extensions without opening the editor
+    Ext(Ext),
     /// Show current config
-    Config,
+    Config {
+        #[clap(subcommand)]
+        command: Option<ConfigCommands>,
+        /// Output format
+        #[clap(long, arg_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// Show the disk usage of portalbox's directories
+    Disk {
+        /// Print the sizes as JSON
+        #[clap(long)]
+        json: bool,
+    },
     /// Reset data
     Reset(Reset),
     /// Show current version
-    Version,
+    Version {
+        /// Print build info as JSON
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Json => "json",
+            ConfigFormat::Yaml => "yaml",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Debug {
+    #[clap(subcommand)]
+    pub command: DebugCommands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DebugCommands {
+    /// Show the proxy server address, what it resolves to and which one would be dialed
+    ProxyTarget,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Print a JSON Schema of the config file, for editor autocompletion and validation
+    Schema,
+}
+
+#[derive(Debug, Args)]
+pub struct Ext {
+    #[clap(subcommand)]
+    pub command: ExtCommands,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ExtCommands {
+    /// List installed extensions
+    List,
+    /// Install extensions by id (e.g. rust-lang.rust-analyzer) or from .vsix files
+    Install {
+        #[clap(required = true)]
+        ids: Vec<String>,
+    },
 }
 
 #[derive(Debug, Args)]
 pub struct Reset {
     #[clap(subcommand)]
     pub command: ResetCommands,
+    /// Print what would be deleted, without deleting anything
+    #[clap(long, global = true)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum ResetCommands {
     /// Delete saved credentials
-    Credentials,
+    Credentials {
+        /// Only delete the credential for this server, signing out its running proxies
+        #[clap(long)]
+        server: Option<Url>,
+    },
     /// Uninstall all apps
     Apps,
     /// Clear apps data
@@ -44,3 +184,27 @@ pub enum ResetCommands {
     /// Reset everything
     All,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_server_url() {
+        let args =
+            Cli::try_parse_from(["portalbox", "--server-url", "http://localhost:8080"]).unwrap();
+        assert_eq!(args.server_url.unwrap().as_str(), "http://localhost:8080/");
+
+        assert!(Cli::try_parse_from(["portalbox", "--server-url", "not a url"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_quiet() {
+        // Global, so it goes after the subcommand too
+        let args = Cli::try_parse_from(["portalbox", "start", "-q"]).unwrap();
+        assert!(args.quiet);
+
+        let args = Cli::try_parse_from(["portalbox", "version"]).unwrap();
+        assert!(!args.quiet);
+    }
+}
@@ -1,16 +1,24 @@
-use std::path::Path;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
 use crate::config::Config;
-use models::AppInfo;
+use models::{AppInfo, AppsResult};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ClientInstance {
     pub vscode: AppInfo,
+    pub vscode_cmd: PathBuf,
 }
 
 impl ClientInstance {
     pub async fn infer(config: &Config) -> Result<Self, anyhow::Error> {
+        if let Some(vscode_path) = &config.vscode_path {
+            return Self::with_vscode_path(vscode_path);
+        }
+
         let mut all_vscodes = all_vscode_installations(config.apps_dir()).await?;
 
         if all_vscodes.is_empty() {
@@ -22,10 +30,56 @@ impl ClientInstance {
 
         let ret = ClientInstance {
             vscode: latest.to_owned(),
+            vscode_cmd: latest.vscode_cmd(config.apps_dir()),
         };
 
         Ok(ret)
     }
+
+    pub fn from_apps(apps: AppsResult, config: &Config) -> Self {
+        let vscode_cmd = apps.vscode.vscode_cmd(config.apps_dir());
+        ClientInstance {
+            vscode: apps.vscode,
+            vscode_cmd,
+        }
+    }
+
+    // Creates the data dirs if needed, vscode and its cli are always started with these
+    pub async fn data_dir_args(&self, data_root: &Path) -> std::io::Result<Vec<OsString>> {
+        self.vscode.create_data_dirs(data_root).await?;
+
+        let ret = vec![
+            "--server-data-dir".into(),
+            self.vscode.server_data_dir(data_root).into(),
+            "--user-data-dir".into(),
+            self.vscode.user_data_dir(data_root).into(),
+            "--extensions-dir".into(),
+            self.vscode.extensions_dir(data_root).into(),
+        ];
+        Ok(ret)
+    }
+
+    // A self managed vscode, its version is unknown and never updated
+    pub fn with_vscode_path(vscode_path: &Path) -> Result<Self, anyhow::Error> {
+        if !vscode_path.exists() {
+            return Err(anyhow::anyhow!(
+                "vscode_path {} doesn't exist",
+                vscode_path.display()
+            ));
+        }
+
+        let vscode = AppInfo {
+            os_arch: models::utils::get_os_arch(),
+            latest_version: semver::Version::new(0, 0, 0),
+            download_link: "".into(),
+        };
+
+        let ret = ClientInstance {
+            vscode,
+            vscode_cmd: vscode_path.to_path_buf(),
+        };
+        Ok(ret)
+    }
 }
 
 async fn all_vscode_installations<P: AsRef<Path>>(
@@ -65,7 +119,7 @@ fn parse_vscode_dir(dir: &str) -> Result<AppInfo, anyhow::Error> {
     Ok(ret)
 }
 
-async fn all_vscode_dirs<P: AsRef<Path>>(apps_dir: P) -> Result<Vec<String>, anyhow::Error> {
+pub async fn all_vscode_dirs<P: AsRef<Path>>(apps_dir: P) -> Result<Vec<String>, anyhow::Error> {
     let mut all_vscode_dirs = vec![];
     let mut entries = tokio::fs::read_dir(apps_dir).await?;
 
@@ -1,68 +1,376 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use anyhow::Context;
+use backoff::ExponentialBackoff;
 use config::{ConfigError, Environment, File};
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use socket2::TcpKeepalive;
 use url::Url;
 
+use crate::cli::ConfigFormat;
+
 pub(crate) const PORTALBOX_DIR: &str = ".portalbox";
+// Under `XDG_STATE_HOME` or `XDG_DATA_HOME` on Linux
+const XDG_PORTALBOX_DIR: &str = "portalbox";
 const CONFIG_FILE: &str = "config.toml";
 const ENV_VAR_PREFIX: &str = "PORTALBOX";
+// String fields where `${VAR}` and `${VAR:-fallback}` are expanded from the environment
+const INTERPOLATED_KEYS: &[&str] = &[
+    "server_url",
+    "home_dir",
+    "runtime_dir",
+    "wwwroot_dir",
+    "templates_dir",
+    "log",
+];
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// portalbox's `config.toml`, each field can also be set with a `PORTALBOX_<FIELD>` environment
+/// variable, e.g. `PORTALBOX_VSCODE_PORT`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(default)]
 pub struct Config {
+    /// The portalbox server signed in to and proxied through
     pub server_url: Url,
+    /// A backup server, used once `server_url` fails `failover_retries` times in a row.
+    /// Signing in there once gives it its own saved session
+    pub fallback_server_url: Option<Url>,
+    /// Failures in a row before switching between `server_url` and `fallback_server_url`
+    pub failover_retries: u32,
+    /// Open proxy connections per service, warm and active, more are refused until some close
+    pub max_connections_per_service: usize,
+    /// Named servers, `current_profile` picks one over `server_url`
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Saved when switching with `--profile`
+    pub current_profile: Option<String>,
+    /// Port of the server's proxy, on the server's host
     pub server_proxy_port: u16,
+    /// Connecting to the proxy server is given up after this long, then retried
+    pub proxy_connect_timeout_secs: u64,
+    /// Timeout of requests to the server, except vscode downloads
+    pub http_timeout_secs: u64,
+    /// TCP keepalive on proxy and tunnel sockets, idle time before the first probe
+    pub tcp_keepalive_idle_secs: u64,
+    /// Time between keepalive probes, ignored where unsupported
+    pub tcp_keepalive_interval_secs: u64,
+    /// Unanswered keepalive probes before the connection is dropped, ignored where unsupported
+    pub tcp_keepalive_retries: u32,
+    /// Keepalive probe period for `portalbox tunnel`, overrides the TCP keepalive settings above
+    pub ssh_keepalive_secs: Option<u64>,
+    /// Proxy reconnect delays are randomized by this factor (0 to 1) so clients don't retry in step
+    pub backoff_randomization_factor: f64,
+    /// Longest delay between proxy reconnect attempts
+    pub backoff_max_interval_secs: u64,
+    /// Logs a warning when the average proxy round trip goes above this
+    pub latency_warn_ms: u64,
+    /// How long fetched server news is shown before fetching it again
+    pub server_news_cache_secs: u64,
+    /// How long the last apps result is used without waiting on the server, which is still asked
+    /// in the background. 0 to always wait for it.
+    pub apps_cache_ttl_secs: u64,
+    /// Shorter than the news, urgent notices should show up quickly
+    pub motd_cache_secs: u64,
+    /// Re-check for a new portalbox version this often, only once at start if 0
+    pub version_check_interval_secs: u64,
+    /// Downloads triggered by server responses are aborted past this size
+    pub max_download_bytes: u64,
+    /// Port the dashboard listens on, on all interfaces
     pub local_home_service_port: u16,
+    /// Larger dashboard request bodies are rejected with 413
+    pub max_request_body_bytes: usize,
+    /// Files attached to the contact form, on top of `max_request_body_bytes` for the text fields
+    pub contact_attachment_max_bytes: usize,
+    /// Address vscode listens on, all interfaces by default
+    pub vscode_host: IpAddr,
+    /// Port vscode listens on
     pub vscode_port: u16,
+    /// Start vscode on a free port picked by the OS when `vscode_port` is taken
+    pub vscode_port_fallback: bool,
+    /// Local ssh server reached through the proxy
     pub ssh_port: u16,
+    /// Without vscode portalbox is only a gateway to the dashboard, terminal and ssh
+    pub start_vscode: bool,
+    /// Services reachable through the proxy, any of "home", "vscode" and "ssh"
+    pub allowed_services: Vec<String>,
+    /// Tiles on the dashboard, in order
+    pub dashboard_tiles: Vec<DashboardTile>,
+    /// User services shown after the tiles
+    pub services: Vec<ServiceConfig>,
+    /// Guest sessions are signed out after this long, never if unset
+    pub guest_session_secs: Option<u64>,
+    /// The dashboard terminal, a shell for anyone who can reach the dashboard
+    pub terminal_enabled: bool,
+    /// Dashboard pages to leave out, any of "signin", "signin-guest", "contact", "about",
+    /// "privacy", "terms" and "new-service"
+    pub disabled_pages: Vec<String>,
+    /// Shell started by the dashboard terminal, defaults to the user's shell
     pub shell_command: Option<String>,
-    // Configurable, default to local data dir/PORTALBOX_DIR
+    /// Size of each read from the terminal pty
+    pub terminal_read_buffer_bytes: usize,
+    /// Pty reads waiting to go out on the websocket, reading pauses once full
+    pub terminal_pending_frames: usize,
+    /// Self managed vscode binary, skips downloading and auto updating vscode
+    pub vscode_path: Option<PathBuf>,
+    /// Where vscode keeps its data and extensions, e.g. on a larger volume, defaults to `apps_data_dir`
+    pub vscode_data_root: Option<PathBuf>,
+    /// Configurable, defaults to `~/.portalbox`, or under `XDG_STATE_HOME`/`XDG_DATA_HOME` on Linux
     pub home_dir: PathBuf,
+    /// Where the dashboard files are read from, defaults to next to the executable
     pub runtime_dir: Option<PathBuf>,
-    pub telemetry: bool,
+    /// Dashboard static files, e.g. for theming, defaults to under `runtime_dir`
+    pub wwwroot_dir: Option<PathBuf>,
+    /// Dashboard templates, e.g. for theming, defaults to under `runtime_dir`
+    pub templates_dir: Option<PathBuf>,
+    /// Rereads the templates on every render, for dashboard development
+    pub templates_hot_reload: bool,
+    /// Unset until the user chooses on the dashboard, nothing is exported until then
+    pub telemetry: Option<bool>,
+    /// Log filter directives, e.g. "info" or "client=debug"
     pub log: String,
+    /// Defaults to `portalbox/<version> (<os-arch>)`
+    pub user_agent: Option<String>,
+    /// http://host:port or socks5://host:port, used for all outbound connections
+    pub outbound_proxy: Option<Url>,
+    /// Local address outbound connections leave from, e.g. a VPN interface's
+    pub outbound_bind_ip: Option<IpAddr>,
+    /// PEM files of internal CAs trusted on top of the system ones, a single path or an array
+    #[serde(deserialize_with = "one_or_many")]
+    #[schemars(with = "OneOrMany")]
+    pub server_ca_cert_path: Vec<PathBuf>,
+    /// Trust the CAs bundled with portalbox when the system trust store is still empty or unreadable
+    pub use_webpki_roots_fallback: bool,
+    /// No signin, news, version check, vscode download or proxy daemon
+    pub offline: bool,
+    /// Logs the proxy protocol messages of every connection at debug level
+    pub trace_protocol: bool,
+    /// Set by `--quiet`, for scripts: no progress bars or status output
+    #[serde(skip)]
+    pub quiet: bool,
+    /// Where choices made on the dashboard are saved
+    #[serde(skip)]
+    pub config_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProfileConfig {
+    /// Used instead of the top level `server_url` while this profile is selected
+    pub server_url: Url,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DashboardTile {
+    /// Shown under the icon
+    pub name: String,
+    /// Defaults to the service's own url, required for tiles without one
+    pub url: Option<String>,
+    /// Relative to the dashboard, or a full url
+    pub icon_url: String,
+    /// Hidden when this service isn't in `allowed_services`, or for "terminal" when it's disabled
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ServiceConfig {
+    /// Shown on the dashboard, and part of the online hostname when forwarded
+    pub name: String,
+    /// Local port the service listens on
+    pub port: u16,
+    /// Relative to the dashboard, or a full url
+    pub icon_url: Option<String>,
+    /// Reachable online at `{base_sub_domain}-{name}.portalbox.app`
+    #[serde(default)]
+    pub forward: bool,
+}
+
+fn is_dns_label(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|val| val.is_ascii_lowercase() || val.is_ascii_digit() || val == '-')
+}
+
+fn default_dashboard_tiles() -> Vec<DashboardTile> {
+    vec![
+        DashboardTile {
+            name: "Visual Studio Code".into(),
+            url: None,
+            icon_url: "/vscode_icon.png".into(),
+            service: Some("vscode".into()),
+        },
+        DashboardTile {
+            name: "Terminal".into(),
+            url: Some("/terminal".into()),
+            icon_url: "/terminal_icon.png".into(),
+            service: Some("terminal".into()),
+        },
+        DashboardTile {
+            name: "SSH".into(),
+            url: Some("https://github.com/portalbox-app/portalbox#ssh-jump-host".into()),
+            icon_url: "/terminal_icon.png".into(),
+            service: Some("ssh".into()),
+        },
+    ]
 }
 
 impl Default for Config {
     fn default() -> Self {
-        let default_home_dir = {
-            let home_dir = dirs::home_dir().unwrap();
-            home_dir.join(PORTALBOX_DIR)
-        };
+        let default_home_dir =
+            default_home_dir(&dirs::home_dir().unwrap(), |name| std::env::var(name).ok());
 
         Self {
             server_url: Url::parse("https://www.portalbox.app").unwrap(),
+            fallback_server_url: None,
+            failover_retries: 5,
+            max_connections_per_service: 64,
+            profiles: BTreeMap::new(),
+            current_profile: None,
             server_proxy_port: 46637,
+            proxy_connect_timeout_secs: 10,
+            http_timeout_secs: 10,
+            tcp_keepalive_idle_secs: 60,
+            tcp_keepalive_interval_secs: 10,
+            tcp_keepalive_retries: 5,
+            ssh_keepalive_secs: None,
+            backoff_randomization_factor: 0.5,
+            backoff_max_interval_secs: 15,
+            latency_warn_ms: 500,
+            server_news_cache_secs: 60 * 60,
+            apps_cache_ttl_secs: 15 * 60,
+            motd_cache_secs: 5 * 60,
+            version_check_interval_secs: 24 * 60 * 60,
+            max_download_bytes: 2 * 1024 * 1024 * 1024,
             local_home_service_port: 3030,
+            max_request_body_bytes: 64 * 1024,
+            contact_attachment_max_bytes: 5 * 1024 * 1024,
+            vscode_host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             vscode_port: 3000,
+            vscode_port_fallback: true,
             ssh_port: 22,
+            start_vscode: true,
+            allowed_services: vec!["home".into(), "vscode".into(), "ssh".into()],
+            dashboard_tiles: default_dashboard_tiles(),
+            services: vec![],
+            guest_session_secs: None,
+            terminal_enabled: true,
+            disabled_pages: vec![],
             shell_command: None,
+            terminal_read_buffer_bytes: 4096,
+            terminal_pending_frames: 64,
+            vscode_path: None,
+            vscode_data_root: None,
             home_dir: default_home_dir,
             runtime_dir: None,
-            telemetry: true,
+            wwwroot_dir: None,
+            templates_dir: None,
+            templates_hot_reload: false,
+            telemetry: None,
             log: "".into(),
+            user_agent: None,
+            outbound_proxy: None,
+            server_ca_cert_path: vec![],
+            use_webpki_roots_fallback: false,
+            outbound_bind_ip: None,
+            offline: false,
+            trace_protocol: false,
+            quiet: false,
+            config_file: default_config_file(),
         }
     }
 }
 
 impl Config {
+    pub fn page_enabled(&self, page: &str) -> bool {
+        !self.disabled_pages.iter().any(|val| val == page)
+    }
+
     pub fn new(config_file: Option<PathBuf>) -> Result<Self, ConfigError> {
-        let config_file = config_file.unwrap_or_else(|| {
-            let home_dir = dirs::home_dir().unwrap();
-            let config_file_relative = format!("{PORTALBOX_DIR}/{CONFIG_FILE}");
-            home_dir.join(config_file_relative)
-        });
+        let config_file = config_file.unwrap_or_else(default_config_file);
 
-        let file_source = File::from(config_file);
+        let mut builder = ::config::Config::builder()
+            .add_source(File::from(config_file.clone()).required(false))
+            .add_source(Environment::with_prefix(ENV_VAR_PREFIX));
 
-        let ret = ::config::Config::builder()
-            .add_source(file_source.required(false))
-            .add_source(Environment::with_prefix(ENV_VAR_PREFIX))
-            .build()?;
+        // Expand before deserializing, e.g. `server_url` isn't a valid url until then
+        let raw = builder.build_cloned()?;
+        for key in INTERPOLATED_KEYS {
+            if let Ok(val) = raw.get_string(key) {
+                let expanded = expand_env_vars(&val, |name| std::env::var(name).ok())
+                    .map_err(|e| ConfigError::Message(format!("{key}: {e}")))?;
+                builder = builder.set_override(*key, expanded)?;
+            }
+        }
+
+        let ret = builder.build()?;
 
         // You can deserialize (and thus freeze) the entire configuration as
-        ret.try_deserialize()
+        let mut ret: Config = ret.try_deserialize()?;
+        ret.config_file = config_file;
+
+        // Forwarded names end up in `{base_sub_domain}-{name}.portalbox.app`
+        if let Some(service) = ret
+            .services
+            .iter()
+            .find(|val| val.forward && !is_dns_label(&val.name))
+        {
+            return Err(ConfigError::Message(format!(
+                "services: {:?} can't be forwarded, names may only have lowercase letters, digits and inner dashes",
+                service.name
+            )));
+        }
+
+        Ok(ret)
+    }
+
+    pub fn telemetry_enabled(&self) -> bool {
+        self.telemetry.unwrap_or(false)
+    }
+
+    pub async fn save_telemetry(&self, enabled: bool) -> Result<(), anyhow::Error> {
+        self.save_value("telemetry", enabled).await
+    }
+
+    pub async fn save_current_profile(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.save_value("current_profile", name).await
+    }
+
+    // Only sets `key` in the config file, leaving the rest as the user wrote it, comments and
+    // formatting included
+    async fn save_value(
+        &self,
+        key: &str,
+        value: impl Into<toml_edit::Value>,
+    ) -> Result<(), anyhow::Error> {
+        let mut document = match tokio::fs::read_to_string(&self.config_file).await {
+            Ok(content) => content.parse::<toml_edit::Document>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml_edit::Document::new(),
+            Err(e) => return Err(e.into()),
+        };
+        document[key] = toml_edit::value(value);
+
+        if let Some(parent) = self.config_file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        crate::utils::write_atomic(&self.config_file, document.to_string()).await
+    }
+
+    // Switches to the server of `current_profile`, if one is selected
+    pub fn apply_profile(&mut self) -> Result<(), anyhow::Error> {
+        let name = match &self.current_profile {
+            Some(val) => val.clone(),
+            None => return Ok(()),
+        };
+        let profile = self.profiles.get(&name).cloned().ok_or_else(|| {
+            anyhow::anyhow!("Unknown profile {name}, add it to the config under [profiles.{name}]")
+        })?;
+
+        self.set_server_url(profile.server_url)
     }
 
     pub fn server_proxy_url(&self) -> String {
@@ -72,10 +380,108 @@ impl Config {
         format!("{host}:{port}")
     }
 
+    pub fn proxy_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.proxy_connect_timeout_secs)
+    }
+
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_timeout_secs)
+    }
+
+    pub fn tcp_keepalive(&self) -> TcpKeepalive {
+        tcp_keepalive(
+            Duration::from_secs(self.tcp_keepalive_idle_secs),
+            Duration::from_secs(self.tcp_keepalive_interval_secs),
+            self.tcp_keepalive_retries,
+        )
+    }
+
+    // Probes are sent at the TCP layer, the tunnel carries ssh's own byte stream
+    pub fn ssh_tunnel_keepalive(&self) -> TcpKeepalive {
+        match self.ssh_keepalive_secs {
+            Some(secs) => tcp_keepalive(
+                Duration::from_secs(secs),
+                Duration::from_secs(secs),
+                self.tcp_keepalive_retries,
+            ),
+            None => self.tcp_keepalive(),
+        }
+    }
+
+    // Retries forever, the proxy connection is kept up as long as portalbox runs
+    pub fn reconnect_backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoff {
+            randomization_factor: self.backoff_randomization_factor.clamp(0.0, 1.0),
+            max_interval: Duration::from_secs(self.backoff_max_interval_secs.max(1)),
+            max_elapsed_time: None,
+            ..Default::default()
+        }
+    }
+
+    // Where the proxy reaches vscode locally
+    pub fn vscode_local_address(&self) -> SocketAddr {
+        let ip = if self.vscode_host.is_unspecified() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            self.vscode_host
+        };
+        SocketAddr::new(ip, self.vscode_port)
+    }
+
+    pub fn forwarded_service_port(&self, name: &str) -> Option<u16> {
+        self.services
+            .iter()
+            .find(|val| val.forward && val.name == name)
+            .map(|val| val.port)
+    }
+
+    pub fn version_check_interval(&self) -> Option<Duration> {
+        match self.version_check_interval_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn latency_warn_threshold(&self) -> Duration {
+        Duration::from_millis(self.latency_warn_ms)
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(crate::utils::default_user_agent)
+    }
+
     pub fn server_url(&self) -> Url {
         self.server_url.clone()
     }
 
+    // Used for the `--server-url` override, which takes precedence over the env and the file
+    pub fn set_server_url(&mut self, server_url: Url) -> Result<(), anyhow::Error> {
+        if server_url.host().is_none() {
+            return Err(anyhow::anyhow!(
+                "Invalid server url {server_url}, missing host"
+            ));
+        }
+
+        self.server_url = server_url;
+        Ok(())
+    }
+
+    // The same config pointed at `fallback_server_url`, so the http helpers, the proxy address
+    // and the saved credential all follow
+    pub fn fallback_config(&self) -> Option<Config> {
+        let fallback_server_url = self
+            .fallback_server_url
+            .clone()
+            .filter(|val| val.host().is_some() && val != &self.server_url)?;
+
+        let mut ret = self.clone();
+        ret.server_url = fallback_server_url;
+        ret.fallback_server_url = None;
+        Some(ret)
+    }
+
     pub fn server_url_with_path(&self, path: &str) -> Url {
         let mut ret = self.server_url();
         ret.set_path(path);
@@ -92,17 +498,60 @@ impl Config {
         home_dir.join("apps-data")
     }
 
+    pub fn vscode_data_dir(&self) -> PathBuf {
+        match &self.vscode_data_root {
+            Some(val) => val.clone(),
+            None => self.apps_data_dir(),
+        }
+    }
+
     pub fn credentials_file_path(&self) -> PathBuf {
         let home_dir = self.home_dir.clone();
         home_dir.join("credentials.toml")
     }
 
+    pub fn apps_cache_file_path(&self) -> PathBuf {
+        let home_dir = self.home_dir.clone();
+        home_dir.join("apps-cache.toml")
+    }
+
+    pub fn proxy_address_cache_file_path(&self) -> PathBuf {
+        let home_dir = self.home_dir.clone();
+        home_dir.join("proxy-address-cache.toml")
+    }
+
+    // Proves a dashboard request comes from the user's own `portalbox` commands
+    pub fn local_secret_file_path(&self) -> PathBuf {
+        let home_dir = self.home_dir.clone();
+        home_dir.join("local-secret")
+    }
+
+    pub fn pid_file_path(&self) -> PathBuf {
+        let home_dir = self.home_dir.clone();
+        home_dir.join("portalbox.pid")
+    }
+
+    // Output of `start --detach`
+    pub fn daemon_log_file_path(&self) -> PathBuf {
+        let home_dir = self.home_dir.clone();
+        home_dir.join("portalbox.log")
+    }
+
+    // Every dir portalbox writes to: the home dir holds credentials, caches, the pid file and
+    // the log file. `runtime_dir` is only read from
     pub async fn ensure_all_dirs(&self) -> Result<(), anyhow::Error> {
-        let apps_dir = self.apps_dir();
-        let apps_data_dir = self.apps_data_dir();
+        let dirs = [
+            self.home_dir.clone(),
+            self.apps_dir(),
+            self.apps_data_dir(),
+            self.vscode_data_dir(),
+        ];
 
-        let _ = tokio::fs::create_dir_all(apps_dir).await?;
-        let _ = tokio::fs::create_dir_all(apps_data_dir).await?;
+        for dir in dirs {
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .with_context(|| format!("Can't create directory {}", dir.display()))?;
+        }
 
         Ok(())
     }
@@ -126,17 +575,235 @@ impl Config {
         Ok(ret)
     }
 
-    pub async fn show(&self) -> Result<(), anyhow::Error> {
-        let toml_format = toml::to_string_pretty(self)?;
-        println!("{}", toml_format);
+    pub fn wwwroot_dir(&self) -> PathBuf {
+        match &self.wwwroot_dir {
+            Some(val) => val.clone(),
+            None => self.runtime_path("wwwroot"),
+        }
+    }
+
+    pub fn templates_dir(&self) -> PathBuf {
+        match &self.templates_dir {
+            Some(val) => val.clone(),
+            None => self.runtime_path("website/templates"),
+        }
+    }
+
+    // Relative to the working dir when the runtime dir can't be found
+    fn runtime_path(&self, path: &str) -> PathBuf {
+        match self.runtime_dir() {
+            Ok(val) => val.join(path),
+            Err(_) => path.into(),
+        }
+    }
+
+    // A mistyped override would otherwise only show up as 404s on the dashboard. The defaults
+    // aren't checked, `wwwroot` is only there once the dashboard assets are built
+    pub fn ensure_dashboard_dirs(&self) -> Result<(), anyhow::Error> {
+        for (key, dir) in [
+            ("wwwroot_dir", &self.wwwroot_dir),
+            ("templates_dir", &self.templates_dir),
+        ] {
+            let dir = match dir {
+                Some(val) => val,
+                None => continue,
+            };
+            if !dir.is_dir() {
+                return Err(anyhow::anyhow!("{key} {} isn't a directory", dir.display()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn show(&self, format: ConfigFormat) -> Result<(), anyhow::Error> {
+        let formatted = self.to_format_string(format)?;
+        println!("{}", formatted);
         Ok(())
     }
+
+    // For editors to autocomplete and validate `config.toml`
+    pub fn schema() -> Result<String, anyhow::Error> {
+        let schema = schemars::schema_for!(Config);
+        let ret = serde_json::to_string_pretty(&schema)?;
+        Ok(ret)
+    }
+
+    pub fn to_format_string(&self, format: ConfigFormat) -> Result<String, anyhow::Error> {
+        let ret = match format {
+            // Through `toml::Value` so plain values are emitted before the tables
+            ConfigFormat::Toml => toml::to_string_pretty(&toml::Value::try_from(self)?)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(self)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(self)?,
+        };
+        Ok(ret)
+    }
+}
+
+// `~/.portalbox` whenever it holds data, so existing setups are left alone. Otherwise on Linux
+// `XDG_STATE_HOME`, then `XDG_DATA_HOME`, when set. Relative values are invalid per the XDG spec
+// and ignored
+fn default_home_dir(home_dir: &Path, lookup: impl Fn(&str) -> Option<String>) -> PathBuf {
+    let legacy = home_dir.join(PORTALBOX_DIR);
+    if has_data(&legacy) {
+        return legacy;
+    }
+
+    if cfg!(target_os = "linux") {
+        let xdg_dir = ["XDG_STATE_HOME", "XDG_DATA_HOME"]
+            .into_iter()
+            .filter_map(&lookup)
+            .map(PathBuf::from)
+            .find(|val| val.is_absolute());
+        if let Some(val) = xdg_dir {
+            return val.join(XDG_PORTALBOX_DIR);
+        }
+    }
+
+    legacy
+}
+
+// Anything besides the config file, that one stays in `~/.portalbox` with XDG dirs too
+fn has_data(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|entries| entries.flatten().any(|val| val.file_name() != CONFIG_FILE))
+        .unwrap_or_default()
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    let ret = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(val) => vec![val],
+        OneOrMany::Many(val) => val,
+    };
+    Ok(ret)
+}
+
+fn default_config_file() -> PathBuf {
+    let home_dir = dirs::home_dir().unwrap();
+    home_dir.join(PORTALBOX_DIR).join(CONFIG_FILE)
+}
+
+// Interval and retries aren't settable on every platform
+#[allow(unused_variables)]
+fn tcp_keepalive(idle: Duration, interval: Duration, retries: u32) -> TcpKeepalive {
+    let ret = TcpKeepalive::new().with_time(idle);
+
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "netbsd",
+        target_vendor = "apple",
+        windows,
+    ))]
+    let ret = ret.with_interval(interval);
+
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "netbsd",
+        target_vendor = "apple",
+    ))]
+    let ret = ret.with_retries(retries);
+
+    ret
+}
+
+fn expand_env_vars<F>(input: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut ret = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        ret.push_str(&rest[..start]);
+
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("Unclosed ${{ in {input}"))?;
+        let expr = &after[..end];
+
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+
+        // Like the shell's `:-`, an empty value gets the default too
+        let value = lookup(name)
+            .filter(|val| default.is_none() || !val.is_empty())
+            .or_else(|| default.map(|val| val.to_string()))
+            .ok_or_else(|| format!("Undefined environment variable {name}"))?;
+        ret.push_str(&value);
+
+        rest = &after[end + 1..];
+    }
+    ret.push_str(rest);
+
+    Ok(ret)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn test_lookup(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/box".into()),
+            "SERVER_URL" => Some("http://localhost:8080".into()),
+            "BLANK" => Some("".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        assert_eq!(
+            expand_env_vars("${HOME}/.portalbox", test_lookup).unwrap(),
+            "/home/box/.portalbox"
+        );
+        assert_eq!(
+            expand_env_vars("${SERVER_URL}/api", test_lookup).unwrap(),
+            "http://localhost:8080/api"
+        );
+        assert_eq!(
+            expand_env_vars("no vars, $HOME", test_lookup).unwrap(),
+            "no vars, $HOME"
+        );
+    }
+
+    #[test]
+    fn test_expand_env_vars_default() {
+        assert_eq!(
+            expand_env_vars("${LOG_LEVEL:-debug}", test_lookup).unwrap(),
+            "debug"
+        );
+        assert_eq!(
+            expand_env_vars("${HOME:-/tmp}", test_lookup).unwrap(),
+            "/home/box"
+        );
+        assert_eq!(expand_env_vars("${EMPTY:-}", test_lookup).unwrap(), "");
+        // Set but empty
+        assert_eq!(
+            expand_env_vars("${BLANK:-info}", test_lookup).unwrap(),
+            "info"
+        );
+        assert_eq!(expand_env_vars("${BLANK}", test_lookup).unwrap(), "");
+    }
+
+    #[test]
+    fn test_expand_env_vars_errors() {
+        assert!(expand_env_vars("${MISSING}/dir", test_lookup).is_err());
+        assert!(expand_env_vars("${HOME", test_lookup).is_err());
+    }
+
     #[test]
     fn test_server_uris() {
         let config = Config::default();
@@ -159,4 +826,377 @@ mod tests {
             "http://localhost:8080/api/services"
         );
     }
+
+    #[test]
+    fn test_to_format_string() {
+        let config = Config::default();
+
+        let toml_format = config.to_format_string(ConfigFormat::Toml).unwrap();
+        let parsed: toml::Value = toml::from_str(&toml_format).unwrap();
+        assert_eq!(
+            parsed["server_url"].as_str(),
+            Some("https://www.portalbox.app/")
+        );
+
+        let json_format = config.to_format_string(ConfigFormat::Json).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json_format).unwrap();
+        assert_eq!(parsed["server_url"], "https://www.portalbox.app/");
+
+        let yaml_format = config.to_format_string(ConfigFormat::Yaml).unwrap();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&yaml_format).unwrap();
+        assert_eq!(
+            parsed["server_url"].as_str(),
+            Some("https://www.portalbox.app/")
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema: serde_json::Value = serde_json::from_str(&Config::schema().unwrap()).unwrap();
+        let properties = &schema["properties"];
+
+        assert_eq!(properties["server_url"]["type"], "string");
+        assert_eq!(properties["server_url"]["format"], "uri");
+        assert_eq!(
+            properties["server_url"]["default"],
+            "https://www.portalbox.app/"
+        );
+        assert_eq!(properties["vscode_port"]["type"], "integer");
+        assert_eq!(
+            properties["vscode_port"]["description"],
+            "Port vscode listens on"
+        );
+        // A single path or an array
+        assert_eq!(
+            schema["definitions"]["OneOrMany"]["anyOf"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        // Set on the command line only
+        assert!(properties.get("quiet").is_none());
+        assert!(schema["description"]
+            .as_str()
+            .unwrap()
+            .contains("PORTALBOX_<FIELD>"));
+    }
+
+    #[tokio::test]
+    async fn test_save_telemetry() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let config_file = dir.join(CONFIG_FILE);
+
+        let config = Config::new(Some(config_file.clone())).unwrap();
+        assert_eq!(config.telemetry, None);
+        assert!(!config.telemetry_enabled());
+
+        config.save_telemetry(true).await.unwrap();
+        let config = Config::new(Some(config_file.clone())).unwrap();
+        assert_eq!(config.telemetry, Some(true));
+
+        let content = "# Picked by hand\nvscode_port = 3001\ntelemetry = true\n\n[profiles.staging]\nserver_url = \"http://staging.localhost\"\n";
+        std::fs::write(&config_file, content).unwrap();
+        config.save_telemetry(false).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&config_file).unwrap(),
+            content.replace("telemetry = true", "telemetry = false")
+        );
+        let config = Config::new(Some(config_file)).unwrap();
+        assert_eq!(config.telemetry, Some(false));
+        assert_eq!(config.vscode_port, 3001);
+    }
+
+    #[tokio::test]
+    async fn test_ensure_all_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: dir.join("home"),
+            vscode_data_root: Some(dir.join("vscode")),
+            ..Default::default()
+        };
+
+        // Twice, it's fine for the dirs to exist already
+        config.ensure_all_dirs().await.unwrap();
+        config.ensure_all_dirs().await.unwrap();
+        assert!(config.apps_dir().is_dir());
+        assert!(config.apps_data_dir().is_dir());
+        assert!(config.vscode_data_dir().is_dir());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_all_dirs_unwritable() {
+        // A regular file can't have subdirs, regardless of permissions, unlike a read-only dir
+        // which root can still write to
+        let temp_dir = tempfile::tempdir().unwrap();
+        let not_a_dir = temp_dir.path().join("not-a-dir");
+        std::fs::write(&not_a_dir, "").unwrap();
+
+        let config = Config {
+            home_dir: not_a_dir.join("home"),
+            ..Default::default()
+        };
+        let e = config.ensure_all_dirs().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("Can't create directory {}", config.home_dir.display())
+        );
+        assert!(e.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        use backoff::backoff::Backoff;
+
+        let config = Config {
+            backoff_randomization_factor: 0.5,
+            backoff_max_interval_secs: 20,
+            ..Default::default()
+        };
+        let mut backoff = config.reconnect_backoff();
+        assert_eq!(backoff.randomization_factor, 0.5);
+        assert_eq!(backoff.max_interval, Duration::from_secs(20));
+
+        for _ in 0..50 {
+            let interval = backoff.next_backoff().unwrap();
+            assert!(interval <= Duration::from_secs(30));
+        }
+
+        let config = Config {
+            backoff_randomization_factor: 3.0,
+            backoff_max_interval_secs: 0,
+            ..Default::default()
+        };
+        let backoff = config.reconnect_backoff();
+        assert_eq!(backoff.randomization_factor, 1.0);
+        assert_eq!(backoff.max_interval, Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_current_profile_persisted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let config_file = dir.join(CONFIG_FILE);
+        std::fs::write(
+            &config_file,
+            "[profiles.staging]\nserver_url = \"http://staging.localhost:8080\"\n",
+        )
+        .unwrap();
+
+        let mut config = Config::new(Some(config_file.clone())).unwrap();
+        config.apply_profile().unwrap();
+        assert_eq!(config.server_url().as_str(), "https://www.portalbox.app/");
+
+        // As `--profile staging` does, the next run picks it up without the flag
+        config.save_current_profile("staging").await.unwrap();
+        let mut config = Config::new(Some(config_file.clone())).unwrap();
+        assert_eq!(config.current_profile.as_deref(), Some("staging"));
+        config.apply_profile().unwrap();
+        assert_eq!(
+            config.server_url().as_str(),
+            "http://staging.localhost:8080/"
+        );
+
+        config.current_profile = Some("missing".into());
+        assert!(config.apply_profile().is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_default_home_dir_xdg() {
+        let home_dir = Path::new("/home/box");
+        let lookup = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, val)| val.to_string())
+            }
+        };
+
+        let ret = default_home_dir(home_dir, lookup(&[]));
+        assert_eq!(ret, Path::new("/home/box/.portalbox"));
+
+        let ret = default_home_dir(home_dir, lookup(&[("XDG_DATA_HOME", "/srv/data")]));
+        assert_eq!(ret, Path::new("/srv/data/portalbox"));
+
+        let ret = default_home_dir(
+            home_dir,
+            lookup(&[
+                ("XDG_STATE_HOME", "/srv/state"),
+                ("XDG_DATA_HOME", "/srv/data"),
+            ]),
+        );
+        assert_eq!(ret, Path::new("/srv/state/portalbox"));
+
+        let config = Config {
+            home_dir: ret,
+            ..Default::default()
+        };
+        assert_eq!(config.apps_dir(), Path::new("/srv/state/portalbox/apps"));
+        assert_eq!(
+            config.credentials_file_path(),
+            Path::new("/srv/state/portalbox/credentials.toml")
+        );
+
+        let ret = default_home_dir(home_dir, lookup(&[("XDG_STATE_HOME", "relative/state")]));
+        assert_eq!(ret, Path::new("/home/box/.portalbox"));
+
+        // Only the config file, e.g. saved by a fresh install using the XDG dir
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let legacy = home_dir.join(PORTALBOX_DIR);
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join(CONFIG_FILE), "").unwrap();
+        let ret = default_home_dir(&home_dir, lookup(&[("XDG_STATE_HOME", "/srv/state")]));
+        assert_eq!(ret, Path::new("/srv/state/portalbox"));
+
+        // Existing installs keep their data where it is
+        std::fs::create_dir_all(legacy.join("apps")).unwrap();
+        let ret = default_home_dir(&home_dir, lookup(&[("XDG_STATE_HOME", "/srv/state")]));
+        assert_eq!(ret, legacy);
+    }
+
+    #[test]
+    fn test_vscode_data_dir() {
+        let config = Config {
+            home_dir: "/home/user/.portalbox".into(),
+            ..Default::default()
+        };
+        assert_eq!(config.vscode_data_dir(), config.home_dir.join("apps-data"));
+
+        let config = Config {
+            vscode_data_root: Some("/mnt/large/vscode".into()),
+            ..config
+        };
+        assert_eq!(config.vscode_data_dir(), PathBuf::from("/mnt/large/vscode"));
+        assert_eq!(config.apps_data_dir(), config.home_dir.join("apps-data"));
+    }
+
+    #[test]
+    fn test_forwarded_service_names() {
+        for name in ["grafana", "app-2", "a"] {
+            assert!(is_dns_label(name), "{name}");
+        }
+        for name in [
+            "",
+            "-app",
+            "app-",
+            "Grafana",
+            "my_app",
+            "app.local",
+            &"a".repeat(64),
+        ] {
+            assert!(!is_dns_label(name), "{name}");
+        }
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let config_file = dir.join(CONFIG_FILE);
+
+        // Only checked when forwarded
+        std::fs::write(
+            &config_file,
+            "[[services]]\nname = \"My App\"\nport = 3001\n",
+        )
+        .unwrap();
+        Config::new(Some(config_file.clone())).unwrap();
+
+        std::fs::write(
+            &config_file,
+            "[[services]]\nname = \"My App\"\nport = 3001\nforward = true\n",
+        )
+        .unwrap();
+        let e = Config::new(Some(config_file)).unwrap_err();
+        assert!(e.to_string().contains("My App"), "{e}");
+    }
+
+    #[test]
+    fn test_server_ca_cert_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let config_file = dir.join(CONFIG_FILE);
+
+        let config = Config::new(Some(config_file.clone())).unwrap();
+        assert!(config.server_ca_cert_path.is_empty());
+
+        std::fs::write(&config_file, "server_ca_cert_path = \"/etc/ca/a.pem\"\n").unwrap();
+        let config = Config::new(Some(config_file.clone())).unwrap();
+        assert_eq!(config.server_ca_cert_path, [Path::new("/etc/ca/a.pem")]);
+
+        std::fs::write(
+            &config_file,
+            "server_ca_cert_path = [\"/etc/ca/a.pem\", \"/etc/ca/b.pem\"]\n",
+        )
+        .unwrap();
+        let config = Config::new(Some(config_file)).unwrap();
+        assert_eq!(
+            config.server_ca_cert_path,
+            [Path::new("/etc/ca/a.pem"), Path::new("/etc/ca/b.pem")]
+        );
+    }
+
+    #[test]
+    fn test_dashboard_dirs() {
+        let config = Config {
+            runtime_dir: Some("/opt/portalbox".into()),
+            ..Default::default()
+        };
+        assert_eq!(config.wwwroot_dir(), Path::new("/opt/portalbox/wwwroot"));
+        assert_eq!(
+            config.templates_dir(),
+            Path::new("/opt/portalbox/website/templates")
+        );
+        config.ensure_dashboard_dirs().unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        std::fs::create_dir_all(dir.join("templates")).unwrap();
+        let config = Config {
+            wwwroot_dir: Some(dir.join("wwwroot")),
+            templates_dir: Some(dir.join("templates")),
+            ..config
+        };
+        assert_eq!(config.templates_dir(), dir.join("templates"));
+        let e = config.ensure_dashboard_dirs().unwrap_err();
+        assert!(e.to_string().starts_with("wwwroot_dir "));
+
+        std::fs::create_dir_all(dir.join("wwwroot")).unwrap();
+        config.ensure_dashboard_dirs().unwrap();
+    }
+
+    #[test]
+    fn test_fallback_config() {
+        let mut config = Config::default();
+        assert!(config.fallback_config().is_none());
+
+        config.fallback_server_url = Some(Url::parse("https://backup.example.com").unwrap());
+        let fallback = config.fallback_config().unwrap();
+        assert_eq!(
+            fallback.server_url_with_path("api/apps").as_str(),
+            "https://backup.example.com/api/apps"
+        );
+        assert_eq!(fallback.server_proxy_url(), "backup.example.com:46637");
+        assert_eq!(fallback.fallback_server_url, None);
+
+        // Failing over to the same server is pointless
+        config.fallback_server_url = Some(config.server_url());
+        assert!(config.fallback_config().is_none());
+    }
+
+    #[test]
+    fn test_set_server_url() {
+        let mut config = Config::default();
+        config
+            .set_server_url(Url::parse("http://localhost:8080").unwrap())
+            .unwrap();
+        assert_eq!(config.server_url().as_str(), "http://localhost:8080/");
+        assert_eq!(config.server_proxy_url(), "localhost:46637");
+
+        assert!(config
+            .set_server_url(Url::parse("unix:/run/portalbox.sock").unwrap())
+            .is_err());
+        assert_eq!(config.server_url().as_str(), "http://localhost:8080/");
+    }
 }
@@ -1,40 +1,136 @@
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::{config::Config, utils::write_atomic};
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct CredManager {
     pub credentials: HashMap<String, Credential>,
 }
 
+// Several portalbox instances may share the same credentials file, writers hold an exclusive
+// lock on `credentials.toml.lock` so writes are serialized, readers only need a shared one.
+// Updates like `insert` and `remove` hold it from the read to the write.
 impl CredManager {
-    pub async fn save(&self, config: &Config) -> Result<(), anyhow::Error> {
-        let contents = toml::to_string_pretty(self)?;
-
-        let filepath = config.credentials_file_path();
-        let _ = tokio::fs::write(filepath, contents).await?;
-        Ok(())
-    }
-
     pub async fn load(config: &Config) -> Result<CredManager, anyhow::Error> {
         // Load previously saved session
+        let _lock = lock(config, false).await?;
         let filepath = config.credentials_file_path();
         let file_content = tokio::fs::read_to_string(filepath).await?;
 
-        let session = toml::from_str(&file_content)?;
+        let mut session: CredManager = toml::from_str(&file_content)?;
+        session.after_load();
 
         Ok(session)
     }
 
+    // Missing file means no credentials, an unparseable one is backed up before starting empty
+    pub async fn load_or_recover(config: &Config) -> Result<CredManager, anyhow::Error> {
+        // Exclusive as the file may be moved away
+        let _lock = lock(config, true).await?;
+        Self::load_or_recover_locked(config).await
+    }
+
+    // Adds or replaces the credential for `server_url`, keeping what another instance saved
+    pub async fn insert(
+        config: &Config,
+        server_url: &str,
+        credential: Credential,
+    ) -> Result<(), anyhow::Error> {
+        let _lock = lock(config, true).await?;
+        let mut session = Self::load_or_recover_locked(config).await?;
+        session
+            .credentials
+            .insert(server_url.to_string(), credential);
+
+        let contents = toml::to_string_pretty(&session)?;
+        write_atomic(config.credentials_file_path(), contents).await?;
+        Ok(())
+    }
+
+    // The caller holds the exclusive lock
+    async fn load_or_recover_locked(config: &Config) -> Result<CredManager, anyhow::Error> {
+        let filepath = config.credentials_file_path();
+        let file_content = match tokio::fs::read_to_string(&filepath).await {
+            Ok(val) => val,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::empty()),
+            Err(e) => return Err(e.into()),
+        };
+
+        match toml::from_str::<CredManager>(&file_content) {
+            Ok(mut val) => {
+                val.after_load();
+                Ok(val)
+            }
+            Err(e) => {
+                let backup_path = filepath.with_extension("toml.bak");
+                tokio::fs::rename(&filepath, &backup_path).await?;
+                tracing::warn!(
+                    ?e,
+                    ?backup_path,
+                    "Credentials file is corrupt, backed up and starting without credentials"
+                );
+                Ok(Self::empty())
+            }
+        }
+    }
+
+    // Returns whether there was a credential for `server_url`, other servers' are kept as is
+    pub async fn remove(config: &Config, server_url: &str) -> Result<bool, anyhow::Error> {
+        let _lock = lock(config, true).await?;
+        let filepath = config.credentials_file_path();
+        let file_content = match tokio::fs::read_to_string(&filepath).await {
+            Ok(val) => val,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut session: CredManager = toml::from_str(&file_content)?;
+        if session.credentials.remove(server_url).is_none() {
+            return Ok(false);
+        }
+
+        let contents = toml::to_string_pretty(&session)?;
+        write_atomic(filepath, contents).await?;
+        Ok(true)
+    }
+
     pub async fn delete(config: &Config) -> Result<(), anyhow::Error> {
+        let _lock = lock(config, true).await?;
         let filepath = config.credentials_file_path();
         let _ = tokio::fs::remove_file(filepath).await?;
         Ok(())
     }
 
+    fn after_load(&mut self) {
+        self.prune_expired();
+
+        // Credentials saved by older versions only have the server url as the map key
+        for (server_url, credential) in self.credentials.iter_mut() {
+            let credential_server_url = match credential {
+                Credential::User(val) => &mut val.server_url,
+                Credential::Guest(val) => &mut val.server_url,
+            };
+            if credential_server_url.is_none() {
+                *credential_server_url = Some(server_url.clone());
+            }
+        }
+    }
+
+    pub fn prune_expired(&mut self) {
+        self.credentials.retain(|server_url, credential| {
+            let expired = credential.is_expired();
+            if expired {
+                tracing::info!(%server_url, "Guest session expired, signing out");
+            }
+            !expired
+        });
+    }
+
     pub fn empty() -> Self {
         Self {
             credentials: HashMap::new(),
@@ -42,6 +138,30 @@ impl CredManager {
     }
 }
 
+// The lock is released when the returned file is dropped
+async fn lock(config: &Config, exclusive: bool) -> Result<std::fs::File, anyhow::Error> {
+    let lock_path = config.credentials_file_path().with_extension("toml.lock");
+
+    let ret = tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+
+        if exclusive {
+            file.lock_exclusive()?;
+        } else {
+            file.lock_shared()?;
+        }
+
+        Ok::<_, std::io::Error>(file)
+    })
+    .await??;
+
+    Ok(ret)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum Credential {
@@ -71,6 +191,46 @@ impl Credential {
             Credential::Guest(val) => &val.base_sub_domain,
         }
     }
+
+    // Where the box's home service is reachable once the proxy is up
+    pub fn home_url(&self) -> String {
+        format!("https://{}-home.portalbox.app", self.base_sub_domain())
+    }
+
+    pub fn signed_in_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Credential::User(val) => val.signed_in_at,
+            Credential::Guest(val) => val.signed_in_at,
+        }
+    }
+
+    pub fn server_url(&self) -> Option<&str> {
+        match self {
+            Credential::User(val) => val.server_url.as_deref(),
+            Credential::Guest(val) => val.server_url.as_deref(),
+        }
+    }
+
+    pub fn email(&self) -> Option<&str> {
+        match self {
+            Credential::User(val) => Some(&val.email),
+            Credential::Guest(_) => None,
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Credential::User(_) => None,
+            Credential::Guest(val) => val.expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at() {
+            Some(val) => val <= Utc::now(),
+            None => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -79,14 +239,25 @@ pub struct UserCredential {
     #[serde(serialize_with = "models::serialize_secret_string")]
     pub client_access_token: SecretString,
     pub base_sub_domain: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_in_at: Option<DateTime<Utc>>,
 }
 
 impl UserCredential {
-    pub fn new(email: String, client_access_token: SecretString, base_sub_domain: String) -> Self {
+    pub fn new(
+        email: String,
+        client_access_token: SecretString,
+        base_sub_domain: String,
+        server_url: String,
+    ) -> Self {
         Self {
             email,
             client_access_token,
             base_sub_domain,
+            server_url: Some(server_url),
+            signed_in_at: Some(Utc::now()),
         }
     }
 }
@@ -98,6 +269,12 @@ pub struct GuestCredential {
     pub client_access_token: SecretString,
     #[serde(serialize_with = "models::serialize_secret_string")]
     pub access_code: SecretString,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_in_at: Option<DateTime<Utc>>,
 }
 
 impl GuestCredential {
@@ -105,11 +282,180 @@ impl GuestCredential {
         base_sub_domain: String,
         client_access_token: SecretString,
         access_code: SecretString,
+        expires_at: Option<DateTime<Utc>>,
+        server_url: String,
     ) -> Self {
         Self {
             base_sub_domain,
             client_access_token,
             access_code,
+            expires_at,
+            server_url: Some(server_url),
+            signed_in_at: Some(Utc::now()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // The config's home is deleted with the returned dir
+    fn test_config() -> (tempfile::TempDir, Config) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            home_dir: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        (temp_dir, config)
+    }
+
+    fn guest(expires_at: Option<DateTime<Utc>>) -> Credential {
+        Credential::new_guest(GuestCredential::new(
+            "guest".into(),
+            SecretString::new("token".into()),
+            SecretString::new("code".into()),
+            expires_at,
+            "https://www.portalbox.app/".into(),
+        ))
+    }
+
+    #[test]
+    fn test_guest_expiry() {
+        assert!(!guest(None).is_expired());
+        assert!(!guest(Some(Utc::now() + chrono::Duration::hours(1))).is_expired());
+        assert!(guest(Some(Utc::now() - chrono::Duration::seconds(1))).is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_load_prunes_expired_guest() {
+        let (_temp_dir, config) = test_config();
+
+        let valid = guest(Some(Utc::now() + chrono::Duration::hours(1)));
+        CredManager::insert(&config, "valid", valid).await.unwrap();
+        CredManager::insert(&config, "no-expiry", guest(None))
+            .await
+            .unwrap();
+        // Last, an insert after it would already prune it
+        let expired = guest(Some(Utc::now() - chrono::Duration::seconds(1)));
+        CredManager::insert(&config, "expired", expired)
+            .await
+            .unwrap();
+
+        let ret = CredManager::load(&config).await.unwrap();
+        let mut server_urls: Vec<_> = ret.credentials.keys().cloned().collect();
+        server_urls.sort();
+        assert_eq!(server_urls, vec!["no-expiry", "valid"]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_single_server() {
+        let (_temp_dir, config) = test_config();
+        assert!(!CredManager::remove(&config, "https://a.example.com/")
+            .await
+            .unwrap());
+
+        for server_url in ["https://a.example.com/", "https://b.example.com/"] {
+            CredManager::insert(&config, server_url, guest(None))
+                .await
+                .unwrap();
+        }
+
+        assert!(CredManager::remove(&config, "https://a.example.com/")
+            .await
+            .unwrap());
+        assert!(!CredManager::remove(&config, "https://a.example.com/")
+            .await
+            .unwrap());
+
+        let ret = CredManager::load(&config).await.unwrap();
+        let server_urls: Vec<_> = ret.credentials.keys().cloned().collect();
+        assert_eq!(server_urls, vec!["https://b.example.com/"]);
+    }
+
+    #[tokio::test]
+    async fn test_load_fills_server_url() {
+        let (_temp_dir, config) = test_config();
+        std::fs::write(
+            config.credentials_file_path(),
+            "[credentials.\"https://www.portalbox.app/\"]\ntype = \"User\"\nemail = \"x@y.z\"\nclient_access_token = \"token\"\nbase_sub_domain = \"xyz\"\n",
+        )
+        .unwrap();
+
+        let ret = CredManager::load(&config).await.unwrap();
+        let credential = &ret.credentials["https://www.portalbox.app/"];
+        assert_eq!(credential.server_url(), Some("https://www.portalbox.app/"));
+        assert_eq!(credential.signed_in_at(), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_with_shared_lock_held() {
+        let (_temp_dir, config) = test_config();
+        CredManager::insert(&config, "https://a.example.com/", guest(None))
+            .await
+            .unwrap();
+
+        // Another instance reading at the same time
+        let other = lock(&config, false).await.unwrap();
+
+        let ret = tokio::time::timeout(Duration::from_secs(5), CredManager::load(&config))
+            .await
+            .expect("shared locks shouldn't block each other");
+        assert_eq!(ret.unwrap().credentials.len(), 1);
+
+        drop(other);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_inserts() {
+        let (_temp_dir, config) = test_config();
+
+        // Like instances signing in to different servers at the same time
+        let handles: Vec<_> = (0..8)
+            .map(|val| {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let server_url = format!("https://{val}.example.com/");
+                    CredManager::insert(&config, &server_url, guest(None)).await
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        let ret = CredManager::load(&config).await.unwrap();
+        assert_eq!(ret.credentials.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_load_or_recover_missing_file() {
+        let (_temp_dir, config) = test_config();
+
+        let ret = CredManager::load_or_recover(&config).await.unwrap();
+        assert!(ret.credentials.is_empty());
+        assert!(!config
+            .credentials_file_path()
+            .with_extension("toml.bak")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_load_or_recover_corrupt_file() {
+        let (_temp_dir, config) = test_config();
+        let filepath = config.credentials_file_path();
+        std::fs::write(
+            &filepath,
+            "[credentials.\"https://www.portalbox.app/\"\ntype = ",
+        )
+        .unwrap();
+
+        let ret = CredManager::load_or_recover(&config).await.unwrap();
+        assert!(ret.credentials.is_empty());
+        assert!(!filepath.exists());
+
+        let backup = std::fs::read_to_string(filepath.with_extension("toml.bak")).unwrap();
+        assert!(backup.starts_with("[credentials."));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+
+const STOP_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Re-runs the current command in the background, detached from the terminal
+pub fn detach(config: &Config) -> Result<(), anyhow::Error> {
+    if let Some(pid) = running_pid(config) {
+        return Err(anyhow::anyhow!("portalbox is already running, pid {pid}"));
+    }
+
+    let log_file_path = config.daemon_log_file_path();
+    let log_file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_file_path)?;
+
+    let args = std::env::args_os().skip(1).filter(|val| val != "--detach");
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file);
+
+    let child = spawn_detached(command)?;
+    let pid = child.id();
+
+    std::fs::write(config.pid_file_path(), pid.to_string())?;
+
+    println!("portalbox started in the background, pid {pid}");
+    println!("Logs: {}", log_file_path.display());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn spawn_detached(mut command: Command) -> Result<std::process::Child, anyhow::Error> {
+    use std::os::unix::process::CommandExt;
+
+    // A new session, so closing the terminal or ssh session doesn't send SIGHUP
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    Ok(command.spawn()?)
+}
+
+#[cfg(not(unix))]
+fn spawn_detached(_command: Command) -> Result<std::process::Child, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "--detach isn't supported on Windows, please run portalbox with a service manager instead"
+    ))
+}
+
+// Asks the background instance to shut down gracefully, same as Ctrl-C in the foreground
+pub async fn stop(config: &Config) -> Result<(), anyhow::Error> {
+    let pid = match running_pid(config) {
+        Some(val) => val,
+        None => {
+            let _ = std::fs::remove_file(config.pid_file_path());
+            println!("portalbox isn't running in the background");
+            return Ok(());
+        }
+    };
+
+    terminate(pid)?;
+
+    let started = Instant::now();
+    while is_running(pid) {
+        if started.elapsed() > STOP_TIMEOUT {
+            return Err(anyhow::anyhow!(
+                "portalbox didn't stop within {:?}, pid {pid}",
+                STOP_TIMEOUT
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let _ = std::fs::remove_file(config.pid_file_path());
+    println!("portalbox stopped, pid {pid}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> Result<(), anyhow::Error> {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn terminate(_pid: u32) -> Result<(), anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "stop isn't supported on Windows, please stop portalbox with its service manager"
+    ))
+}
+
+// Resolves when `stop` is run against this process
+pub async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut val) => {
+                val.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(?e, "Can't listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await;
+}
+
+// Only removes the pid file if it was written for this process
+pub fn remove_own_pid_file(config: &Config) {
+    let pid_file_path = config.pid_file_path();
+    let own = std::fs::read_to_string(&pid_file_path)
+        .map(|val| val.trim() == std::process::id().to_string())
+        .unwrap_or_default();
+
+    if own {
+        let _ = std::fs::remove_file(pid_file_path);
+    }
+}
+
+pub fn status(config: &Config) -> Result<(), anyhow::Error> {
+    match running_pid(config) {
+        Some(pid) => {
+            println!("portalbox is running, pid {pid}");
+            println!("Dashboard: {}", crate::browser::dashboard_url(config));
+        }
+        None => println!("portalbox isn't running in the background"),
+    }
+
+    Ok(())
+}
+
+pub fn logs(config: &Config) -> Result<(), anyhow::Error> {
+    let log_file_path = config.daemon_log_file_path();
+    let logs = std::fs::read_to_string(&log_file_path)
+        .map_err(|e| anyhow::anyhow!("Can't read logs at {}: {}", log_file_path.display(), e))?;
+
+    print!("{logs}");
+
+    Ok(())
+}
+
+// The pid from the pid file, if that process is still alive
+pub fn running_pid(config: &Config) -> Option<u32> {
+    let pid = std::fs::read_to_string(config.pid_file_path())
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    if is_running(pid) {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
@@ -0,0 +1,45 @@
+use crate::{cache, config::Config, whoami};
+
+// What `start` would dial, without starting anything
+pub async fn proxy_target(config: &Config) -> anyhow::Result<()> {
+    let server_proxy_url = config.server_proxy_url();
+    println!("server_proxy_url = {server_proxy_url}");
+
+    let resolved = tokio::net::lookup_host(&server_proxy_url)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to resolve {server_proxy_url}: {e}"))?
+        .collect::<Vec<_>>();
+    for addr in &resolved {
+        println!("resolved         = {addr}");
+    }
+
+    // `start` prefers the cached address and refreshes it in the background
+    let cached = cache::load_proxy_address(config).await.ok();
+    match cached {
+        Some(addr) => println!("cached           = {addr}"),
+        None => println!("cached           = none"),
+    }
+
+    let dial = cached
+        .or_else(|| resolved.first().copied())
+        .ok_or(anyhow::anyhow!("{server_proxy_url} resolved to no address"))?;
+    println!("dial             = {dial}");
+
+    // The server picks the hostname when approving the proxy service, asking it again would
+    // approve a new one, so only a running portalbox can tell
+    let status = whoami::dashboard_status(config).await;
+    match status
+        .as_ref()
+        .and_then(|val| val["service_status"].as_object())
+    {
+        Some(services) if !services.is_empty() => {
+            for hostname in services.keys() {
+                println!("sni              = {hostname}");
+            }
+        }
+        Some(_) => println!("sni              = unknown, portalbox isn't connected"),
+        None => println!("sni              = unknown, portalbox isn't running"),
+    }
+
+    Ok(())
+}
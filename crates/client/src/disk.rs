@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use crate::{client_instance, config::Config};
+
+#[derive(Debug, Serialize)]
+struct DiskUsage {
+    name: String,
+    path: PathBuf,
+    bytes: u64,
+    // Already counted in its parent, e.g. a vscode version in apps_dir
+    #[serde(skip)]
+    nested: bool,
+}
+
+// What takes space in the home dir, to decide what to reset
+pub async fn show(config: &Config, json: bool) -> Result<(), anyhow::Error> {
+    let usage = disk_usage(config).await?;
+    let total_bytes = total_bytes(&usage);
+
+    if json {
+        let ret = serde_json::json!({
+            "entries": usage,
+            "total_bytes": total_bytes,
+        });
+        println!("{}", serde_json::to_string_pretty(&ret)?);
+        return Ok(());
+    }
+
+    for val in &usage {
+        println!(
+            "{:<40} {:>10}  {}",
+            val.name,
+            HumanBytes(val.bytes).to_string(),
+            val.path.display()
+        );
+    }
+    println!(
+        "{:<40} {:>10}",
+        "total",
+        HumanBytes(total_bytes).to_string()
+    );
+
+    Ok(())
+}
+
+// Largest first, missing paths count as empty
+async fn disk_usage(config: &Config) -> Result<Vec<DiskUsage>, anyhow::Error> {
+    let apps_dir = config.apps_dir();
+    let mut paths = vec![
+        ("apps_dir".to_string(), apps_dir.clone(), false),
+        ("apps_data_dir".to_string(), config.apps_data_dir(), false),
+        ("logs".to_string(), config.daemon_log_file_path(), false),
+        (
+            "credentials".to_string(),
+            config.credentials_file_path(),
+            false,
+        ),
+    ];
+    if let Some(vscode_data_root) = &config.vscode_data_root {
+        paths.push(("vscode_data_root".into(), vscode_data_root.clone(), false));
+    }
+    if apps_dir.exists() {
+        for dir in client_instance::all_vscode_dirs(&apps_dir).await? {
+            paths.push((dir.clone(), apps_dir.join(dir), true));
+        }
+    }
+
+    let mut ret = tokio::task::spawn_blocking(move || {
+        paths
+            .into_iter()
+            .map(|(name, path, nested)| DiskUsage {
+                bytes: path_size(&path),
+                name,
+                path,
+                nested,
+            })
+            .collect::<Vec<_>>()
+    })
+    .await?;
+    ret.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(ret)
+}
+
+fn total_bytes(usage: &[DiskUsage]) -> u64 {
+    usage
+        .iter()
+        .filter(|val| !val.nested)
+        .map(|val| val.bytes)
+        .sum()
+}
+
+// Symlinks aren't followed, so nothing is counted twice
+pub fn path_size(path: &Path) -> u64 {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(val) => val,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|val| val.ok())
+                .map(|val| path_size(&val.path()))
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            ..Default::default()
+        };
+
+        let old = config.apps_dir().join("portalbox-vscode-1.0.0-linux-x64");
+        let new = config.apps_dir().join("portalbox-vscode-1.2.3-linux-x64");
+        std::fs::create_dir_all(old.join("bin")).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        std::fs::write(old.join("bin/code"), [0u8; 300]).unwrap();
+        std::fs::write(new.join("code"), [0u8; 200]).unwrap();
+        std::fs::create_dir_all(config.apps_data_dir()).unwrap();
+        std::fs::write(config.apps_data_dir().join("data"), [0u8; 100]).unwrap();
+        std::fs::write(config.credentials_file_path(), [0u8; 10]).unwrap();
+
+        let ret = disk_usage(&config).await.unwrap();
+        let sizes: Vec<_> = ret
+            .iter()
+            .map(|val| (val.name.as_str(), val.bytes))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                ("apps_dir", 500),
+                ("portalbox-vscode-1.0.0-linux-x64", 300),
+                ("portalbox-vscode-1.2.3-linux-x64", 200),
+                ("apps_data_dir", 100),
+                ("credentials", 10),
+                ("logs", 0),
+            ]
+        );
+        assert_eq!(total_bytes(&ret), 610);
+    }
+}
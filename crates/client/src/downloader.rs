@@ -1,38 +1,413 @@
 use std::io::Write;
-use std::{cmp::min, path::Path};
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
+use anyhow::Context;
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use reqwest::Client;
 
-pub async fn download_file<P: AsRef<Path>>(url: &str, path: P) -> Result<(), anyhow::Error> {
-    let client = Client::new();
+// Overrides the client's default timeout, downloading vscode takes a while on slow networks
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// Where `download_file_with_reporter` reports how far along it is, bytes everywhere
+pub trait ProgressReporter: Send + Sync {
+    fn set_total(&self, total: u64);
+    fn inc(&self, delta: u64);
+    fn finish(&self);
+    // The download failed midway
+    fn abandon(&self) {}
+}
+
+// The CLI progress bar
+pub struct IndicatifReporter {
+    pb: ProgressBar,
+}
+
+impl IndicatifReporter {
+    pub fn new(max_bytes: u64) -> Self {
+        let pb = ProgressBar::new(0);
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .progress_chars("#>-"));
+        pb.set_message(format!("Downloading... (max {})", HumanBytes(max_bytes)));
+
+        Self { pb }
+    }
+}
+
+impl ProgressReporter for IndicatifReporter {
+    fn set_total(&self, total: u64) {
+        self.pb.set_length(total);
+    }
+
+    fn inc(&self, delta: u64) {
+        self.pb.inc(delta);
+    }
+
+    fn finish(&self) {
+        self.pb.finish_with_message("Downloaded");
+    }
+
+    fn abandon(&self) {
+        self.pb.abandon_with_message("Download aborted");
+    }
+}
+
+pub struct NoopReporter;
+
+impl ProgressReporter for NoopReporter {
+    fn set_total(&self, _total: u64) {}
+    fn inc(&self, _delta: u64) {}
+    fn finish(&self) {}
+}
+
+// The progress bar unless `quiet`, and `on_progress` gets the downloaded and total bytes after every chunk
+struct DefaultReporter<'a> {
+    bar: Box<dyn ProgressReporter>,
+    on_progress: &'a (dyn Fn(u64, u64) + Sync),
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ProgressReporter for DefaultReporter<'_> {
+    fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.bar.set_total(total);
+    }
+
+    fn inc(&self, delta: u64) {
+        let done = self.done.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.bar.inc(delta);
+        let total = self.total.load(Ordering::Relaxed);
+        (self.on_progress)(done.min(total), total);
+    }
+
+    fn finish(&self) {
+        self.bar.finish();
+    }
+
+    fn abandon(&self) {
+        self.bar.abandon();
+    }
+}
+
+pub async fn download_file<P: AsRef<Path>>(
+    client: &Client,
+    url: &str,
+    path: P,
+    max_bytes: u64,
+    quiet: bool,
+    on_progress: &(dyn Fn(u64, u64) + Sync),
+) -> Result<(), anyhow::Error> {
+    let reporter = DefaultReporter {
+        bar: if quiet {
+            Box::new(NoopReporter)
+        } else {
+            Box::new(IndicatifReporter::new(max_bytes))
+        },
+        on_progress,
+        done: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+    };
+    download_file_with_reporter(client, url, path, max_bytes, &reporter).await
+}
+
+pub async fn download_file_with_reporter<P: AsRef<Path>>(
+    client: &Client,
+    url: &str,
+    path: P,
+    max_bytes: u64,
+    reporter: &dyn ProgressReporter,
+) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+
+    let ret = download_file_inner(client, url, path, max_bytes, reporter).await;
+    if ret.is_err() {
+        reporter.abandon();
+        if path.exists() {
+            // Don't leave a partial file behind
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    ret
+}
+
+// Redirects are followed, an error page at the end of them must not be saved as the archive
+fn check_response(res: &reqwest::Response) -> Result<(), anyhow::Error> {
+    if !res.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Download failed with status {} from {}",
+            res.status(),
+            res.url()
+        ));
+    }
+
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("text/html") {
+        return Err(anyhow::anyhow!(
+            "Download from {} returned an html page instead of an archive",
+            res.url()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn download_file_inner(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    max_bytes: u64,
+    reporter: &dyn ProgressReporter,
+) -> Result<(), anyhow::Error> {
     // Reqwest setup
-    let res = client.get(url).send().await?;
+    let res = client
+        .get(url)
+        .timeout(DOWNLOAD_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("Can't download {url}"))?;
+    check_response(&res)?;
+
     let total_size = res
         .content_length()
         .ok_or(anyhow::anyhow!("Failed to get content length"))?;
 
-    // Indicatif setup
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-        .progress_chars("#>-"));
-    pb.set_message("Downloading...");
+    if total_size > max_bytes {
+        return Err(anyhow::anyhow!(
+            "Download size {} exceeds the maximum of {}",
+            HumanBytes(total_size),
+            HumanBytes(max_bytes)
+        ));
+    }
+    reporter.set_total(total_size);
 
     // download chunks
     let mut file = std::fs::File::create(path)?;
-    let mut downloaded: u64 = 0;
+    let mut received: u64 = 0;
     let mut stream = res.bytes_stream();
 
     while let Some(item) = stream.next().await {
         let chunk = item?;
+
+        received += chunk.len() as u64;
+        if received > max_bytes {
+            return Err(anyhow::anyhow!(
+                "Download exceeded the maximum of {}",
+                HumanBytes(max_bytes)
+            ));
+        }
+
         file.write_all(&chunk)?;
-        let new = min(downloaded + (chunk.len() as u64), total_size);
-        downloaded = new;
-        pb.set_position(new);
+        reporter.inc(chunk.len() as u64);
     }
 
-    pb.finish_with_message("Downloaded");
+    reporter.finish();
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Total(u64),
+        Inc(u64),
+        Finish,
+        Abandon,
+    }
+
+    #[derive(Default)]
+    struct RecordingReporter(Mutex<Vec<Event>>);
+
+    impl ProgressReporter for RecordingReporter {
+        fn set_total(&self, total: u64) {
+            self.0.lock().unwrap().push(Event::Total(total));
+        }
+
+        fn inc(&self, delta: u64) {
+            self.0.lock().unwrap().push(Event::Inc(delta));
+        }
+
+        fn finish(&self) {
+            self.0.lock().unwrap().push(Event::Finish);
+        }
+
+        fn abandon(&self) {
+            self.0.lock().unwrap().push(Event::Abandon);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_exceeds_max_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/vscode.tar.gz", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 1024\r\n\r\n")
+                .await;
+            let _ = stream.write_all(&[0u8; 1024]).await;
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("vscode.tar.gz");
+        let ret =
+            download_file_with_reporter(&Client::new(), &url, &path, 512, &NoopReporter).await;
+
+        assert!(ret.is_err());
+        assert!(!path.exists());
+
+        // Too large from the start, so nothing was reported but the failure
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/vscode.tar.gz", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 1024\r\n\r\n")
+                .await;
+        });
+        let reporter = RecordingReporter::default();
+        let ret = download_file_with_reporter(&Client::new(), &url, &path, 512, &reporter).await;
+        assert!(ret.is_err());
+        assert_eq!(reporter.0.into_inner().unwrap(), [Event::Abandon]);
+    }
+
+    #[tokio::test]
+    async fn test_download_error_page() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let responses: [&[u8]; 3] = [
+                // Redirected to a sign in page
+                b"HTTP/1.1 302 Found\r\nlocation: /signin\r\ncontent-length: 0\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\ncontent-length: 13\r\n\r\n<html></html>",
+                b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n",
+            ];
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("vscode.tar.gz");
+        let url = format!("http://{addr}/vscode.tar.gz");
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(0)
+            .build()
+            .unwrap();
+
+        let ret = download_file_with_reporter(&client, &url, &path, 8192, &NoopReporter).await;
+        let err = ret.unwrap_err().to_string();
+        assert!(err.contains("html page"), "{err}");
+        assert!(err.contains("/signin"), "{err}");
+        assert!(!path.exists());
+
+        let ret = download_file_with_reporter(&client, &url, &path, 8192, &NoopReporter).await;
+        let err = ret.unwrap_err().to_string();
+        assert!(err.contains("404"), "{err}");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_redirect_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/vscode.tar.gz", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 302 Found\r\nlocation: /vscode.tar.gz\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("vscode.tar.gz");
+        let client = crate::utils::http_client(&crate::config::Config::default()).unwrap();
+        let ret = download_file_with_reporter(&client, &url, &path, 8192, &NoopReporter).await;
+        assert!(ret.is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_progress() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/vscode.tar.gz", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 4096\r\nconnection: close\r\n\r\n",
+                    )
+                    .await;
+                // Separate chunks, so progress is reported more than once
+                for _ in 0..4 {
+                    let _ = stream.write_all(&[0u8; 1024]).await;
+                    let _ = stream.flush().await;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("vscode.tar.gz");
+        let reporter = RecordingReporter::default();
+        download_file_with_reporter(&Client::new(), &url, &path, 8192, &reporter)
+            .await
+            .unwrap();
+
+        let events = reporter.0.into_inner().unwrap();
+        assert_eq!(events.first(), Some(&Event::Total(4096)));
+        assert_eq!(events.last(), Some(&Event::Finish));
+
+        let increments: Vec<_> = events[1..events.len() - 1]
+            .iter()
+            .map(|val| match val {
+                Event::Inc(delta) => *delta,
+                _ => panic!("Unexpected {val:?}"),
+            })
+            .collect();
+        assert!(increments.len() > 1);
+        assert!(increments.iter().all(|val| *val > 0));
+        assert_eq!(increments.iter().sum::<u64>(), 4096);
+
+        // The default reporter passes the progress to the callback
+        let progress = Mutex::new(vec![]);
+        download_file(&Client::new(), &url, &path, 8192, true, &|done, total| {
+            progress.lock().unwrap().push((done, total))
+        })
+        .await
+        .unwrap();
+        let progress = progress.into_inner().unwrap();
+        assert!(progress.len() > 1);
+        assert_eq!(progress.last(), Some(&(4096, 4096)));
+    }
+}
@@ -13,17 +13,65 @@ pub enum ServerError {
     TeraError(#[from] tera::Error),
     #[error("Reqwest error")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Not available in offline mode")]
+    Offline,
+    #[error("Invalid contact form: {0}")]
+    InvalidContactForm(String),
 }
 
 impl IntoResponse for ServerError {
     fn into_response(self) -> Response<axum::body::BoxBody> {
-        tracing::error!(?self, "Internal error");
-        let body = Body::from("Something went wrong");
+        let request_id = crate::request_id::current().unwrap_or_default();
+        tracing::error!(?self, %request_id, "Internal error");
+
+        let (status, message) = match &self {
+            ServerError::ReqwestError(e) if e.is_timeout() => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "The PortalBox server took too long to respond, please try again",
+            ),
+            ServerError::ReqwestError(e) if e.is_connect() => (
+                StatusCode::BAD_GATEWAY,
+                "The PortalBox server is unreachable, please check your network and try again",
+            ),
+            ServerError::Offline => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Not available in offline mode, restart portalbox without --offline",
+            ),
+            ServerError::InvalidContactForm(message) => (StatusCode::BAD_REQUEST, message.as_str()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong"),
+        };
+        let body = Body::from(format!("{message}. Reference: {request_id}"));
         let boxed_body = axum::body::boxed(body);
 
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(boxed_body)
-            .unwrap()
+        Response::builder().status(status).body(boxed_body).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, utils::http_client};
+
+    #[tokio::test]
+    async fn test_http_timeout() {
+        // Accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let config = Config {
+            http_timeout_secs: 1,
+            ..Default::default()
+        };
+        let client = http_client(&config).unwrap();
+
+        let ret = tokio::time::timeout(std::time::Duration::from_secs(5), client.get(url).send())
+            .await
+            .expect("http timeout should fire before the outer timeout");
+
+        let e = ret.expect_err("request should time out");
+        assert!(e.is_timeout());
+
+        let response = ServerError::from(e).into_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
     }
 }
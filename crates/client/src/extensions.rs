@@ -0,0 +1,95 @@
+use crate::{
+    cli::{Ext, ExtCommands},
+    client_instance::ClientInstance,
+    config::Config,
+};
+
+// Runs the vscode cli against the same data dirs `start` uses, its output goes straight through
+pub async fn run(config: &Config, ext: Ext) -> anyhow::Result<()> {
+    let client_instance = ClientInstance::infer(config).await.map_err(|e| {
+        anyhow::anyhow!("vscode isn't installed, run `portalbox start` first to download it ({e})")
+    })?;
+    if !client_instance.vscode_cmd.exists() {
+        return Err(anyhow::anyhow!(
+            "Can't find vscode at {}",
+            client_instance.vscode_cmd.display()
+        ));
+    }
+
+    let mut args = client_instance
+        .data_dir_args(&config.vscode_data_dir())
+        .await?;
+    match ext.command {
+        ExtCommands::List => args.push("--list-extensions".into()),
+        ExtCommands::Install { ids } => {
+            for id in ids {
+                args.push("--install-extension".into());
+                args.push(id.into());
+            }
+        }
+    }
+
+    let status = tokio::process::Command::new(&client_instance.vscode_cmd)
+        .args(args)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("vscode exited with {status}"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_install_args() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let args_file = dir.join("args");
+        let vscode_path = dir.join("code-server");
+        std::fs::write(
+            &vscode_path,
+            format!("#!/bin/sh\necho \"$@\" > {}\n", args_file.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&vscode_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = Config {
+            vscode_path: Some(vscode_path),
+            home_dir: dir.clone(),
+            ..Default::default()
+        };
+        let ext = Ext {
+            command: ExtCommands::Install {
+                ids: vec!["rust-lang.rust-analyzer".into(), "vscodevim.vim".into()],
+            },
+        };
+        run(&config, ext).await.unwrap();
+
+        let args = std::fs::read_to_string(&args_file).unwrap();
+        let extensions_dir = config.apps_data_dir().join("vscode-extensions");
+        assert!(args.contains(&format!("--extensions-dir {}", extensions_dir.display())));
+        assert!(args.contains(
+            "--install-extension rust-lang.rust-analyzer --install-extension vscodevim.vim"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vscode_not_installed() {
+        let config = Config {
+            vscode_path: Some("/nonexistent/code-server".into()),
+            ..Default::default()
+        };
+        let ext = Ext {
+            command: ExtCommands::List,
+        };
+        let e = run(&config, ext).await.unwrap_err();
+        assert!(e.to_string().starts_with("vscode isn't installed"));
+    }
+}
@@ -0,0 +1,101 @@
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerChoice {
+    Primary,
+    Fallback,
+}
+
+// Picks the server to connect to: the primary until it fails `retries` times in a row, then the
+// fallback until it does the same, then the primary again
+#[derive(Debug)]
+pub struct Failover {
+    retries: u32,
+    failures: u32,
+    current: ServerChoice,
+}
+
+impl Failover {
+    pub fn new(retries: u32) -> Self {
+        Self {
+            retries: retries.max(1),
+            failures: 0,
+            current: ServerChoice::Primary,
+        }
+    }
+
+    pub fn current(&self) -> ServerChoice {
+        self.current
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    // Returns the newly picked server when this failure used up the retries. `has_fallback` can
+    // change, the fallback may be approved after the first failures.
+    pub fn record_failure(&mut self, has_fallback: bool) -> Option<ServerChoice> {
+        self.failures += 1;
+        if !has_fallback || self.failures < self.retries {
+            return None;
+        }
+
+        self.current = match self.current {
+            ServerChoice::Primary => ServerChoice::Fallback,
+            ServerChoice::Fallback => ServerChoice::Primary,
+        };
+        self.failures = 0;
+        Some(self.current)
+    }
+
+    // Without waiting for the retries, e.g. the fallback rejected the connection outright
+    pub fn use_primary(&mut self) {
+        self.current = ServerChoice::Primary;
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover() {
+        let mut failover = Failover::new(3);
+        assert_eq!(failover.current(), ServerChoice::Primary);
+
+        // A success in between starts the count again
+        failover.record_failure(true);
+        failover.record_failure(true);
+        failover.record_success();
+        assert_eq!(failover.record_failure(true), None);
+        assert_eq!(failover.record_failure(true), None);
+        assert_eq!(failover.record_failure(true), Some(ServerChoice::Fallback));
+        assert_eq!(failover.current(), ServerChoice::Fallback);
+
+        // The fallback gets the same retries, then the next cycle is on the primary
+        assert_eq!(failover.record_failure(true), None);
+        assert_eq!(failover.record_failure(true), None);
+        assert_eq!(failover.record_failure(true), Some(ServerChoice::Primary));
+
+        failover.record_failure(true);
+        failover.record_failure(true);
+        failover.record_failure(true);
+        assert_eq!(failover.current(), ServerChoice::Fallback);
+        failover.use_primary();
+        assert_eq!(failover.current(), ServerChoice::Primary);
+    }
+
+    #[test]
+    fn test_failover_without_fallback() {
+        let mut failover = Failover::new(2);
+        for _ in 0..10 {
+            assert_eq!(failover.record_failure(false), None);
+        }
+        assert_eq!(failover.current(), ServerChoice::Primary);
+
+        // Approved later, the failures so far count
+        assert_eq!(failover.record_failure(true), Some(ServerChoice::Fallback));
+    }
+}
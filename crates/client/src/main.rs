@@ -1,37 +1,70 @@
 use crate::{
-    cli::{Cli, Commands},
+    apps_status::{AppsPhase, SharedAppsStatus},
+    cli::{Cli, Commands, ConfigCommands, DebugCommands},
     client_instance::ClientInstance,
     config::Config,
     credentials::CredManager,
+    proxy_client::{ConnectionState, ProxyConnectionEvent, ProxyServices, ProxyShared},
+    server_api::{HttpServerApi, ServerApi},
 };
-use axum::{error_handling::HandleError, extract::Extension, http::StatusCode, Router};
+use axum::{extract::Extension, middleware, Router};
 use clap::StructOpt;
 use credentials::Credential;
 use dotenv::dotenv;
-use models::AppsResult;
+use models::{protocol::AuthFailedReason, AppsResult};
 use secrecy::SecretString;
-use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
 use tera::Tera;
 use tokio::signal;
 use tokio::sync::Mutex;
-use tower_http::{services::ServeDir, trace::TraceLayer};
+use tokio_util::sync::CancellationToken;
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, Predicate},
+        CompressionLayer,
+    },
+    limit::RequestBodyLimitLayer,
+    request_id::{PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 
 mod api;
+mod apps_status;
+mod archive;
+mod browser;
+mod cache;
+mod cache_headers;
 mod cli;
 mod client_instance;
 mod config;
 mod credentials;
+mod daemon;
+mod debug;
+mod disk;
 mod downloader;
 mod error;
+mod extensions;
+mod failover;
+mod outbound_proxy;
+mod ping;
 mod proxy_client;
+mod relay;
+mod request_id;
 mod reset;
+mod server_api;
+mod signin;
 mod telemetry;
 mod tunnel;
 mod utils;
 mod version;
 mod website;
+mod whoami;
+
+// How long in-flight dashboard requests get to finish on shutdown
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+// Proxy events a dashboard can fall behind by before it's disconnected
+const PROXY_EVENTS_CAPACITY: usize = 256;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -39,123 +72,195 @@ async fn main() -> Result<(), anyhow::Error> {
     let args = Cli::parse();
     let config_file = args.config_file;
 
-    let config = match Config::new(config_file) {
+    let mut config = match Config::new(config_file) {
         Ok(val) => val,
         Err(e) => {
             return Err(anyhow::anyhow!("Invalid config file {}", e));
         }
     };
 
+    if args.offline {
+        config.offline = true;
+    }
+    if args.trace_protocol {
+        config.trace_protocol = true;
+    }
+    if args.quiet {
+        config.quiet = true;
+    }
+
+    // A profile picked with `--profile` stays selected for the next runs, once it's known to exist
+    let switched_profile = args
+        .profile
+        .filter(|val| config.current_profile.as_ref() != Some(val));
+    if let Some(profile) = &switched_profile {
+        config.current_profile = Some(profile.clone());
+    }
+    config.apply_profile()?;
+    if let Some(profile) = switched_profile {
+        config.save_current_profile(&profile).await?;
+    }
+
+    if let Some(server_url) = args.server_url {
+        config.set_server_url(server_url)?;
+    }
+
     telemetry::init_subscriber(&config);
 
     config.ensure_all_dirs().await?;
 
     if let Some(command) = args.command {
         match command {
-            Commands::Start => start(config).await,
-            Commands::Tunnel { host } => tunnel::connect(&host).await,
-            Commands::Config => config.show().await,
+            Commands::Start {
+                dry_run_apps,
+                check,
+                open,
+                detach,
+                no_flush_telemetry,
+                no_vscode,
+                wwwroot_dir,
+                templates_dir,
+            } => {
+                if no_vscode {
+                    config.start_vscode = false;
+                }
+                if wwwroot_dir.is_some() {
+                    config.wwwroot_dir = wwwroot_dir;
+                }
+                if templates_dir.is_some() {
+                    config.templates_dir = templates_dir;
+                }
+
+                if dry_run_apps {
+                    dry_run_apps_update(&config).await
+                } else if check {
+                    check_startup(&config).await
+                } else if detach {
+                    daemon::detach(&config)
+                } else {
+                    start(config, open, !no_flush_telemetry).await
+                }
+            }
+            Commands::Open => browser::open_dashboard(&config),
+            Commands::Status => daemon::status(&config),
+            Commands::Logs => daemon::logs(&config),
+            Commands::Stop => daemon::stop(&config).await,
+            Commands::Tunnel { host } => tunnel::connect(&config, &host).await,
+            Commands::Ping => ping::ping(&config).await,
+            Commands::Whoami => whoami::whoami(&config).await,
+            Commands::Signin { email } => signin::signin(&config, email).await,
+            Commands::SigninGuest => signin::signin_guest(&config).await,
+            Commands::Debug(debug) => match debug.command {
+                DebugCommands::ProxyTarget => debug::proxy_target(&config).await,
+            },
+            Commands::Ext(ext) => extensions::run(&config, ext).await,
+            Commands::Config {
+                command: Some(ConfigCommands::Schema),
+                ..
+            } => Config::schema().map(|val| println!("{val}")),
+            Commands::Config { format, .. } => config.show(format).await,
+            Commands::Disk { json } => disk::show(&config, json).await,
             Commands::Reset(reset) => {
                 let ret = reset::reset(reset, config).await;
                 ret
             }
-            Commands::Version => {
-                let git_sha = &env!("VERGEN_GIT_SHA")[..7];
-                println!("portalbox {} ({})", version::VERSION, git_sha);
-                Ok(())
-            }
+            Commands::Version { json } => version::show(json, config.quiet),
         }
     } else {
-        start(config).await
+        start(config, false, true).await
     }
 }
 
-async fn start(config: Config) -> Result<(), anyhow::Error> {
+async fn start(
+    mut config: Config,
+    open_browser: bool,
+    flush_telemetry: bool,
+) -> Result<(), anyhow::Error> {
+    config.ensure_dashboard_dirs()?;
+
+    // Everything after this, the dashboard and the proxy included, uses the port vscode got
+    if config.start_vscode {
+        config.vscode_port = pick_vscode_port(&config)?;
+    }
+
     let config = Arc::new(config);
     let config_1 = config.clone();
     let config_2 = config.clone();
     let config_3 = config.clone();
+    let config_4 = config.clone();
 
     tracing::info!("Starting...");
     tracing::debug!(?config, runtime_dir = ?config.runtime_dir());
 
-    let apps = match init_apps(&config).await {
-        Ok(val) => val,
-        Err(e) => {
-            tracing::error!(?e, "Error initializing");
-            return Err(e.into());
-        }
-    };
-
-    tracing::debug!(?apps);
-
-    let vscode_full_cmd = apps.vscode.vscode_cmd(&config.apps_dir());
-    let vscode_log_file = apps.vscode.output_file(&config.apps_data_dir());
+    if config.telemetry.is_none() {
+        tracing::info!(
+            "Telemetry is off until you choose, on the dashboard or with `telemetry = true` or `telemetry = false` in {}",
+            config.config_file.display()
+        );
+    }
 
-    if !vscode_full_cmd.exists() {
-        tracing::error!(?vscode_full_cmd, "Can't find vscode");
-        return Err(anyhow::anyhow!("Can't find vscode"));
+    // Before anything connects, so waiting for the system trust store doesn't block the runtime
+    if let Err(e) = utils::preload_root_certs(&config).await {
+        tracing::warn!("Can't load the root certs: {e:#}");
     }
 
-    tracing::debug!("VSCode starting...");
-    let vscode_handle = duct::cmd!(
-        vscode_full_cmd,
-        "--host",
-        "0.0.0.0",
-        "--port",
-        config.vscode_port.to_string(),
-        "--server-data-dir",
-        apps.vscode.server_data_dir(&config_1.apps_data_dir()),
-        "--user-data-dir",
-        apps.vscode.user_data_dir(&config_1.apps_data_dir()),
-        "--extensions-dir",
-        apps.vscode.extensions_dir(&config_1.apps_data_dir()),
-        "--without-connection-token"
-    )
-    .stderr_to_stdout()
-    .stdout_path(vscode_log_file)
-    .start()?;
+    // Shared by everything talking to the server, so connections are pooled
+    let http_client = utils::http_client(&config)?;
+    let server_api: Arc<dyn ServerApi> =
+        Arc::new(HttpServerApi::new((*config).clone(), http_client.clone()));
+    let server_api_1 = server_api.clone();
+    let server_api_2 = server_api.clone();
 
-    let serve_dir_service = {
-        let wwwroot_dir = if let Ok(runtime_dir) = &config.runtime_dir() {
-            runtime_dir.join("wwwroot")
-        } else {
-            "wwwroot".into()
-        };
+    let apps_status = SharedAppsStatus::default();
+    let vscode_handle =
+        spawn_vscode(&config, server_api.clone(), &http_client, &apps_status).await?;
+    let vscode_address = SocketAddr::new(config.vscode_host, config.vscode_port);
 
-        ServeDir::new(wwwroot_dir)
-    };
+    let templates = website::Templates::new(load_templates(&config)?, config.templates_hot_reload)
+        .with_disabled_pages(config.disabled_pages.clone());
 
-    let tera = {
-        let templates_dir = if let Ok(runtime_dir) = &config.runtime_dir() {
-            runtime_dir.join("website/templates")
-        } else {
-            "website/templates".into()
-        };
-        let dir_glob = format!("{}/**/*.html", templates_dir.display());
-        Tera::new(&dir_glob).unwrap()
-    };
+    let serve_dir_service = website::static_files_service(config.wwwroot_dir(), templates.clone());
     let (proxy_request_sender, proxy_request_receiver) = tokio::sync::mpsc::channel(10);
+    let local_secret = api::write_local_secret(&config).await?;
 
     let env = Environment {
         config,
-        tera,
+        templates,
+        apps_status,
         existing_credential: Arc::new(Mutex::new(None)),
+        proxy_auth_failure: Arc::new(Mutex::new(None)),
+        proxy_connection_state: Arc::new(Mutex::new(ConnectionState::default())),
+        proxy_services: Arc::new(Mutex::new(ProxyServices::default())),
+        proxy_events: tokio::sync::broadcast::channel(PROXY_EVENTS_CAPACITY).0,
+        latest_version: Arc::new(Mutex::new(None)),
+        server_news_cache: Arc::new(website::server_news_cache(&config_2)),
+        motd_cache: Arc::new(website::motd_cache(&config_2)),
+        vscode_address,
+        telemetry_consent: Arc::new(Mutex::new(config_2.telemetry)),
         proxy_request_sender,
+        http_client,
+        server_api,
+        local_secret,
     };
 
-    let credentials = match CredManager::load(&env.config).await {
-        Ok(val) => {
+    let credentials = match CredManager::load_or_recover(&env.config).await {
+        Ok(val) if !val.credentials.is_empty() => {
             tracing::info!("Credentials loaded... signing in");
             val
         }
-        Err(_e) => {
+        Ok(val) => {
             tracing::info!("No existing credentials");
+            val
+        }
+        Err(e) => {
+            tracing::error!(?e, "Error loading credentials");
             CredManager::empty()
         }
     };
 
-    if let Some(credential) = credentials
+    if env.config.offline {
+        tracing::info!("Offline mode, skip signing in");
+    } else if let Some(credential) = credentials
         .credentials
         .get(env.config.server_url().as_str())
     {
@@ -166,34 +271,74 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
     }
 
     let addr = SocketAddr::from(([0, 0, 0, 0], env.config.local_home_service_port));
+    // Bound here so a port in use fails the start instead of panicking the server task
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|e| anyhow::anyhow!("Can't listen on {addr}: {e}"))?;
     tracing::info!(
         "Dasboard available at http://localhost:{}",
         env.config.local_home_service_port
     );
+    let proxy_shared = ProxyShared {
+        existing_credential: env.existing_credential.clone(),
+        auth_failure: env.proxy_auth_failure.clone(),
+        connection_state: env.proxy_connection_state.clone(),
+        services: env.proxy_services.clone(),
+        events: env.proxy_events.clone(),
+    };
+    let latest_version = env.latest_version.clone();
+    let server_news_cache = env.server_news_cache.clone();
+    let motd_cache = env.motd_cache.clone();
+
     let app = Router::new()
-        .merge(website::routes())
-        .nest("/api", api::routes())
-        .fallback(HandleError::new(serve_dir_service, handle_serve_dir_error))
-        .layer(TraceLayer::new_for_http())
+        .merge(website::routes(&env.config))
+        .fallback(serve_dir_service)
+        .nest("/api", api::routes(&env.config))
+        .layer(compression_layer())
+        .merge(api::health_routes())
+        .layer(RequestBodyLimitLayer::new(
+            env.config.max_request_body_bytes,
+        ))
+        // Outside the limit above, it has its own to make room for attachments
+        .merge(website::contact_routes(&env.config))
+        .layer(middleware::from_fn(request_id::scope_request_id))
+        .layer(TraceLayer::new_for_http().make_span_with(request_id::make_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(
+            request_id::MakeShortRequestId,
+        ))
         .layer(Extension(env));
 
-    let server_fut = async move {
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
-    };
+    let shutdown = CancellationToken::new();
+    let server_shutdown = shutdown.clone();
+    let mut server_handle = tokio::spawn(async move {
+        let ret = server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move { server_shutdown.cancelled().await })
+            .await;
+        if let Err(e) = ret {
+            tracing::error!(?e, "Dashboard server error");
+        }
+    });
+    let mut server_ended = false;
 
     let proxy_client_fut = {
-        let server_proxy_url = config_1.server_proxy_url();
-        tracing::debug!(?server_proxy_url, "proxy_client_fut");
-        let mut sock_addrs = tokio::net::lookup_host(server_proxy_url).await?;
-        let first = sock_addrs
-            .next()
-            .ok_or(anyhow::anyhow!("Failed to resolve proxy server"))?;
+        let first = if config_1.offline {
+            None
+        } else {
+            Some(init_proxy_address(&config_1).await?)
+        };
 
         async move {
-            let ret = proxy_client::start_deamon(config_1, first, proxy_request_receiver).await;
+            let first = match first {
+                Some(val) => val,
+                None => {
+                    tracing::info!("Offline mode, proxy client not started");
+                    return std::future::pending().await;
+                }
+            };
+            let ret =
+                proxy_client::start_deamon(config_1, first, proxy_request_receiver, proxy_shared)
+                    .await;
             if let Err(e) = ret {
                 tracing::error!(?e, "proxy server error");
             }
@@ -202,20 +347,23 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
 
     let server_news_fut = async move {
         tracing::debug!("Pre fetch server news");
-        let _ = website::fetch_server_news(&config_2).await;
+        let _ =
+            website::fetch_server_news(&config_2, server_api_1.as_ref(), &server_news_cache).await;
+        let _ = website::fetch_motd(&config_2, server_api_1.as_ref(), &motd_cache).await;
     };
 
-    let version_check_fut = async move {
-        tracing::debug!("Checking for update...");
-        let _ = version::check(&config_3).await;
-    };
+    let version_check_fut = version::run(config_3, server_api_2, latest_version);
 
     tokio::task::spawn(server_news_fut);
+    if open_browser {
+        let _ = browser::open_dashboard(&config_4);
+    }
     tokio::task::spawn(version_check_fut);
 
     tokio::select! {
-        _ = server_fut => {
+        _ = &mut server_handle => {
             tracing::debug!("server_fut ended");
+            server_ended = true;
         }
         _ = proxy_client_fut => {
             tracing::debug!("proxy client ended");
@@ -223,47 +371,269 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
         _ = signal::ctrl_c() => {
             tracing::debug!("Ctrl-C received, terminating...");
         }
+        _ = daemon::terminate_signal() => {
+            tracing::debug!("Stop requested, terminating...");
+        }
+    }
+
+    // Stop accepting new dashboard requests and let the current ones finish.
+    // Open terminal websockets keep the server up until the grace period ends.
+    shutdown.cancel();
+    if !server_ended {
+        match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, server_handle).await {
+            Ok(_) => tracing::debug!("Dashboard server stopped"),
+            Err(_) => tracing::warn!("In-flight requests didn't finish in time, terminating"),
+        }
     }
 
-    let vscode_killed = vscode_handle.kill();
-    if let Err(e) = vscode_killed {
-        tracing::error!(?e, "Failed to kill the vscode process");
+    if let Some(vscode_handle) = vscode_handle {
+        if let Err(e) = vscode_handle.kill() {
+            tracing::error!(?e, "Failed to kill the vscode process");
+        }
     }
+    daemon::remove_own_pid_file(&config_4);
+    telemetry::shutdown(flush_telemetry).await;
     tracing::debug!("Terminated");
     Ok(())
 }
 
-async fn handle_serve_dir_error(err: std::io::Error) -> (StatusCode, String) {
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        format!("Something went wrong: {}", err),
-    )
+// `None` when vscode is disabled
+async fn spawn_vscode(
+    config: &Config,
+    server_api: Arc<dyn ServerApi>,
+    http_client: &reqwest::Client,
+    apps_status: &SharedAppsStatus,
+) -> Result<Option<duct::Handle>, anyhow::Error> {
+    if !config.start_vscode {
+        tracing::info!("vscode is disabled, only serving the dashboard, terminal and proxy");
+        return Ok(None);
+    }
+
+    let client_instance = if config.vscode_path.is_some() {
+        tracing::info!("Using the configured vscode_path, vscode auto update is disabled");
+        ClientInstance::infer(config).await?
+    } else {
+        match init_apps(config, server_api, http_client, apps_status).await {
+            Ok(val) => ClientInstance::from_apps(val, config),
+            Err(e) => {
+                tracing::error!(?e, "Error initializing");
+                return Err(e);
+            }
+        }
+    };
+
+    tracing::debug!(?client_instance);
+
+    let vscode_full_cmd = client_instance.vscode_cmd.clone();
+    let vscode_log_file = client_instance.vscode.output_file(&config.apps_data_dir());
+
+    if !vscode_full_cmd.exists() {
+        tracing::error!(?vscode_full_cmd, "Can't find vscode");
+        return Err(anyhow::anyhow!("Can't find vscode"));
+    }
+
+    let vscode_data_dir = config.vscode_data_dir();
+    let data_dir_error = |e: std::io::Error| {
+        tracing::error!(?e, ?vscode_data_dir, "Can't create vscode data dirs");
+        anyhow::anyhow!(
+            "Can't create vscode data dirs under {}: {}",
+            vscode_data_dir.display(),
+            e
+        )
+    };
+    let data_dir_args = client_instance
+        .data_dir_args(&vscode_data_dir)
+        .await
+        .map_err(data_dir_error)?;
+
+    tracing::debug!(vscode_host = %config.vscode_host, vscode_port = config.vscode_port, "VSCode starting...");
+    let vscode_args = {
+        let mut ret: Vec<std::ffi::OsString> = vec![
+            "--host".into(),
+            config.vscode_host.to_string().into(),
+            "--port".into(),
+            config.vscode_port.to_string().into(),
+        ];
+        ret.extend(data_dir_args);
+        ret.push("--without-connection-token".into());
+        ret
+    };
+    let vscode_handle = duct::cmd(vscode_full_cmd, vscode_args)
+        .stderr_to_stdout()
+        .stdout_path(vscode_log_file)
+        .start()?;
+
+    Ok(Some(vscode_handle))
+}
+
+fn load_templates(config: &Config) -> Result<Tera, anyhow::Error> {
+    let templates_dir = config.templates_dir();
+    let dir_glob = format!("{}/**/*.html", templates_dir.display());
+    let ret = Tera::new(&dir_glob)?;
+    Ok(ret)
 }
 
-async fn init_apps(config: &Config) -> Result<AppsResult, anyhow::Error> {
-    let client_instance = ClientInstance::infer(config).await;
+// Goes through `start` up to serving, stopping at the first failure. Nothing is downloaded.
+async fn check_startup(config: &Config) -> Result<(), anyhow::Error> {
+    let report = |line: String| {
+        if !config.quiet {
+            println!("{line}");
+        }
+    };
+
+    report(format!("config       ok  {}", config.config_file.display()));
+
+    if config.start_vscode {
+        let client_instance = if config.vscode_path.is_some() {
+            ClientInstance::infer(config).await
+        } else {
+            load_local_apps(config)
+                .await
+                .map(|val| ClientInstance::from_apps(val, config))
+        };
+        let vscode_cmd = client_instance
+            .map(|val| val.vscode_cmd)
+            .map_err(|e| anyhow::anyhow!("vscode: {e}"))?;
+        if !vscode_cmd.exists() {
+            return Err(anyhow::anyhow!(
+                "vscode: can't find {}",
+                vscode_cmd.display()
+            ));
+        }
+        report(format!("vscode       ok  {}", vscode_cmd.display()));
 
-    match client_instance {
+        let vscode_port = pick_vscode_port(config)?;
+        report(format!("vscode port  ok  {vscode_port}"));
+    } else {
+        report("vscode       skipped, disabled".into());
+    }
+
+    let dashboard_port = config.local_home_service_port;
+    utils::free_port(dashboard_port, false)
+        .map_err(|e| anyhow::anyhow!("Dashboard port {dashboard_port} isn't available: {e}"))?;
+    report(format!("dashboard    ok  {dashboard_port}"));
+
+    if config.offline {
+        report("proxy        skipped in offline mode".into());
+    } else {
+        let proxy_address = resolve_proxy_address(config).await?;
+        report(format!("proxy        ok  {proxy_address}"));
+    }
+
+    config.ensure_dashboard_dirs()?;
+    report(format!(
+        "wwwroot      ok  {}",
+        config.wwwroot_dir().display()
+    ));
+
+    let tera = load_templates(config).map_err(|e| anyhow::anyhow!("Templates: {e}"))?;
+    tera.get_template("index.html")
+        .map_err(|e| anyhow::anyhow!("Templates: {e}"))?;
+    report(format!(
+        "templates    ok  {}",
+        tera.get_template_names().count()
+    ));
+
+    Ok(())
+}
+
+// vscode only logs to its own file when it can't listen, so check the port before spawning it
+fn pick_vscode_port(config: &Config) -> Result<u16, anyhow::Error> {
+    let port = utils::free_port(config.vscode_port, config.vscode_port_fallback)
+        .map_err(|e| anyhow::anyhow!("vscode_port {} isn't available: {e}", config.vscode_port))?;
+
+    if port != config.vscode_port {
+        tracing::warn!(
+            vscode_port = config.vscode_port,
+            port,
+            "vscode_port is already in use, starting vscode on another port"
+        );
+    }
+
+    Ok(port)
+}
+
+// Resolved at every start, so a moved proxy server is picked up right away. The last known
+// address is only used when resolving fails, e.g. DNS is down.
+async fn init_proxy_address(config: &Config) -> Result<SocketAddr, anyhow::Error> {
+    let e = match resolve_proxy_address(config).await {
+        Ok(val) => return Ok(val),
+        Err(e) => e,
+    };
+
+    match cache::load_proxy_address(config).await {
+        Ok(val) => {
+            tracing::warn!(?e, proxy_address = ?val, "Can't resolve the proxy server, using the cached address");
+            Ok(val)
+        }
+        Err(_) => Err(e),
+    }
+}
+
+async fn resolve_proxy_address(config: &Config) -> Result<SocketAddr, anyhow::Error> {
+    let server_proxy_url = config.server_proxy_url();
+    tracing::debug!(?server_proxy_url, "Resolving proxy address");
+    let mut sock_addrs = tokio::net::lookup_host(server_proxy_url).await?;
+    let first = sock_addrs
+        .next()
+        .ok_or(anyhow::anyhow!("Failed to resolve proxy server"))?;
+
+    if let Err(e) = cache::save_proxy_address(config, first).await {
+        tracing::error!(?e, "Error caching proxy address");
+    }
+
+    Ok(first)
+}
+
+async fn init_apps(
+    config: &Config,
+    server_api: Arc<dyn ServerApi>,
+    http_client: &reqwest::Client,
+    apps_status: &SharedAppsStatus,
+) -> Result<AppsResult, anyhow::Error> {
+    let local_apps = load_local_apps(config).await;
+
+    match local_apps {
+        Ok(val) if config.offline => Ok(val),
+        Err(e) if config.offline => {
+            tracing::error!(?e, "Error loading client instance");
+            Err(anyhow::anyhow!(
+                "No downloaded vscode, can't download it in offline mode"
+            ))
+        }
         Ok(val) => {
             let current_vscode_version = val.vscode.latest_version.clone();
             let config_1 = config.clone();
+            let http_client_1 = http_client.clone();
+            let apps_status_1 = apps_status.clone();
             let update_fut = async move {
-                let apps_result =
-                    fetch_or_update_apps(&config_1, Some(current_vscode_version)).await;
+                let apps_result = fetch_or_update_apps(
+                    &config_1,
+                    server_api,
+                    &http_client_1,
+                    Some(current_vscode_version),
+                    &apps_status_1,
+                )
+                .await;
                 if let Err(e) = apps_result {
                     tracing::error!(?e, "Error getting apps result");
                 }
             };
             tokio::task::spawn(update_fut);
 
-            let ret = AppsResult {
-                vscode: val.vscode.clone(),
-            };
-            Ok(ret)
+            Ok(val)
         }
         Err(e) => {
             tracing::error!(?e, "Error loading client instance");
-            let init_apps = match fetch_or_update_apps(&config, None).await {
+            let init_apps = match fetch_or_update_apps(
+                config,
+                server_api,
+                http_client,
+                None,
+                apps_status,
+            )
+            .await
+            {
                 Ok(val) => val,
                 Err(e) => {
                     tracing::error!(?e, "Can't fetch vscode from server");
@@ -276,28 +646,151 @@ async fn init_apps(config: &Config) -> Result<AppsResult, anyhow::Error> {
     }
 }
 
-async fn fetch_or_update_apps(
+async fn load_local_apps(config: &Config) -> Result<AppsResult, anyhow::Error> {
+    let cached_apps = cache::load_apps(config)
+        .await
+        .ok()
+        .filter(|val| val.vscode.vscode_dir(&config.apps_dir()).exists());
+
+    match cached_apps {
+        Some(val) => Ok(val),
+        None => ClientInstance::infer(config)
+            .await
+            .map(|val| AppsResult { vscode: val.vscode }),
+    }
+}
+
+// Within `apps_cache_ttl_secs` of the last fetch the cached result is used right away, and
+// refreshed in the background for the next time
+async fn fetch_apps_cached(
     config: &Config,
-    current_vscode_version: Option<semver::Version>,
+    server_api: Arc<dyn ServerApi>,
 ) -> Result<AppsResult, anyhow::Error> {
     let os_arch = models::utils::get_os_arch();
-    let url = config.server_url_with_path("api/apps");
-    tracing::debug!(%url, ?os_arch, "Getting apps");
+    let ttl = Duration::from_secs(config.apps_cache_ttl_secs);
+    if let Some(val) = cache::load_fresh_apps(config, &os_arch, ttl).await {
+        tracing::debug!(?os_arch, "Using cached apps result");
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fetch_and_save_apps(&config, server_api.as_ref()).await {
+                tracing::warn!(?e, "Error refreshing the cached apps result");
+            }
+        });
+        return Ok(val);
+    }
 
-    let apps_request = models::AppsRequest { os_arch };
+    fetch_and_save_apps(config, server_api.as_ref()).await
+}
 
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()?;
-    let apps_result = client
-        .get(url)
-        .json(&apps_request)
-        .send()
-        .await?
-        .json::<models::AppsResult>()
-        .await?;
+async fn fetch_and_save_apps(
+    config: &Config,
+    server_api: &dyn ServerApi,
+) -> Result<AppsResult, anyhow::Error> {
+    let apps_result = fetch_apps(server_api).await?;
+    if let Err(e) = cache::save_apps(config, &apps_result).await {
+        tracing::error!(?e, "Error caching apps result");
+    }
+
+    Ok(apps_result)
+}
+
+async fn fetch_apps(server_api: &dyn ServerApi) -> Result<AppsResult, anyhow::Error> {
+    let os_arch = models::utils::get_os_arch();
+    let apps_result = server_api.apps(os_arch).await?;
     tracing::debug!(?apps_result, "Got app_results");
 
+    Ok(apps_result)
+}
+
+// Reports what `fetch_or_update_apps` would do on start, without downloading or extracting
+async fn dry_run_apps_update(config: &Config) -> Result<(), anyhow::Error> {
+    if config.vscode_path.is_some() {
+        println!("vscode_path is set, vscode is never downloaded or updated");
+        return Ok(());
+    }
+    if config.offline {
+        return Err(anyhow::anyhow!(
+            "Can't check for vscode updates in offline mode"
+        ));
+    }
+
+    let http_client = utils::http_client(config)?;
+
+    let current_version = load_local_apps(config)
+        .await
+        .ok()
+        .map(|val| val.vscode.latest_version);
+    let server_api = HttpServerApi::new(config.clone(), http_client.clone());
+    let apps_result = fetch_apps(&server_api).await?;
+    let latest = &apps_result.vscode;
+
+    let download_size = http_client
+        .head(&latest.download_link)
+        .send()
+        .await
+        .ok()
+        .and_then(|val| val.content_length());
+
+    let action = if current_version.as_ref() == Some(&latest.latest_version) {
+        "none, already up to date".to_string()
+    } else if latest.vscode_dir(&config.apps_dir()).exists() {
+        "none, already downloaded".to_string()
+    } else if download_size.unwrap_or_default() > config.max_download_bytes {
+        format!(
+            "none, download exceeds max_download_bytes {}",
+            indicatif::HumanBytes(config.max_download_bytes)
+        )
+    } else {
+        format!("download and extract vscode {}", latest.latest_version)
+    };
+
+    let current_version = current_version
+        .map(|val| val.to_string())
+        .unwrap_or_else(|| "not installed".into());
+    let download_size = download_size
+        .map(|val| indicatif::HumanBytes(val).to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    println!("{:<16} {}", "current version", current_version);
+    println!("{:<16} {}", "latest version", latest.latest_version);
+    println!("{:<16} {}", "download url", latest.download_link);
+    println!("{:<16} {}", "download size", download_size);
+    println!("{:<16} {}", "action", action);
+
+    Ok(())
+}
+
+async fn fetch_or_update_apps(
+    config: &Config,
+    server_api: Arc<dyn ServerApi>,
+    http_client: &reqwest::Client,
+    current_vscode_version: Option<semver::Version>,
+    apps_status: &SharedAppsStatus,
+) -> Result<AppsResult, anyhow::Error> {
+    let ret = fetch_or_update_apps_inner(
+        config,
+        server_api,
+        http_client,
+        current_vscode_version,
+        apps_status,
+    )
+    .await;
+    apps_status.set_phase(AppsPhase::Idle, None);
+
+    ret
+}
+
+async fn fetch_or_update_apps_inner(
+    config: &Config,
+    server_api: Arc<dyn ServerApi>,
+    http_client: &reqwest::Client,
+    current_vscode_version: Option<semver::Version>,
+    apps_status: &SharedAppsStatus,
+) -> Result<AppsResult, anyhow::Error> {
+    apps_status.set_phase(AppsPhase::Checking, None);
+    let apps_result = fetch_apps_cached(config, server_api).await?;
+    let latest_version = &apps_result.vscode.latest_version;
+
     if let Some(current_vscode_version) = current_vscode_version {
         if current_vscode_version == apps_result.vscode.latest_version {
             // Already have the latest version
@@ -313,24 +806,31 @@ async fn fetch_or_update_apps(
     }
 
     tracing::info!("Downloading vscode");
+    apps_status.set_phase(AppsPhase::Downloading, Some(latest_version));
 
     let tar_gz_path = {
         let home_dir = config.home_dir.clone();
         home_dir.join("vscode-latest.tar.gz")
     };
-    let _ = downloader::download_file(&apps_result.vscode.download_link, &tar_gz_path).await?;
-
-    let path = tar_gz_path;
-
-    let tar_gz = std::fs::File::open(path)?;
-    let tar = flate2::read::GzDecoder::new(tar_gz);
-    let mut archive = tar::Archive::new(tar);
+    downloader::download_file(
+        http_client,
+        &apps_result.vscode.download_link,
+        &tar_gz_path,
+        config.max_download_bytes,
+        config.quiet,
+        &|done, total| apps_status.set_progress(done, total),
+    )
+    .await?;
 
     let extracting_msg = format!(
         "Extracing vscode {}",
         apps_result.vscode.latest_version.to_string()
     );
-    let spinner = indicatif::ProgressBar::new_spinner();
+    let spinner = if config.quiet {
+        indicatif::ProgressBar::hidden()
+    } else {
+        indicatif::ProgressBar::new_spinner()
+    };
     spinner.set_style(indicatif::ProgressStyle::default_spinner().tick_strings(&[
         "[    ]", "[=   ]", "[==  ]", "[=== ]", "[ ===]", "[  ==]", "[   =]", "[    ]", "[   =]",
         "[  ==]", "[ ===]", "[=== ]", "[==  ]", "[=   ]", "[====]",
@@ -338,7 +838,14 @@ async fn fetch_or_update_apps(
 
     spinner.set_message(extracting_msg);
     spinner.enable_steady_tick(120);
-    archive.unpack(&config.apps_dir())?;
+    apps_status.set_phase(AppsPhase::Extracting, Some(latest_version));
+    let unpacked = archive::unpack_tar_gz(&tar_gz_path, &config.apps_dir(), &|done, total| {
+        apps_status.set_progress(done, total)
+    });
+    if let Err(e) = unpacked {
+        spinner.abandon_with_message("Extracting vscode failed");
+        return Err(e);
+    }
 
     let extracted_msg = format!(
         "Extracted vscode {}",
@@ -349,18 +856,305 @@ async fn fetch_or_update_apps(
     Ok(apps_result)
 }
 
+// Request bodies of the dashboard routes, capped at `max_request_body_bytes`
+pub type RequestBody = http_body::Limited<axum::body::Body>;
+
 #[derive(Clone)]
 pub struct Environment {
     config: Arc<Config>,
-    tera: Tera,
+    templates: website::Templates,
+    // Progress of a vscode download running in the background
+    apps_status: SharedAppsStatus,
     existing_credential: Arc<Mutex<Option<Credential>>>,
+    proxy_auth_failure: Arc<Mutex<Option<AuthFailedReason>>>,
+    proxy_connection_state: Arc<Mutex<ConnectionState>>,
+    proxy_services: Arc<Mutex<ProxyServices>>,
+    proxy_events: tokio::sync::broadcast::Sender<ProxyConnectionEvent>,
+    // Set when a newer portalbox version is available
+    latest_version: Arc<Mutex<Option<semver::Version>>>,
+    server_news_cache: Arc<website::ServerNewsCache>,
+    motd_cache: Arc<website::MotdCache>,
+    // Where vscode was started, its port may differ from `vscode_port` when that was taken
+    vscode_address: SocketAddr,
+    // The user's telemetry choice, `None` until made
+    telemetry_consent: Arc<Mutex<Option<bool>>>,
     proxy_request_sender: tokio::sync::mpsc::Sender<ProxyRequest>,
+    http_client: reqwest::Client,
+    server_api: Arc<dyn ServerApi>,
+    // Required by state changing api routes, see `api::write_local_secret`
+    local_secret: SecretString,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct ProxyRequest {
-    #[serde(serialize_with = "models::serialize_secret_string")]
     pub portalbox_inner_token: SecretString,
     pub base_sub_domain: String,
     pub hostname: String,
+    // The proxy is torn down once the guest session expires
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Set when approved by the fallback server, the daemon's primary proxy address otherwise
+    pub proxy_address: Option<SocketAddr>,
+    // Approved by the fallback server too, to fail over to. Set in the background, the proxy
+    // starts without waiting for it.
+    pub fallback: tokio::sync::watch::Receiver<Option<ProxyTarget>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyTarget {
+    pub proxy_address: SocketAddr,
+    pub portalbox_inner_token: SecretString,
+}
+
+// Websocket upgrades are left alone, the terminal and the proxy events stream over them
+fn compression_layer() -> CompressionLayer<impl Predicate> {
+    let not_upgrade = |status: axum::http::StatusCode,
+                       _: axum::http::Version,
+                       _: &axum::http::HeaderMap,
+                       _: &axum::http::Extensions| {
+        status != axum::http::StatusCode::SWITCHING_PROTOCOLS
+    };
+
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(not_upgrade))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_api::mock::MockServerApi;
+
+    #[tokio::test]
+    async fn test_fetch_or_update_apps_skips_download() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            apps_cache_ttl_secs: 0,
+            ..Default::default()
+        };
+        let http_client = utils::http_client(&config).unwrap();
+        let server_api = Arc::new(MockServerApi::new("1.2.3", "1.0.0"));
+
+        // The mock's download link is unreachable, so these only pass without downloading
+        let current = Some(semver::Version::parse("1.2.3").unwrap());
+        let ret = fetch_or_update_apps(
+            &config,
+            server_api.clone(),
+            &http_client,
+            current,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ret.vscode.latest_version.to_string(), "1.2.3");
+
+        std::fs::create_dir_all(ret.vscode.vscode_dir(&config.apps_dir())).unwrap();
+        let current = Some(semver::Version::parse("1.0.0").unwrap());
+        fetch_or_update_apps(
+            &config,
+            server_api.clone(),
+            &http_client,
+            current,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+
+        let ret = fetch_or_update_apps(
+            &config,
+            Arc::new(MockServerApi::new("2.0.0", "1.0.0")),
+            &http_client,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(server_api.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_init_proxy_address() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let mut config = Config {
+            home_dir: home_dir.clone(),
+            ..Default::default()
+        };
+        config
+            .set_server_url(url::Url::parse("http://localhost:8080").unwrap())
+            .unwrap();
+
+        let resolved = init_proxy_address(&config).await.unwrap();
+        assert!(resolved.ip().is_loopback());
+        assert_eq!(cache::load_proxy_address(&config).await.unwrap(), resolved);
+
+        // The cached address is only used when resolving fails
+        config
+            .set_server_url(url::Url::parse("http://portalbox.invalid").unwrap())
+            .unwrap();
+        assert_eq!(init_proxy_address(&config).await.unwrap(), resolved);
+
+        cache::delete(&config).await.unwrap();
+        assert!(init_proxy_address(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_or_update_apps_cached() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            ..Default::default()
+        };
+        let http_client = utils::http_client(&config).unwrap();
+        let current = || Some(semver::Version::parse("1.2.3").unwrap());
+
+        // Miss
+        let server_api = Arc::new(MockServerApi::new("1.2.3", "1.0.0"));
+        fetch_or_update_apps(
+            &config,
+            server_api.clone(),
+            &http_client,
+            current(),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(server_api.calls(), 1);
+
+        // Hit, with the current version installed there's no download
+        let server_api = Arc::new(MockServerApi::new("2.0.0", "1.0.0"));
+        let ret = fetch_or_update_apps(
+            &config,
+            server_api.clone(),
+            &http_client,
+            current(),
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ret.vscode.latest_version.to_string(), "1.2.3");
+
+        // The server's newer answer is cached in the background
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let ret = cache::load_apps(&config).await;
+                if ret.map(|val| val.vscode.latest_version.to_string()).ok() == Some("2.0.0".into())
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(server_api.calls(), 1);
+
+        // Expired
+        let config = Config {
+            apps_cache_ttl_secs: 0,
+            ..config
+        };
+        let ret = fetch_or_update_apps(
+            &config,
+            server_api.clone(),
+            &http_client,
+            current(),
+            &Default::default(),
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(server_api.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_start_without_vscode() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            start_vscode: false,
+            local_home_service_port: 0,
+            offline: true,
+            ..Default::default()
+        };
+        let http_client = utils::http_client(&config).unwrap();
+        let server_api = Arc::new(MockServerApi::new("1.2.3", "1.0.0"));
+
+        // No apps are downloaded or installed, and none are needed
+        let ret = spawn_vscode(
+            &config,
+            server_api.clone(),
+            &http_client,
+            &Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(ret.is_none());
+        assert_eq!(server_api.calls(), 0);
+        assert!(!config.apps_dir().exists());
+
+        check_startup(&config).await.unwrap();
+
+        let config = Config {
+            start_vscode: true,
+            ..config
+        };
+        assert!(
+            spawn_vscode(&config, server_api, &http_client, &Default::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_startup() {
+        let config = Config {
+            vscode_path: Some(std::env::current_exe().unwrap()),
+            vscode_port: 0,
+            local_home_service_port: 0,
+            offline: true,
+            ..Default::default()
+        };
+        check_startup(&config).await.unwrap();
+
+        let config = Config {
+            vscode_path: Some("/nonexistent/code-server".into()),
+            ..config
+        };
+        let e = check_startup(&config).await.unwrap_err();
+        assert!(e.to_string().starts_with("vscode:"));
+    }
+
+    #[tokio::test]
+    async fn test_api_compression() {
+        use axum::{
+            body::Body,
+            http::{header, Request, StatusCode},
+            routing::get,
+        };
+        use tower::ServiceExt;
+
+        let api = Router::new()
+            .route("/status", get(|| async { "status ".repeat(100) }))
+            // With a body, so only the status keeps it from being compressed
+            .route(
+                "/term-ws",
+                get(|| async { (StatusCode::SWITCHING_PROTOCOLS, "upgrade ".repeat(100)) }),
+            );
+        let app = Router::new().nest("/api", api).layer(compression_layer());
+
+        let request = |uri| {
+            Request::get(uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("/api/status")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let response = app.oneshot(request("/api/term-ws")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
 }
@@ -0,0 +1,249 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
+use tokio_socks::tcp::Socks5Stream;
+use url::Url;
+
+const DEFAULT_SOCKS_PORT: u16 = 1080;
+const MAX_CONNECT_RESPONSE_LENGTH: usize = 8 * 1024;
+
+// Opens a tcp connection to `target` (host:port), through the outbound proxy if there's one.
+// With `bind_ip` the connection (to the proxy if any) leaves from that local address.
+pub async fn connect(
+    outbound_proxy: Option<&Url>,
+    bind_ip: Option<IpAddr>,
+    target: &str,
+) -> anyhow::Result<TcpStream> {
+    let outbound_proxy = match outbound_proxy {
+        Some(val) => val,
+        None => return connect_tcp(target, bind_ip).await,
+    };
+
+    let proxy_host = outbound_proxy
+        .host_str()
+        .ok_or(anyhow::anyhow!("Outbound proxy has no host"))?;
+
+    match outbound_proxy.scheme() {
+        "http" => {
+            let proxy_port = outbound_proxy.port_or_known_default().unwrap_or(80);
+            let proxy_addr = format!("{proxy_host}:{proxy_port}");
+            let stream = connect_tcp(&proxy_addr, bind_ip)
+                .await
+                .with_context(|| format!("Can't reach outbound proxy {proxy_addr}"))?;
+
+            http_connect(stream, outbound_proxy, target)
+                .await
+                .with_context(|| format!("Outbound proxy {proxy_addr} failed to connect"))
+        }
+        "socks5" | "socks5h" => {
+            let proxy_port = outbound_proxy.port().unwrap_or(DEFAULT_SOCKS_PORT);
+            let proxy_addr = format!("{proxy_host}:{proxy_port}");
+            let username = outbound_proxy.username();
+
+            let socket = connect_tcp(&proxy_addr, bind_ip)
+                .await
+                .with_context(|| format!("Can't reach outbound proxy {proxy_addr}"))?;
+
+            let stream = if username.is_empty() {
+                Socks5Stream::connect_with_socket(socket, target).await
+            } else {
+                let password = outbound_proxy.password().unwrap_or_default();
+                Socks5Stream::connect_with_password_and_socket(socket, target, username, password)
+                    .await
+            };
+
+            let stream =
+                stream.with_context(|| format!("Outbound proxy {proxy_addr} failed to connect"))?;
+            Ok(stream.into_inner())
+        }
+        scheme => Err(anyhow::anyhow!(
+            "Unsupported outbound proxy scheme {scheme}, expecting http or socks5"
+        )),
+    }
+}
+
+async fn connect_tcp(addr: &str, bind_ip: Option<IpAddr>) -> anyhow::Result<TcpStream> {
+    let bind_ip = match bind_ip {
+        Some(val) => val,
+        None => return Ok(TcpStream::connect(addr).await?),
+    };
+
+    // Only addresses of the bound family are reachable
+    let mut last_error = None;
+    for target in tokio::net::lookup_host(addr).await? {
+        if target.is_ipv4() != bind_ip.is_ipv4() {
+            continue;
+        }
+
+        let socket = if bind_ip.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(bind_ip, 0))?;
+
+        match socket.connect(target).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => Err(anyhow::anyhow!(
+            "{addr} has no address reachable from {bind_ip}"
+        )),
+    }
+}
+
+// Binding fails unless the address is assigned to one of this machine's interfaces
+pub fn ensure_local_ip(ip: IpAddr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind(SocketAddr::new(ip, 0))
+        .with_context(|| format!("{ip} isn't a local address"))?;
+    Ok(())
+}
+
+async fn http_connect(
+    mut stream: TcpStream,
+    outbound_proxy: &Url,
+    target: &str,
+) -> anyhow::Result<TcpStream> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if !outbound_proxy.username().is_empty() {
+        let user_pass = format!(
+            "{}:{}",
+            outbound_proxy.username(),
+            outbound_proxy.password().unwrap_or_default()
+        );
+        let credentials = base64::encode(user_pass);
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // Read byte by byte so nothing past the response headers is consumed
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_CONNECT_RESPONSE_LENGTH {
+            return Err(anyhow::anyhow!("CONNECT response too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status_ok = status_line
+        .split_whitespace()
+        .nth(1)
+        .map(|val| val.starts_with('2'))
+        .unwrap_or_default();
+
+    if !status_ok {
+        return Err(anyhow::anyhow!("CONNECT refused: {status_line}"));
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let proxy_fut = async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello")
+                .await
+                .unwrap();
+            request
+        };
+
+        let (request, stream) = tokio::join!(
+            proxy_fut,
+            connect(Some(&proxy_url), None, "example.com:443")
+        );
+
+        assert!(request.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+
+        let mut stream = stream.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let proxy_fut = async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+        };
+
+        let (_, stream) = tokio::join!(
+            proxy_fut,
+            connect(Some(&proxy_url), None, "example.com:443")
+        );
+
+        assert!(stream.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let e = connect(Some(&proxy_url), None, "example.com:443")
+            .await
+            .expect_err("proxy should be unreachable");
+        assert!(e.to_string().starts_with("Can't reach outbound proxy"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_bind_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let bind_ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let (accepted, stream) =
+            tokio::join!(listener.accept(), connect(None, Some(bind_ip), &addr));
+        let (_, peer_addr) = accepted.unwrap();
+        assert_eq!(peer_addr.ip(), bind_ip);
+        assert_eq!(stream.unwrap().local_addr().unwrap(), peer_addr);
+
+        let e = connect(None, Some("::1".parse().unwrap()), &addr)
+            .await
+            .expect_err("no ipv6 address to connect to");
+        assert!(e.to_string().contains("no address reachable"));
+    }
+
+    #[test]
+    fn test_ensure_local_ip() {
+        assert!(ensure_local_ip("127.0.0.1".parse().unwrap()).is_ok());
+        // TEST-NET-1, never assigned
+        assert!(ensure_local_ip("192.0.2.1".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::{future::Future, time::Instant};
+
+use crate::{
+    config::Config, credentials::CredManager, outbound_proxy, utils::get_tls_connector, whoami,
+};
+use models::proxy_connection::{HelloResult, ProxyConnection};
+
+pub async fn ping(config: &Config) -> anyhow::Result<()> {
+    let credential = CredManager::load(config)
+        .await
+        .ok()
+        .and_then(|val| val.credentials.get(config.server_url().as_str()).cloned())
+        .ok_or(anyhow::anyhow!("No saved credential, please sign in first"))?;
+
+    // Approving a service would create a new one on the server, so the hostname comes from a
+    // running portalbox, like `debug proxy-target`
+    let status = whoami::dashboard_status(config)
+        .await
+        .ok_or(anyhow::anyhow!(
+            "portalbox isn't running, start it first so the proxy hostname is known"
+        ))?;
+    let hostname = proxy_hostname(&status, credential.base_sub_domain()).ok_or(anyhow::anyhow!(
+        "portalbox is running but not connected, the proxy hostname is unknown"
+    ))?;
+    println!("  hostname     = {hostname}");
+
+    let proxy_address = timed("dns", async {
+        let mut sock_addrs = tokio::net::lookup_host(config.server_proxy_url()).await?;
+        sock_addrs
+            .next()
+            .ok_or(anyhow::anyhow!("Failed to resolve proxy server"))
+    })
+    .await?;
+    println!("  proxy server = {proxy_address}");
+
+    let tcp_stream = timed("tcp", async {
+        let ret = tokio::time::timeout(
+            config.proxy_connect_timeout(),
+            outbound_proxy::connect(
+                config.outbound_proxy.as_ref(),
+                config.outbound_bind_ip,
+                &proxy_address.to_string(),
+            ),
+        )
+        .await??;
+        Ok(ret)
+    })
+    .await?;
+    let _ = tcp_stream.set_nodelay(true);
+
+    let tls_stream = timed("tls", async {
+        let tls_connector = get_tls_connector(config)?;
+        let domain = hostname.as_str().try_into()?;
+        let ret = tokio::time::timeout(
+            config.proxy_connect_timeout(),
+            tls_connector.connect(domain, tcp_stream),
+        )
+        .await??;
+        Ok(ret)
+    })
+    .await?;
+
+    timed("auth", async {
+        let mut connection =
+            ProxyConnection::new(tls_stream).with_protocol_trace(config.trace_protocol);
+        match connection
+            .do_hello(credential.client_access_token().clone())
+            .await?
+        {
+            HelloResult::Accepted => Ok(()),
+            HelloResult::Rejected(Some(reason)) => Err(anyhow::anyhow!("{reason}")),
+            HelloResult::Rejected(None) => Err(anyhow::anyhow!("Authentication failed")),
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+// The home service's hostname, else any service's, from a running portalbox's `api/status`
+fn proxy_hostname(status: &serde_json::Value, base_sub_domain: &str) -> Option<String> {
+    let services = status["service_status"].as_object()?;
+    let home = format!("{base_sub_domain}-home.");
+
+    services
+        .keys()
+        .find(|val| val.starts_with(&home))
+        .or_else(|| services.keys().next())
+        .cloned()
+}
+
+// Run one step, printing how long it took and whether it succeeded
+async fn timed<T, F>(step: &str, fut: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let start = Instant::now();
+    let ret = fut.await;
+    let elapsed = start.elapsed();
+
+    match &ret {
+        Ok(_) => println!("{step:<8} ok      {elapsed:.2?}"),
+        Err(e) => println!("{step:<8} failed  {elapsed:.2?}  {e}"),
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_hostname() {
+        let status = serde_json::json!({
+            "service_status": {
+                "xyz-api.portalbox.app": {},
+                "xyz-home.portalbox.app": {},
+            }
+        });
+        assert_eq!(
+            proxy_hostname(&status, "xyz").as_deref(),
+            Some("xyz-home.portalbox.app")
+        );
+        assert_eq!(
+            proxy_hostname(&status, "abc").as_deref(),
+            Some("xyz-api.portalbox.app")
+        );
+
+        let status = serde_json::json!({"service_status": {}});
+        assert_eq!(proxy_hostname(&status, "xyz"), None);
+        assert_eq!(proxy_hostname(&serde_json::json!({}), "xyz"), None);
+    }
+}
@@ -1,48 +1,436 @@
 use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
     time::Duration,
 };
 
-use backoff::{backoff::Backoff, ExponentialBackoff};
-use models::{consts::MAX_READY_CONNECTIONS, protocol::ProxyConnectionMessage};
+use anyhow::Context;
+use backoff::backoff::Backoff;
+use chrono::{DateTime, Utc};
+use models::{
+    consts::MAX_READY_CONNECTIONS,
+    protocol::{
+        is_valid_token, AuthFailedCode, AuthFailedReason, ProtocolError, ProxyConnectionMessage,
+        SERVICE_UNAVAILABLE_VERSION,
+    },
+    proxy_connection::{HelloResult, ProxyConnection, ProxyEvent},
+};
 use secrecy::SecretString;
-use tokio::{io::copy_bidirectional, net::TcpStream, sync::mpsc::Sender};
+use serde::Serialize;
+use socket2::TcpKeepalive;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{broadcast, mpsc::Sender, watch, Mutex},
+};
 use tokio_rustls::{client::TlsStream, TlsConnector};
 use tokio_util::sync::CancellationToken;
+use url::Url;
 
-use crate::{config::Config, utils::get_tls_connector, ProxyRequest};
+use crate::{
+    config::Config,
+    credentials::Credential,
+    failover::{Failover, ServerChoice},
+    outbound_proxy,
+    relay::{relay, CloseReason},
+    utils::{get_tls_connector, set_tcp_keepalive},
+    ProxyRequest, ProxyTarget,
+};
 
 const CONN_PING_TIMEOUT: Duration = Duration::from_secs(30);
+// Number of round trips in the rolling average
+const RTT_WINDOW: usize = 10;
+
+// Ordered from worst to best
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    Disconnected,
+    Reconnecting,
+    Connected,
+}
+
+// Result of the most recent hello, a connection can be up while new ones are being rejected
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthStatus {
+    #[default]
+    Unknown,
+    Authenticated,
+    AuthFailing,
+}
+
+// Shared with the website, so the dashboard reflects whether the tunnel is actually up. Kept per
+// service hostname, one service failing can't hide behind another's healthy connections.
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    services: BTreeMap<String, ServiceConnectionState>,
+}
+
+impl ConnectionState {
+    fn service_mut(&mut self, hostname: &str) -> &mut ServiceConnectionState {
+        self.services.entry(hostname.to_string()).or_default()
+    }
+
+    // Services with connections up or being retried, or failing auth. A torn down service is left
+    // out once its connections ended.
+    pub fn services(&self) -> impl Iterator<Item = (&String, &ServiceConnectionState)> {
+        self.services.iter().filter(|(_, val)| {
+            val.status() != ConnectionStatus::Disconnected || val.auth == AuthStatus::AuthFailing
+        })
+    }
+
+    // The worst of all services
+    pub fn status(&self) -> ConnectionStatus {
+        self.services()
+            .map(|(_, val)| val.status())
+            .min()
+            .unwrap_or(ConnectionStatus::Disconnected)
+    }
+
+    pub fn auth(&self) -> AuthStatus {
+        let mut auths = self.services().map(|(_, val)| val.auth).peekable();
+        if auths.peek().is_none() {
+            return AuthStatus::Unknown;
+        }
+
+        auths.fold(AuthStatus::Authenticated, |ret, val| match (ret, val) {
+            (AuthStatus::AuthFailing, _) | (_, AuthStatus::AuthFailing) => AuthStatus::AuthFailing,
+            (AuthStatus::Unknown, _) | (_, AuthStatus::Unknown) => AuthStatus::Unknown,
+            _ => AuthStatus::Authenticated,
+        })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        let mut services = self.services().peekable();
+        services.peek().is_some() && services.all(|(_, val)| val.is_ready())
+    }
+
+    // The slowest service's
+    pub fn average_rtt(&self) -> Option<Duration> {
+        self.services()
+            .filter_map(|(_, val)| val.average_rtt())
+            .max()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServiceConnectionState {
+    ready_connections: usize,
+    retrying_connections: usize,
+    rtt_samples: VecDeque<Duration>,
+    auth: AuthStatus,
+}
+
+impl ServiceConnectionState {
+    pub fn auth(&self) -> AuthStatus {
+        self.auth
+    }
+
+    // Ready to serve once a connection is up and the latest hello wasn't rejected
+    pub fn is_ready(&self) -> bool {
+        self.status() == ConnectionStatus::Connected && self.auth == AuthStatus::Authenticated
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        if self.rtt_samples.len() == RTT_WINDOW {
+            self.rtt_samples.pop_front();
+        }
+        self.rtt_samples.push_back(rtt);
+    }
+
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.rtt_samples.is_empty() {
+            return None;
+        }
+
+        let total: Duration = self.rtt_samples.iter().sum();
+        Some(total / self.rtt_samples.len() as u32)
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        if self.ready_connections > 0 {
+            ConnectionStatus::Connected
+        } else if self.retrying_connections > 0 {
+            ConnectionStatus::Reconnecting
+        } else {
+            ConnectionStatus::Disconnected
+        }
+    }
+}
+
+// The proxies of approved services by hostname, each with its own token, so one can be torn down
+// (e.g. revoked server side) while the others keep running
+#[derive(Default)]
+pub struct ProxyServices {
+    next_id: u64,
+    services: HashMap<String, ProxyService>,
+}
+
+struct ProxyService {
+    id: u64,
+    token: CancellationToken,
+    connections: Arc<ConnectionLimit>,
+}
+
+impl ProxyServices {
+    // Tears down the proxy already running for `hostname` if any, e.g. after signing in again
+    fn register(
+        &mut self,
+        hostname: &str,
+        max_connections: usize,
+    ) -> (u64, CancellationToken, Arc<ConnectionLimit>) {
+        self.next_id += 1;
+        let service = ProxyService {
+            id: self.next_id,
+            token: CancellationToken::new(),
+            connections: Arc::new(ConnectionLimit::new(max_connections)),
+        };
+        let ret = (
+            service.id,
+            service.token.clone(),
+            service.connections.clone(),
+        );
+
+        if let Some(previous) = self.services.insert(hostname.to_string(), service) {
+            tracing::debug!(?hostname, "Replacing running proxy");
+            previous.token.cancel();
+        }
+
+        ret
+    }
+
+    // Ignored when `id` was already replaced by a newer proxy for the same hostname
+    fn tear_down(&mut self, hostname: &str, id: u64) {
+        if self.services.get(hostname).map(|val| val.id) == Some(id) {
+            if let Some(service) = self.services.remove(hostname) {
+                service.token.cancel();
+            }
+        }
+    }
+
+    // After signing out, nothing may keep proxying with the removed credential
+    pub fn tear_down_all(&mut self) {
+        for (_, service) in self.services.drain() {
+            service.token.cancel();
+        }
+    }
+
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut ret: Vec<_> = self.services.keys().cloned().collect();
+        ret.sort();
+        ret
+    }
+
+    // Open proxy connections by hostname, warm and active
+    pub fn connections(&self) -> BTreeMap<String, usize> {
+        self.services
+            .iter()
+            .map(|(hostname, service)| (hostname.clone(), service.connections.open()))
+            .collect()
+    }
+}
+
+// Caps the connections of one service, so a client opening too many can't starve the others.
+// Warm connections refused at the cap are opened once an active one closes
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    // Open and refused connections
+    counts: StdMutex<(usize, usize)>,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            counts: StdMutex::new((0, 0)),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.0 < self.max {
+            counts.0 += 1;
+            true
+        } else {
+            counts.1 += 1;
+            false
+        }
+    }
+
+    // Whether a refused connection should be opened now
+    fn release(&self) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        counts.0 -= 1;
+        if counts.1 > 0 {
+            counts.1 -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn open(&self) -> usize {
+        self.counts.lock().unwrap().0
+    }
+}
+
+// Connection lifecycle events, for the dashboard's live view
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyConnectionEvent {
+    Established {
+        hostname: String,
+    },
+    PingRtt {
+        hostname: String,
+        rtt_ms: u64,
+    },
+    Reconnecting {
+        hostname: String,
+        error: String,
+    },
+    Closed {
+        hostname: String,
+        bytes_sent: u64,
+        bytes_received: u64,
+        reason: CloseReason,
+    },
+    ServiceUnavailable {
+        hostname: String,
+        port: u16,
+    },
+}
+
+// State the proxy shares with the dashboard
+#[derive(Clone)]
+pub struct ProxyShared {
+    pub existing_credential: Arc<Mutex<Option<Credential>>>,
+    pub auth_failure: Arc<Mutex<Option<AuthFailedReason>>>,
+    pub connection_state: Arc<Mutex<ConnectionState>>,
+    pub services: Arc<Mutex<ProxyServices>>,
+    // Sending never waits, subscribers that fall behind miss events
+    pub events: broadcast::Sender<ProxyConnectionEvent>,
+}
 
 #[derive(Clone)]
 struct ProxyContext {
     proxy_address: SocketAddr,
-    portalbox_inner_token: SecretString,
+    // Shared by all connections of this proxy, so a rotated token is used for new ones
+    portalbox_inner_token: Arc<Mutex<SecretString>>,
     base_sub_domain: String,
     hostname: String,
+    // Identifies this proxy in `services`
+    service_id: u64,
+    services: Arc<Mutex<ProxyServices>>,
+    connections: Arc<ConnectionLimit>,
     tls_connector: Arc<TlsConnector>,
+    connect_timeout: Duration,
+    latency_warn_threshold: Duration,
+    tcp_keepalive: TcpKeepalive,
+    outbound_proxy: Option<Url>,
+    outbound_bind_ip: Option<IpAddr>,
+    expires_at: Option<DateTime<Utc>>,
+    existing_credential: Arc<Mutex<Option<Credential>>>,
+    auth_failure: Arc<Mutex<Option<AuthFailedReason>>>,
+    connection_state: Arc<Mutex<ConnectionState>>,
+    events: broadcast::Sender<ProxyConnectionEvent>,
+    trace_protocol: bool,
+    // The fallback server's proxy, with the token it approved. Taken from `fallback_approval`
+    // once the fallback server approved it, that can be after the proxy started.
+    fallback: Arc<Mutex<Option<ProxyEndpoint>>>,
+    fallback_approval: watch::Receiver<Option<ProxyTarget>>,
+    failover_retries: u32,
+}
+
+#[derive(Clone)]
+struct ProxyEndpoint {
+    proxy_address: SocketAddr,
+    portalbox_inner_token: Arc<Mutex<SecretString>>,
+}
+
+impl ProxyContext {
+    fn send_event(&self, event: ProxyConnectionEvent) {
+        // Errors only when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    async fn fallback(&self) -> Option<ProxyEndpoint> {
+        let mut fallback = self.fallback.lock().await;
+        if fallback.is_none() {
+            *fallback = self
+                .fallback_approval
+                .borrow()
+                .as_ref()
+                .map(|val| ProxyEndpoint {
+                    proxy_address: val.proxy_address,
+                    portalbox_inner_token: Arc::new(Mutex::new(val.portalbox_inner_token.clone())),
+                });
+        }
+        fallback.clone()
+    }
+
+    async fn endpoint(&self, choice: ServerChoice) -> ProxyEndpoint {
+        let fallback = match choice {
+            ServerChoice::Fallback => self.fallback().await,
+            ServerChoice::Primary => None,
+        };
+
+        fallback.unwrap_or_else(|| ProxyEndpoint {
+            proxy_address: self.proxy_address,
+            portalbox_inner_token: self.portalbox_inner_token.clone(),
+        })
+    }
 }
 
 pub async fn start_deamon(
     config: Arc<Config>,
     proxy_server: SocketAddr,
     mut proxy_request_receiver: tokio::sync::mpsc::Receiver<ProxyRequest>,
+    shared: ProxyShared,
 ) -> Result<(), anyhow::Error> {
-    let connector = get_tls_connector()?;
+    if let Some(ip) = config.outbound_bind_ip {
+        outbound_proxy::ensure_local_ip(ip)?;
+    }
+
+    let connector = get_tls_connector(&config)?;
     let connector = Arc::new(connector);
 
     let start_proxy_fut = async move {
         while let Some(req) = proxy_request_receiver.recv().await {
+            let (service_id, token, connections) = shared
+                .services
+                .lock()
+                .await
+                .register(&req.hostname, config.max_connections_per_service);
             let proxy_context = ProxyContext {
-                proxy_address: proxy_server.clone(),
-                portalbox_inner_token: req.portalbox_inner_token,
+                proxy_address: req.proxy_address.unwrap_or(proxy_server),
+                portalbox_inner_token: Arc::new(Mutex::new(req.portalbox_inner_token)),
                 base_sub_domain: req.base_sub_domain,
                 hostname: req.hostname,
+                service_id,
+                services: shared.services.clone(),
+                connections,
                 tls_connector: connector.clone(),
+                connect_timeout: config.proxy_connect_timeout(),
+                latency_warn_threshold: config.latency_warn_threshold(),
+                tcp_keepalive: config.tcp_keepalive(),
+                outbound_proxy: config.outbound_proxy.clone(),
+                outbound_bind_ip: config.outbound_bind_ip,
+                expires_at: req.expires_at,
+                existing_credential: shared.existing_credential.clone(),
+                auth_failure: shared.auth_failure.clone(),
+                connection_state: shared.connection_state.clone(),
+                events: shared.events.clone(),
+                trace_protocol: config.trace_protocol,
+                fallback: Arc::new(Mutex::new(None)),
+                fallback_approval: req.fallback,
+                failover_retries: config.failover_retries,
             };
 
-            tokio::task::spawn(start_proxy(proxy_context, config.clone()));
+            tokio::task::spawn(start_proxy(proxy_context, config.clone(), token));
         }
     };
 
@@ -51,34 +439,57 @@ pub async fn start_deamon(
     Ok(())
 }
 
-async fn start_proxy(context: ProxyContext, config: Arc<Config>) -> Result<(), anyhow::Error> {
+async fn start_proxy(
+    context: ProxyContext,
+    config: Arc<Config>,
+    token: CancellationToken,
+) -> Result<(), anyhow::Error> {
     tracing::info!(?context.base_sub_domain, "Starting proxy...");
 
     let (new_stream_sender, mut new_stream_receiver) =
         tokio::sync::mpsc::channel::<()>(MAX_READY_CONNECTIONS);
     let new_stream_sender_1 = new_stream_sender.clone();
 
-    let token = CancellationToken::new();
     let token_1 = token.clone();
 
+    let existing_credential = context.existing_credential.clone();
+    let services = context.services.clone();
+    let hostname = context.hostname.clone();
+    let service_id = context.service_id;
+    let expiry_fut = wait_till_expired(context.expires_at);
+
     let create_connection_fut = async move {
         while let Some(_) = new_stream_receiver.recv().await {
+            if !context.connections.try_acquire() {
+                tracing::warn!(
+                    hostname = ?context.hostname,
+                    max = context.connections.max,
+                    "Connection cap reached, not opening a new warm connection"
+                );
+                continue;
+            }
+
             let proxy_context_task = context.clone();
             let new_stream_sender_task = new_stream_sender_1.clone();
             let token_task = token_1.clone();
             let config = config.clone();
 
             let connect_fut = async move {
+                let connections = proxy_context_task.connections.clone();
                 let ret = run_proxy_connection(
                     proxy_context_task,
                     config,
-                    new_stream_sender_task,
+                    new_stream_sender_task.clone(),
                     token_task,
                 )
                 .await;
                 if let Err(e) = ret {
                     tracing::error!(?e, "connect_proxy error");
                 }
+
+                if connections.release() {
+                    let _ = new_stream_sender_task.send(()).await;
+                }
             };
 
             let _handle = tokio::task::spawn(connect_fut);
@@ -95,13 +506,34 @@ async fn start_proxy(context: ProxyContext, config: Arc<Config>) -> Result<(), a
         _ = token.cancelled() => {
             tracing::debug!("Terminating proxy...");
         }
+        _ = expiry_fut => {
+            tracing::info!("Guest session expired, terminating proxy...");
+            token.cancel();
+
+            // Unless the user signed in again in the meantime
+            let mut cred_guard = existing_credential.lock().await;
+            if cred_guard.as_ref().map(|val| val.is_expired()).unwrap_or_default() {
+                *cred_guard = None;
+            }
+        }
     }
 
+    services.lock().await.tear_down(&hostname, service_id);
     tracing::debug!("Proxy ended");
 
     Ok(())
 }
 
+async fn wait_till_expired(expires_at: Option<DateTime<Utc>>) {
+    match expires_at {
+        Some(val) => {
+            let remaining = (val - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 // After: always kick off a new connection
 async fn run_proxy_connection(
     proxy_context: ProxyContext,
@@ -110,24 +542,78 @@ async fn run_proxy_connection(
     token: CancellationToken,
 ) -> Result<(), anyhow::Error> {
     tracing::debug!(?proxy_context.proxy_address, "run_proxy_connection");
-    let mut backoff = ExponentialBackoff {
-        max_interval: Duration::from_secs(4),
-        max_elapsed_time: None,
-        ..Default::default()
-    };
+    let mut backoff = config.reconnect_backoff();
+
+    let connection_state = proxy_context.connection_state.clone();
+    let mut retrying = false;
+    // Counted per connection, so each new one tries the primary again first
+    let mut failover = Failover::new(proxy_context.failover_retries);
 
     // Loop until we have a ready connection
-    let mut proxy_stream = loop {
+    let (mut proxy_stream, choice) = loop {
         if token.is_cancelled() {
+            if retrying {
+                connection_state
+                    .lock()
+                    .await
+                    .service_mut(&proxy_context.hostname)
+                    .retrying_connections -= 1;
+            }
             return Ok(());
         }
 
-        let ret = get_ready_connection(&proxy_context, token.clone()).await;
+        let ret = get_ready_connection(&proxy_context, &mut failover, token.clone()).await;
 
         match ret {
-            Ok(val) => break val,
+            Ok(val) => {
+                let mut state = connection_state.lock().await;
+                let state = state.service_mut(&proxy_context.hostname);
+                if retrying {
+                    state.retrying_connections -= 1;
+                }
+                state.ready_connections += 1;
+                proxy_context.send_event(ProxyConnectionEvent::Established {
+                    hostname: proxy_context.hostname.clone(),
+                });
+                break val;
+            }
             Err(e) => {
+                if let Some(e) = e.downcast_ref::<ProtocolError>() {
+                    if !e.is_retryable() {
+                        tracing::error!(?e, "Incompatible proxy protocol, stopping");
+                        if retrying {
+                            connection_state
+                                .lock()
+                                .await
+                                .service_mut(&proxy_context.hostname)
+                                .retrying_connections -= 1;
+                        }
+                        token.cancel();
+                        return Err(anyhow::anyhow!("Incompatible proxy protocol"));
+                    }
+                }
+
+                if !retrying {
+                    retrying = true;
+                    connection_state
+                        .lock()
+                        .await
+                        .service_mut(&proxy_context.hostname)
+                        .retrying_connections += 1;
+                }
+
                 tracing::error!(?e, "Error getting ready connection, trying again");
+                proxy_context.send_event(ProxyConnectionEvent::Reconnecting {
+                    hostname: proxy_context.hostname.clone(),
+                    error: e.to_string(),
+                });
+
+                let has_fallback = proxy_context.fallback().await.is_some();
+                if let Some(choice) = failover.record_failure(has_fallback) {
+                    tracing::warn!(?choice, "Proxy server unreachable, failing over");
+                    backoff.reset();
+                    continue;
+                }
                 if let Some(b) = backoff.next_backoff() {
                     let _ = tokio::time::sleep(b).await;
                 }
@@ -135,95 +621,960 @@ async fn run_proxy_connection(
         }
     };
 
-    let data_type = wailt_till_data(&mut proxy_stream).await;
+    let endpoint = proxy_context.endpoint(choice).await;
+    let event = wailt_till_data(&mut proxy_stream, &endpoint).await;
+    connection_state
+        .lock()
+        .await
+        .service_mut(&proxy_context.hostname)
+        .ready_connections -= 1;
 
     // Start/error receiving data:
     // - Signal a new connection
     // - Continue this task to end
-    tracing::debug!(?data_type, "Connection active, creating a new one");
+    tracing::debug!(?event, "Connection active, creating a new one");
+    // Before the new connection starts, so it doesn't retry with a token known to be unusable
+    if let Err(ProtocolError::InvalidToken) = &event {
+        if choice == ServerChoice::Primary {
+            handle_auth_failed(&proxy_context, Some(invalid_token_reason())).await;
+        }
+    }
     let _ = new_stream_sender.send(()).await;
 
     // Return if there's any error with waiting for data.
-    let data_type = data_type?;
+    let event = event?;
 
-    let dest_port = match data_type {
-        ProxyConnectionMessage::DataHome => config.local_home_service_port,
-        ProxyConnectionMessage::DataVscode => config.vscode_port,
-        ProxyConnectionMessage::DataSsh => config.ssh_port,
-        _ => return Err(anyhow::anyhow!("Invalid data_type")),
-    };
+    let local_service_address = local_address(&config, &event)?;
 
-    let local_service_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), dest_port);
+    let mut local_stream = match TcpStream::connect(local_service_address).await {
+        Ok(val) => val,
+        Err(e) => {
+            let port = local_service_address.port();
 
-    let mut local_stream = TcpStream::connect(local_service_address).await?;
+            // So the remote user can be told the service is starting up, not just see a drop.
+            // Older servers would relay the message as data, they just get the close.
+            if proxy_stream.version() >= SERVICE_UNAVAILABLE_VERSION {
+                let _ = proxy_stream
+                    .write_message(ProxyConnectionMessage::ServiceUnavailable)
+                    .await;
+            }
+            let _ = proxy_stream.get_mut().shutdown().await;
+            proxy_context.send_event(ProxyConnectionEvent::ServiceUnavailable {
+                hostname: proxy_context.hostname.clone(),
+                port,
+            });
+            return Err(e).with_context(|| format!("Local service on port {port} not reachable"));
+        }
+    };
 
-    let _ = copy_bidirectional(&mut proxy_stream, &mut local_stream).await;
+    let relayed = relay(
+        tokio::io::split(&mut local_stream),
+        tokio::io::split(proxy_stream.get_mut()),
+        true,
+    )
+    .await;
+    if relayed.reason.error.is_some() {
+        tracing::warn!(hostname = ?proxy_context.hostname, reason = %relayed.reason, "Proxy connection failed");
+    } else {
+        tracing::debug!(hostname = ?proxy_context.hostname, reason = %relayed.reason, "Proxy connection closed");
+    }
+    proxy_context.send_event(ProxyConnectionEvent::Closed {
+        hostname: proxy_context.hostname.clone(),
+        bytes_sent: relayed.bytes_sent,
+        bytes_received: relayed.bytes_received,
+        reason: relayed.reason,
+    });
 
     Ok(())
 }
 
+fn local_address(config: &Config, event: &ProxyEvent) -> Result<SocketAddr, anyhow::Error> {
+    let localhost = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+
+    match event {
+        ProxyEvent::Data(data_type) => {
+            if !is_service_allowed(config, data_type) {
+                tracing::warn!(
+                    ?data_type,
+                    "Service not in allowed_services, refusing connection"
+                );
+                return Err(anyhow::anyhow!("Service not allowed"));
+            }
+
+            match data_type {
+                ProxyConnectionMessage::DataHome => Ok(localhost(config.local_home_service_port)),
+                ProxyConnectionMessage::DataVscode => Ok(config.vscode_local_address()),
+                ProxyConnectionMessage::DataSsh => Ok(localhost(config.ssh_port)),
+                _ => Err(anyhow::anyhow!("Invalid data_type")),
+            }
+        }
+        ProxyEvent::Forward(name) => config
+            .forwarded_service_port(name)
+            .map(localhost)
+            .ok_or_else(|| {
+                tracing::warn!(?name, "Service not forwarded, refusing connection");
+                anyhow::anyhow!("Service not allowed")
+            }),
+        ProxyEvent::TokenRotated(_) => Err(anyhow::anyhow!("Invalid data_type")),
+    }
+}
+
+fn is_service_allowed(config: &Config, data_type: &ProxyConnectionMessage) -> bool {
+    let service = match data_type {
+        ProxyConnectionMessage::DataHome => "home",
+        ProxyConnectionMessage::DataVscode => "vscode",
+        ProxyConnectionMessage::DataSsh => "ssh",
+        _ => return false,
+    };
+
+    if service == "vscode" && !config.start_vscode {
+        return false;
+    }
+
+    config.allowed_services.iter().any(|val| val == service)
+}
+
+// Also returns which server the connection is to, so its token rotations are kept apart
 async fn get_ready_connection(
     proxy_context: &ProxyContext,
+    failover: &mut Failover,
     token: CancellationToken,
-) -> Result<TlsStream<TcpStream>, anyhow::Error> {
-    let tcp_stream = TcpStream::connect(proxy_context.proxy_address).await?;
+) -> Result<(ProxyConnection<TlsStream<TcpStream>>, ServerChoice), anyhow::Error> {
+    let choice = failover.current();
+    let endpoint = proxy_context.endpoint(choice).await;
+
+    // Sending it would fail as a protocol error, but it's the credential that's unusable
+    let connection_token = endpoint.portalbox_inner_token.lock().await.clone();
+    if !is_valid_token(&connection_token) {
+        return Err(auth_rejected(
+            proxy_context,
+            failover,
+            choice,
+            Some(invalid_token_reason()),
+            &token,
+        )
+        .await);
+    }
+
+    let tcp_stream = tokio::time::timeout(
+        proxy_context.connect_timeout,
+        outbound_proxy::connect(
+            proxy_context.outbound_proxy.as_ref(),
+            proxy_context.outbound_bind_ip,
+            &endpoint.proxy_address.to_string(),
+        ),
+    )
+    .await??;
     let _ = tcp_stream.set_nodelay(true);
+    set_tcp_keepalive(&tcp_stream, &proxy_context.tcp_keepalive);
 
     let domain = proxy_context.hostname.as_str().try_into()?;
-    let mut tls_stream = proxy_context
-        .tls_connector
-        .connect(domain, tcp_stream)
-        .await?;
-
-    let _ = models::protocol::write_hello_message(
-        proxy_context.portalbox_inner_token.clone(),
-        &mut tls_stream,
+    let tls_stream = tokio::time::timeout(
+        proxy_context.connect_timeout,
+        proxy_context.tls_connector.connect(domain, tcp_stream),
     )
-    .await?;
+    .await??;
 
-    let ack_mess = models::protocol::read_proxy_message(&mut tls_stream).await?;
+    let mut connection =
+        ProxyConnection::new(tls_stream).with_protocol_trace(proxy_context.trace_protocol);
 
-    match ack_mess {
-        ProxyConnectionMessage::AuthOk => Ok(tls_stream),
-        ProxyConnectionMessage::AuthFailed => {
-            token.cancel();
-            Err(anyhow::anyhow!("Stream failed auth"))
+    let hello = connection.do_hello(connection_token).await?;
+
+    match hello {
+        HelloResult::Accepted => {
+            proxy_context
+                .connection_state
+                .lock()
+                .await
+                .service_mut(&proxy_context.hostname)
+                .auth = AuthStatus::Authenticated;
+            // Not timing the hello, that includes the server checking the token
+            if let Some(rtt) = connection.ping().await? {
+                record_rtt(proxy_context, rtt).await;
+            }
+            Ok((connection, choice))
         }
-        val @ _ => {
-            tracing::error!(?val, "Got unepxtected proxy message");
-            Err(anyhow::anyhow!("Unexpected proxy message"))
+        HelloResult::Rejected(reason) => {
+            Err(auth_rejected(proxy_context, failover, choice, reason, &token).await)
         }
     }
 }
 
-// - Reply to ping message
-// - Error out if this task doesn't see any ping message for a pre-defined period
-// - Return once got the `data` message
+fn invalid_token_reason() -> AuthFailedReason {
+    AuthFailedReason {
+        code: AuthFailedCode::InvalidToken,
+        message: None,
+    }
+}
+
+async fn auth_rejected(
+    proxy_context: &ProxyContext,
+    failover: &mut Failover,
+    choice: ServerChoice,
+    reason: Option<AuthFailedReason>,
+    token: &CancellationToken,
+) -> anyhow::Error {
+    // The service keeps running on the primary, only the fallback is given up on
+    if choice == ServerChoice::Fallback {
+        tracing::warn!(?reason, "Fallback proxy server rejected the connection");
+        failover.use_primary();
+
+        return anyhow::anyhow!("Stream failed auth on the fallback");
+    }
+
+    token.cancel();
+    handle_auth_failed(proxy_context, reason).await;
+
+    anyhow::anyhow!("Stream failed auth")
+}
+
+async fn record_rtt(proxy_context: &ProxyContext, rtt: Duration) {
+    proxy_context.send_event(ProxyConnectionEvent::PingRtt {
+        hostname: proxy_context.hostname.clone(),
+        rtt_ms: rtt.as_millis() as u64,
+    });
+
+    let mut state = proxy_context.connection_state.lock().await;
+    let state = state.service_mut(&proxy_context.hostname);
+    let before = state.average_rtt();
+    state.record_rtt(rtt);
+    let after = state.average_rtt();
+
+    let threshold = proxy_context.latency_warn_threshold;
+    let was_degraded = before.map(|val| val > threshold).unwrap_or_default();
+    let is_degraded = after.map(|val| val > threshold).unwrap_or_default();
+
+    if is_degraded && !was_degraded {
+        tracing::warn!(hostname = ?proxy_context.hostname, average_rtt = ?after, ?threshold, "Proxy latency degraded");
+    } else if was_degraded && !is_degraded {
+        tracing::info!(hostname = ?proxy_context.hostname, average_rtt = ?after, "Proxy latency recovered");
+    }
+}
+
+async fn handle_auth_failed(proxy_context: &ProxyContext, reason: Option<AuthFailedReason>) {
+    let reason = reason.unwrap_or(AuthFailedReason {
+        code: AuthFailedCode::Unknown,
+        message: None,
+    });
+    tracing::error!(?proxy_context.base_sub_domain, %reason, "Proxy auth failed");
+    proxy_context
+        .connection_state
+        .lock()
+        .await
+        .service_mut(&proxy_context.hostname)
+        .auth = AuthStatus::AuthFailing;
+
+    // Only this service is torn down, the proxies of other services keep running
+    proxy_context
+        .services
+        .lock()
+        .await
+        .tear_down(&proxy_context.hostname, proxy_context.service_id);
+
+    if reason.code.requires_signin() {
+        let mut cred_guard = proxy_context.existing_credential.lock().await;
+        *cred_guard = None;
+    }
+
+    let mut auth_failure_guard = proxy_context.auth_failure.lock().await;
+    *auth_failure_guard = Some(reason);
+}
+
+// - Store rotated tokens for subsequent connections
+// - Return once got the `data` message, pings are answered by `await_data`
+// Returns `Data` or `Forward`, token rotations are handled here
 async fn wailt_till_data(
-    stream: &mut TlsStream<TcpStream>,
-) -> anyhow::Result<ProxyConnectionMessage> {
-    let ret = loop {
-        let mess = tokio::time::timeout(
-            CONN_PING_TIMEOUT,
-            models::protocol::read_proxy_message(stream),
+    connection: &mut ProxyConnection<TlsStream<TcpStream>>,
+    endpoint: &ProxyEndpoint,
+) -> Result<ProxyEvent, ProtocolError> {
+    loop {
+        match connection.await_data(CONN_PING_TIMEOUT).await? {
+            ProxyEvent::TokenRotated(token) => {
+                tracing::info!(?endpoint.proxy_address, "Proxy token rotated");
+                *endpoint.portalbox_inner_token.lock().await = token;
+            }
+            val => return Ok(val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServiceConfig, relay::Side, utils::tls_connector_with_roots};
+
+    fn test_proxy_context(proxy_address: SocketAddr) -> ProxyContext {
+        ProxyContext {
+            proxy_address,
+            portalbox_inner_token: Arc::new(Mutex::new(SecretString::new(
+                "t".repeat(models::protocol::AUTH_TOKEN_LENGTH),
+            ))),
+            base_sub_domain: "test".into(),
+            hostname: "test-home.portalbox.app".into(),
+            service_id: 0,
+            services: Arc::new(Mutex::new(ProxyServices::default())),
+            connections: Arc::new(ConnectionLimit::new(8)),
+            tls_connector: Arc::new(get_tls_connector(&Config::default()).unwrap()),
+            connect_timeout: Duration::from_millis(200),
+            latency_warn_threshold: Duration::from_millis(500),
+            tcp_keepalive: Config::default().tcp_keepalive(),
+            outbound_proxy: None,
+            outbound_bind_ip: None,
+            expires_at: None,
+            existing_credential: Arc::new(Mutex::new(None)),
+            auth_failure: Arc::new(Mutex::new(None)),
+            connection_state: Arc::new(Mutex::new(ConnectionState::default())),
+            events: broadcast::channel(16).0,
+            trace_protocol: false,
+            fallback: Arc::new(Mutex::new(None)),
+            fallback_approval: watch::channel(None).1,
+            failover_retries: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallback_approved_later() {
+        let primary: SocketAddr = "127.0.0.1:46637".parse().unwrap();
+        let fallback: SocketAddr = "127.0.0.2:46637".parse().unwrap();
+        let (sender, receiver) = watch::channel(None);
+        let mut proxy_context = test_proxy_context(primary);
+        proxy_context.fallback_approval = receiver;
+
+        // Stays on the primary until then
+        assert!(proxy_context.fallback().await.is_none());
+        let endpoint = proxy_context.endpoint(ServerChoice::Fallback).await;
+        assert_eq!(endpoint.proxy_address, primary);
+
+        sender
+            .send(Some(ProxyTarget {
+                proxy_address: fallback,
+                portalbox_inner_token: SecretString::new("f".into()),
+            }))
+            .unwrap();
+        let endpoint = proxy_context.endpoint(ServerChoice::Fallback).await;
+        assert_eq!(endpoint.proxy_address, fallback);
+        let endpoint = proxy_context.endpoint(ServerChoice::Primary).await;
+        assert_eq!(endpoint.proxy_address, primary);
+    }
+
+    #[test]
+    fn test_is_service_allowed() {
+        let config = Config::default();
+        assert!(is_service_allowed(
+            &config,
+            &ProxyConnectionMessage::DataSsh
+        ));
+
+        let config = Config {
+            allowed_services: vec!["home".into(), "vscode".into()],
+            ..Default::default()
+        };
+        assert!(is_service_allowed(
+            &config,
+            &ProxyConnectionMessage::DataHome
+        ));
+        assert!(is_service_allowed(
+            &config,
+            &ProxyConnectionMessage::DataVscode
+        ));
+        assert!(!is_service_allowed(
+            &config,
+            &ProxyConnectionMessage::DataSsh
+        ));
+        assert!(!is_service_allowed(&config, &ProxyConnectionMessage::Ping));
+    }
+
+    #[test]
+    fn test_local_address() {
+        let mut config = Config::default();
+        config.services.push(ServiceConfig {
+            name: "grafana".into(),
+            port: 3001,
+            icon_url: None,
+            forward: true,
+        });
+        config.services.push(ServiceConfig {
+            name: "private".into(),
+            port: 3002,
+            icon_url: None,
+            forward: false,
+        });
+
+        let event = ProxyEvent::Data(ProxyConnectionMessage::DataVscode);
+        assert_eq!(
+            local_address(&config, &event).unwrap(),
+            "127.0.0.1:3000".parse().unwrap()
+        );
+
+        let event = ProxyEvent::Forward("grafana".into());
+        assert_eq!(
+            local_address(&config, &event).unwrap(),
+            "127.0.0.1:3001".parse().unwrap()
+        );
+
+        config.vscode_host = "10.0.0.2".parse().unwrap();
+        let event = ProxyEvent::Data(ProxyConnectionMessage::DataVscode);
+        assert_eq!(
+            local_address(&config, &event).unwrap(),
+            "10.0.0.2:3000".parse().unwrap()
+        );
+
+        let event = ProxyEvent::Forward("private".into());
+        assert!(local_address(&config, &event).is_err());
+        let event = ProxyEvent::Forward("missing".into());
+        assert!(local_address(&config, &event).is_err());
+        let event = ProxyEvent::Data(ProxyConnectionMessage::Ping);
+        assert!(local_address(&config, &event).is_err());
+    }
+
+    #[test]
+    fn test_proxy_services_independent_teardown() {
+        let mut services = ProxyServices::default();
+        let (box_id, box_token, _) = services.register("box-home.portalbox.app", 8);
+        let (lab_id, lab_token, _) = services.register("lab-home.portalbox.app", 8);
+
+        services.tear_down("box-home.portalbox.app", box_id);
+        assert!(box_token.is_cancelled());
+        assert!(!lab_token.is_cancelled());
+        assert_eq!(services.hostnames(), ["lab-home.portalbox.app"]);
+
+        // Signing in again replaces the running proxy, the old one going away doesn't affect it
+        let (new_lab_id, new_lab_token, _) = services.register("lab-home.portalbox.app", 8);
+        assert!(lab_token.is_cancelled());
+        services.tear_down("lab-home.portalbox.app", lab_id);
+        assert!(!new_lab_token.is_cancelled());
+        assert_eq!(services.hostnames(), ["lab-home.portalbox.app"]);
+
+        services.tear_down("lab-home.portalbox.app", new_lab_id);
+        assert!(new_lab_token.is_cancelled());
+        assert!(services.hostnames().is_empty());
+
+        let (_, box_token, _) = services.register("box-home.portalbox.app", 8);
+        let (_, lab_token, _) = services.register("lab-home.portalbox.app", 8);
+        services.tear_down_all();
+        assert!(box_token.is_cancelled());
+        assert!(lab_token.is_cancelled());
+        assert!(services.hostnames().is_empty());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut services = ProxyServices::default();
+        let (_, _, connections) = services.register("box-home.portalbox.app", 2);
+
+        assert!(connections.try_acquire());
+        assert!(connections.try_acquire());
+        // Refused at the cap, twice
+        assert!(!connections.try_acquire());
+        assert!(!connections.try_acquire());
+        assert_eq!(services.connections()["box-home.portalbox.app"], 2);
+
+        // Each closing connection makes room for one refused earlier
+        assert!(connections.release());
+        assert!(connections.try_acquire());
+        assert!(connections.release());
+        assert!(connections.try_acquire());
+        assert_eq!(services.connections()["box-home.portalbox.app"], 2);
+
+        assert!(!connections.release());
+        assert!(!connections.release());
+        assert_eq!(services.connections()["box-home.portalbox.app"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_auth_failed_tears_down_one_service() {
+        let services = Arc::new(Mutex::new(ProxyServices::default()));
+        let mut contexts = vec![];
+        let mut tokens = vec![];
+        for hostname in ["box-home.portalbox.app", "lab-home.portalbox.app"] {
+            let (service_id, token, _) = services.lock().await.register(hostname, 8);
+            let mut context = test_proxy_context("127.0.0.1:46637".parse().unwrap());
+            context.hostname = hostname.into();
+            context.service_id = service_id;
+            context.services = services.clone();
+            contexts.push(context);
+            tokens.push(token);
+        }
+
+        let reason = AuthFailedReason {
+            code: models::protocol::AuthFailedCode::Unknown,
+            message: None,
+        };
+        handle_auth_failed(&contexts[0], Some(reason)).await;
+
+        assert!(tokens[0].is_cancelled());
+        assert!(!tokens[1].is_cancelled());
+        assert_eq!(
+            services.lock().await.hostnames(),
+            ["lab-home.portalbox.app"]
+        );
+    }
+
+    #[test]
+    fn test_average_rtt() {
+        let mut state = ServiceConnectionState::default();
+        assert_eq!(state.average_rtt(), None);
+
+        state.record_rtt(Duration::from_millis(10));
+        state.record_rtt(Duration::from_millis(30));
+        assert_eq!(state.average_rtt(), Some(Duration::from_millis(20)));
+
+        // Older samples roll out of the window
+        for _ in 0..RTT_WINDOW {
+            state.record_rtt(Duration::from_millis(50));
+        }
+        assert_eq!(state.average_rtt(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_connection_status() {
+        let mut state = ServiceConnectionState::default();
+        assert_eq!(state.status(), ConnectionStatus::Disconnected);
+
+        state.retrying_connections = 2;
+        assert_eq!(state.status(), ConnectionStatus::Reconnecting);
+
+        state.ready_connections = 1;
+        assert_eq!(state.status(), ConnectionStatus::Connected);
+    }
+
+    #[test]
+    fn test_is_ready() {
+        let mut state = ServiceConnectionState {
+            ready_connections: 1,
+            ..Default::default()
+        };
+        assert!(!state.is_ready());
+
+        state.auth = AuthStatus::Authenticated;
+        assert!(state.is_ready());
+
+        // Still connected, but new connections are rejected
+        state.auth = AuthStatus::AuthFailing;
+        assert!(!state.is_ready());
+    }
+
+    #[test]
+    fn test_connection_state_per_service() {
+        let mut state = ConnectionState::default();
+        assert_eq!(state.status(), ConnectionStatus::Disconnected);
+        assert_eq!(state.auth(), AuthStatus::Unknown);
+        assert!(!state.is_ready());
+
+        for hostname in ["box-home.portalbox.app", "lab-home.portalbox.app"] {
+            let service = state.service_mut(hostname);
+            service.ready_connections = 1;
+            service.auth = AuthStatus::Authenticated;
+        }
+        state
+            .service_mut("box-home.portalbox.app")
+            .record_rtt(Duration::from_millis(10));
+        state
+            .service_mut("lab-home.portalbox.app")
+            .record_rtt(Duration::from_millis(30));
+        assert_eq!(state.status(), ConnectionStatus::Connected);
+        assert!(state.is_ready());
+        assert_eq!(state.average_rtt(), Some(Duration::from_millis(30)));
+
+        // One service down is enough to show it, the other's connections don't hide it
+        let lab = state.service_mut("lab-home.portalbox.app");
+        lab.ready_connections = 0;
+        lab.retrying_connections = 1;
+        assert_eq!(state.status(), ConnectionStatus::Reconnecting);
+        assert!(!state.is_ready());
+
+        state.service_mut("lab-home.portalbox.app").auth = AuthStatus::AuthFailing;
+        assert_eq!(state.auth(), AuthStatus::AuthFailing);
+
+        // Torn down, once its connections ended it's no longer part of the state
+        let lab = state.service_mut("lab-home.portalbox.app");
+        lab.retrying_connections = 0;
+        lab.auth = AuthStatus::Authenticated;
+        assert_eq!(state.services().count(), 1);
+        assert_eq!(state.status(), ConnectionStatus::Connected);
+        assert!(state.is_ready());
+        assert_eq!(state.average_rtt(), Some(Duration::from_millis(10)));
+    }
+
+    // Answers one hello, accepting it when `reason` is `None`
+    async fn start_mock_auth_server(
+        reason: Option<AuthFailedReason>,
+    ) -> (ProxyContext, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy_context = test_proxy_context(listener.local_addr().unwrap());
+        let (acceptor, connector) = test_tls_pair(&proxy_context.hostname);
+        proxy_context.tls_connector = Arc::new(connector);
+        proxy_context.connect_timeout = Duration::from_secs(5);
+
+        let handle = tokio::spawn(async move {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(tcp_stream).await.unwrap();
+            let mut connection = ProxyConnection::new(tls_stream);
+
+            connection.read_hello().await.unwrap();
+            match reason {
+                Some(reason) => connection.write_auth_failed(&reason).await.unwrap(),
+                None => connection
+                    .write_message(ProxyConnectionMessage::AuthOk)
+                    .await
+                    .unwrap(),
+            }
+        });
+
+        (proxy_context, handle)
+    }
+
+    #[tokio::test]
+    async fn test_auth_status_from_hello() {
+        let (proxy_context, server) = start_mock_auth_server(None).await;
+        get_ready_connection(
+            &proxy_context,
+            &mut Failover::new(1),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+        assert_eq!(
+            proxy_context
+                .connection_state
+                .lock()
+                .await
+                .service_mut(&proxy_context.hostname)
+                .auth(),
+            AuthStatus::Authenticated
+        );
+        assert!(proxy_context.auth_failure.lock().await.is_none());
+
+        let reason = AuthFailedReason {
+            code: models::protocol::AuthFailedCode::TokenRevoked,
+            message: Some("revoked by admin".into()),
+        };
+        let (proxy_context, server) = start_mock_auth_server(Some(reason.clone())).await;
+        let token = CancellationToken::new();
+        assert!(
+            get_ready_connection(&proxy_context, &mut Failover::new(1), token.clone())
+                .await
+                .is_err()
+        );
+        server.await.unwrap();
+        assert!(token.is_cancelled());
+        assert_eq!(
+            proxy_context
+                .connection_state
+                .lock()
+                .await
+                .service_mut(&proxy_context.hostname)
+                .auth(),
+            AuthStatus::AuthFailing
+        );
+        assert_eq!(*proxy_context.auth_failure.lock().await, Some(reason));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_requires_signin() {
+        // Nothing listens there, the token is rejected before connecting
+        let proxy_context = test_proxy_context("127.0.0.1:1".parse().unwrap());
+        *proxy_context.portalbox_inner_token.lock().await = SecretString::new("short".into());
+
+        let token = CancellationToken::new();
+        let ret = get_ready_connection(&proxy_context, &mut Failover::new(1), token.clone()).await;
+        assert!(ret.is_err());
+        assert!(token.is_cancelled());
+        assert_eq!(
+            proxy_context
+                .connection_state
+                .lock()
+                .await
+                .service_mut(&proxy_context.hostname)
+                .auth(),
+            AuthStatus::AuthFailing
+        );
+        let reason = proxy_context.auth_failure.lock().await.clone().unwrap();
+        assert_eq!(reason.code, AuthFailedCode::InvalidToken);
+        assert!(reason.code.requires_signin());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_non_routable() {
+        // Non-routable address, the connection attempt never completes
+        let proxy_context = test_proxy_context("10.255.255.1:46637".parse().unwrap());
+
+        let ret = tokio::time::timeout(
+            Duration::from_secs(5),
+            get_ready_connection(
+                &proxy_context,
+                &mut Failover::new(1),
+                CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("connect timeout should fire before the outer timeout");
+
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_tls_handshake() {
+        // Accepts the tcp connection but never answers the tls handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_context = test_proxy_context(listener.local_addr().unwrap());
+
+        let ret = tokio::time::timeout(
+            Duration::from_secs(5),
+            get_ready_connection(
+                &proxy_context,
+                &mut Failover::new(1),
+                CancellationToken::new(),
+            ),
         )
-        .await??;
+        .await
+        .expect("connect timeout should fire before the outer timeout");
+
+        let e = ret.expect_err("tls handshake should time out");
+        assert!(e.is::<tokio::time::error::Elapsed>());
+    }
+
+    // A self signed cert for `hostname`, with a connector trusting only that cert
+    fn test_tls_pair(hostname: &str) -> (tokio_rustls::TlsAcceptor, TlsConnector) {
+        use tokio_rustls::rustls::{self, Certificate, PrivateKey};
 
-        match mess {
-            ProxyConnectionMessage::Ping => {
-                let _write =
-                    models::protocol::write_proxy_message(stream, ProxyConnectionMessage::Pong)
-                        .await?;
+        let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let key_der = PrivateKey(cert.serialize_private_key_der());
+
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+
+        let mut root_cert_store = rustls::RootCertStore::empty();
+        root_cert_store.add(&cert_der).unwrap();
+
+        (
+            tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            tls_connector_with_roots(root_cert_store),
+        )
+    }
+
+    async fn start_echo_service() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn test_proxy_connection_end_to_end() {
+        use models::protocol::AUTH_TOKEN_LENGTH;
+        use secrecy::ExposeSecret;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy_context = test_proxy_context(listener.local_addr().unwrap());
+        let (acceptor, connector) = test_tls_pair(&proxy_context.hostname);
+        proxy_context.tls_connector = Arc::new(connector);
+
+        let connection_token = "t".repeat(AUTH_TOKEN_LENGTH);
+        proxy_context.portalbox_inner_token =
+            Arc::new(Mutex::new(SecretString::new(connection_token.clone())));
+
+        let config = Arc::new(Config {
+            vscode_port: start_echo_service().await,
+            ..Default::default()
+        });
+
+        // Plays the proxy server for one connection
+        let server_fut = async move {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(tcp_stream).await.unwrap();
+            let mut connection = ProxyConnection::new(tls_stream);
+
+            let hello = connection.read_hello().await.unwrap();
+            assert_eq!(hello.connection_token.expose_secret(), &connection_token);
+            connection.write_auth_ok().await.unwrap();
+            assert_eq!(
+                connection.read_message().await.unwrap(),
+                ProxyConnectionMessage::Ping
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            connection
+                .write_message(ProxyConnectionMessage::Pong)
+                .await
+                .unwrap();
+
+            connection
+                .write_message(ProxyConnectionMessage::Ping)
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.read_message().await.unwrap(),
+                ProxyConnectionMessage::Pong
+            );
+
+            connection
+                .write_message(ProxyConnectionMessage::DataVscode)
+                .await
+                .unwrap();
+
+            let stream = connection.get_mut();
+            stream.write_all(b"hello vscode").await.unwrap();
+            let mut buf = [0u8; 12];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello vscode");
+        };
+
+        let mut events = proxy_context.events.subscribe();
+        let (new_stream_sender, mut new_stream_receiver) = tokio::sync::mpsc::channel(1);
+        let client_fut = run_proxy_connection(
+            proxy_context.clone(),
+            config,
+            new_stream_sender,
+            CancellationToken::new(),
+        );
+
+        let server_handle = tokio::spawn(server_fut);
+        let client_handle = tokio::spawn(client_fut);
+
+        tokio::time::timeout(Duration::from_secs(10), server_handle)
+            .await
+            .expect("mock proxy server should finish")
+            .unwrap();
+
+        // Receiving data asks for a new warm connection
+        assert!(new_stream_receiver.recv().await.is_some());
+        {
+            let mut state = proxy_context.connection_state.lock().await;
+            let state = state.service_mut(&proxy_context.hostname);
+            assert_eq!(state.status(), ConnectionStatus::Disconnected);
+            // Includes the delayed Pong
+            assert!(state.average_rtt().unwrap() >= Duration::from_millis(50));
+        }
+
+        // Dropping the server side ends the bridge
+        tokio::time::timeout(Duration::from_secs(10), client_handle)
+            .await
+            .expect("client should finish once the server is gone")
+            .unwrap()
+            .unwrap();
+
+        let closed = loop {
+            if let ProxyConnectionEvent::Closed { reason, .. } = events.recv().await.unwrap() {
+                break reason;
             }
-            val @ (ProxyConnectionMessage::DataHome
-            | ProxyConnectionMessage::DataVscode
-            | ProxyConnectionMessage::DataSsh) => break val,
-            val @ _ => {
-                tracing::error!(?val, "Getting unexpected message");
-                return Err(anyhow::anyhow!("Unexpected message"));
+        };
+        // Dropped without a TLS close_notify
+        assert!(closed.side == Side::Remote && closed.error.is_some());
+    }
+
+    // Runs one connection to a local service that isn't listening, returns what the mock proxy
+    // server got instead of data and the client's result
+    async fn run_service_down(report_version: bool) -> (Vec<u8>, Result<(), anyhow::Error>) {
+        use models::protocol::AUTH_TOKEN_LENGTH;
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy_context = test_proxy_context(listener.local_addr().unwrap());
+        let (acceptor, connector) = test_tls_pair(&proxy_context.hostname);
+        proxy_context.tls_connector = Arc::new(connector);
+        proxy_context.portalbox_inner_token =
+            Arc::new(Mutex::new(SecretString::new("t".repeat(AUTH_TOKEN_LENGTH))));
+
+        // Nothing listens on vscode's port
+        let vscode_port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let config = Arc::new(Config {
+            vscode_port,
+            ..Default::default()
+        });
+
+        let server_fut = async move {
+            let (tcp_stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(tcp_stream).await.unwrap();
+            let mut connection = ProxyConnection::new(tls_stream);
+
+            connection.read_hello().await.unwrap();
+            if report_version {
+                connection.write_auth_ok().await.unwrap();
+                connection.answer_ping().await.unwrap();
+            } else {
+                connection
+                    .write_message(ProxyConnectionMessage::AuthOk)
+                    .await
+                    .unwrap();
             }
-        }
-    };
+            connection
+                .write_message(ProxyConnectionMessage::DataVscode)
+                .await
+                .unwrap();
+
+            // Ends with a clean close
+            let mut received = vec![];
+            connection
+                .get_mut()
+                .read_to_end(&mut received)
+                .await
+                .unwrap();
+            received
+        };
+
+        let mut events = proxy_context.events.subscribe();
+        let (new_stream_sender, _new_stream_receiver) = tokio::sync::mpsc::channel(1);
+        let client_handle = tokio::spawn(run_proxy_connection(
+            proxy_context.clone(),
+            config,
+            new_stream_sender,
+            CancellationToken::new(),
+        ));
+
+        let received = tokio::time::timeout(Duration::from_secs(10), server_fut)
+            .await
+            .expect("mock proxy server should finish");
+        let ret = client_handle.await.unwrap();
+
+        let event = loop {
+            match events.recv().await.unwrap() {
+                val @ ProxyConnectionEvent::ServiceUnavailable { .. } => break val,
+                _ => continue,
+            }
+        };
+        assert_eq!(
+            event,
+            ProxyConnectionEvent::ServiceUnavailable {
+                hostname: proxy_context.hostname.clone(),
+                port: vscode_port,
+            }
+        );
+
+        (received, ret)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_connection_service_down() {
+        let (received, ret) = run_service_down(true).await;
 
-    Ok(ret)
+        // Only the message, instead of any data
+        let code: u16 = ProxyConnectionMessage::ServiceUnavailable.into();
+        assert_eq!(received, code.to_be_bytes());
+        let e = ret.unwrap_err();
+        assert!(e.to_string().contains("not reachable"), "{e:?}");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_connection_service_down_old_server() {
+        let (received, ret) = run_service_down(false).await;
+
+        // It would relay the message as data, so the connection is just closed
+        assert!(received.is_empty());
+        assert!(ret.is_err());
+    }
 }
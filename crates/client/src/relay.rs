@@ -0,0 +1,246 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const BUFFER_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::Local => Side::Remote,
+            Side::Remote => Side::Local,
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::Local => f.write_str("local"),
+            Side::Remote => f.write_str("remote"),
+        }
+    }
+}
+
+// The side that ended a relayed connection, with its error unless it closed cleanly
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CloseReason {
+    pub side: Side,
+    pub error: Option<String>,
+}
+
+impl CloseReason {
+    fn eof(side: Side) -> Self {
+        Self { side, error: None }
+    }
+
+    fn error(side: Side, e: std::io::Error) -> Self {
+        Self {
+            side,
+            error: Some(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(e) => write!(f, "{} error: {e}", self.side),
+            None => write!(f, "{} EOF", self.side),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Relayed {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub reason: CloseReason,
+}
+
+// Copies both ways between the local and remote halves until the connection ends. With
+// `half_close`, like `copy_bidirectional`, a direction that reaches EOF shuts its writer down and
+// the other one keeps going, otherwise the first direction to end ends the relay. An error always
+// ends it.
+pub async fn relay<LR, LW, RR, RW>(
+    (mut local_read, mut local_write): (LR, LW),
+    (mut remote_read, mut remote_write): (RR, RW),
+    half_close: bool,
+) -> Relayed
+where
+    LR: AsyncRead + Unpin,
+    LW: AsyncWrite + Unpin,
+    RR: AsyncRead + Unpin,
+    RW: AsyncWrite + Unpin,
+{
+    let bytes_sent = AtomicU64::new(0);
+    let bytes_received = AtomicU64::new(0);
+
+    let reason = {
+        let to_remote = copy_half(&mut local_read, &mut remote_write, Side::Local, &bytes_sent);
+        let to_local = copy_half(
+            &mut remote_read,
+            &mut local_write,
+            Side::Remote,
+            &bytes_received,
+        );
+        tokio::pin!(to_remote, to_local);
+
+        tokio::select! {
+            ret = &mut to_remote => match ret {
+                Err(reason) => reason,
+                Ok(()) if !half_close => CloseReason::eof(Side::Local),
+                Ok(()) => to_local.await.err().unwrap_or(CloseReason::eof(Side::Local)),
+            },
+            ret = &mut to_local => match ret {
+                Err(reason) => reason,
+                Ok(()) if !half_close => CloseReason::eof(Side::Remote),
+                Ok(()) => to_remote.await.err().unwrap_or(CloseReason::eof(Side::Remote)),
+            },
+        }
+    };
+
+    Relayed {
+        bytes_sent: bytes_sent.into_inner(),
+        bytes_received: bytes_received.into_inner(),
+        reason,
+    }
+}
+
+// Errors are blamed on the side that was read from or written to
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    from: Side,
+    bytes: &AtomicU64,
+) -> Result<(), CloseReason>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let to = from.other();
+    let mut buf = vec![0u8; BUFFER_BYTES];
+
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| CloseReason::error(from, e))?;
+        if n == 0 {
+            break;
+        }
+
+        writer
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| CloseReason::error(to, e))?;
+        writer
+            .flush()
+            .await
+            .map_err(|e| CloseReason::error(to, e))?;
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    writer
+        .shutdown()
+        .await
+        .map_err(|e| CloseReason::error(to, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{duplex, split, ReadBuf};
+
+    use super::*;
+
+    // A peer that resets the connection
+    struct ResetReader;
+
+    impl AsyncRead for ResetReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_local_eof() {
+        let (local, mut local_peer) = duplex(64);
+        let (remote, mut remote_peer) = duplex(64);
+
+        let relay_handle = tokio::spawn(relay(split(local), split(remote), true));
+
+        local_peer.write_all(b"request").await.unwrap();
+        local_peer.shutdown().await.unwrap();
+        let mut buf = vec![];
+        remote_peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"request");
+
+        // Half closed, the answer still gets through
+        remote_peer.write_all(b"answer").await.unwrap();
+        drop(remote_peer);
+        let mut buf = vec![];
+        local_peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"answer");
+
+        let ret = relay_handle.await.unwrap();
+        assert_eq!(ret.reason, CloseReason::eof(Side::Local));
+        assert_eq!(ret.reason.to_string(), "local EOF");
+        assert_eq!((ret.bytes_sent, ret.bytes_received), (7, 6));
+    }
+
+    #[tokio::test]
+    async fn test_relay_remote_eof_without_half_close() {
+        let (local, _local_peer) = duplex(64);
+        let (remote, remote_peer) = duplex(64);
+        drop(remote_peer);
+
+        // Ends although the local side is still open
+        let ret = relay(split(local), split(remote), false).await;
+        assert_eq!(ret.reason, CloseReason::eof(Side::Remote));
+    }
+
+    #[tokio::test]
+    async fn test_relay_remote_reset() {
+        let (local, _local_peer) = duplex(64);
+        let (_, remote_write) = split(duplex(64).0);
+
+        let ret = relay(split(local), (ResetReader, remote_write), true).await;
+        assert_eq!(ret.reason.side, Side::Remote);
+        assert!(ret.reason.to_string().starts_with("remote error: "));
+    }
+
+    #[tokio::test]
+    async fn test_relay_local_write_error() {
+        let (local, local_peer) = duplex(64);
+        let (remote, mut remote_peer) = duplex(64);
+        // Nothing can be written to the local side anymore
+        drop(local_peer);
+
+        let relay_handle = tokio::spawn(relay(split(local), split(remote), true));
+        remote_peer.write_all(b"answer").await.unwrap();
+
+        let ret = relay_handle.await.unwrap();
+        assert_eq!(ret.reason.side, Side::Local);
+        assert!(ret.reason.error.is_some());
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, RequestParts},
+    http::{Extensions, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::request_id::{MakeRequestId, RequestId as TowerRequestId};
+use tracing::Span;
+
+const REQUEST_ID_LENGTH: usize = 8;
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: String;
+}
+
+// Short ids are easier for users to quote in bug reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MakeShortRequestId;
+
+impl MakeRequestId for MakeShortRequestId {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<TowerRequestId> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let id = id[..REQUEST_ID_LENGTH].parse().ok()?;
+        Some(TowerRequestId::new(id))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_extensions(extensions: &Extensions) -> Option<Self> {
+        extensions
+            .get::<TowerRequestId>()
+            .and_then(|val| val.header_value().to_str().ok())
+            .map(|val| RequestId(val.to_string()))
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for RequestId {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        RequestId::from_extensions(req.extensions()).ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+// Makes the request id available to code without access to the request, e.g. `ServerError`
+pub async fn scope_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    match RequestId::from_extensions(request.extensions()) {
+        Some(RequestId(id)) => CURRENT_REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
+
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(|val| val.clone()).ok()
+}
+
+// Static files and status polling, too frequent to be worth exporting
+fn is_quiet_request(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    matches!(path, "/api/status" | "/api/apps-status" | "/readyz") || file_name.contains('.')
+}
+
+// Quiet requests get a trace level span, below the debug level exported to telemetry
+pub fn make_span<B>(request: &Request<B>) -> Span {
+    let request_id = RequestId::from_extensions(request.extensions())
+        .map(|val| val.0)
+        .unwrap_or_default();
+
+    if is_quiet_request(request.uri().path()) {
+        return tracing::trace_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+            %request_id,
+        );
+    }
+
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        %request_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_quiet_request() {
+        assert!(is_quiet_request("/api/status"));
+        assert!(is_quiet_request("/api/apps-status"));
+        assert!(is_quiet_request("/readyz"));
+        assert!(is_quiet_request("/css/main.css"));
+        assert!(is_quiet_request("/favicon.ico"));
+        assert!(!is_quiet_request("/"));
+        assert!(!is_quiet_request("/contact"));
+        assert!(!is_quiet_request("/api/signout"));
+    }
+}
@@ -1,23 +1,50 @@
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::{cli::Reset, config::Config, credentials::CredManager};
+use indicatif::HumanBytes;
+use url::Url;
+
+use crate::{
+    browser, cache,
+    cli::{Reset, ResetCommands},
+    config::Config,
+    credentials::CredManager,
+    disk,
+};
+
+const SIGNOUT_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub async fn reset(reset: Reset, config: Config) -> Result<(), anyhow::Error> {
     tracing::info!(?reset, home_dir = ?config.home_dir, "reseting");
 
+    if reset.dry_run {
+        dry_run(&reset.command, &config);
+        return Ok(());
+    }
+
     match reset.command {
-        crate::cli::ResetCommands::Credentials => {
+        crate::cli::ResetCommands::Credentials { server: None } => {
             let _ = CredManager::delete(&config).await?;
         }
+        crate::cli::ResetCommands::Credentials {
+            server: Some(server_url),
+        } => {
+            let _ = remove_server_credential(&config, &server_url).await?;
+        }
         crate::cli::ResetCommands::Apps => {
             let _ = clean_apps(&config.apps_dir()).await?;
         }
         crate::cli::ResetCommands::AppsData => {
             let _ = clean_apps_data(&config.apps_data_dir()).await?;
+            let _ = clean_vscode_data_root(&config).await?;
         }
         crate::cli::ResetCommands::All => {
             let _ = clean_apps(&config.apps_dir()).await?;
             let _ = clean_apps_data(&config.apps_data_dir()).await?;
+            let _ = clean_vscode_data_root(&config).await?;
+            let _ = cache::delete(&config).await?;
             let _ = CredManager::delete(&config).await?;
         }
     }
@@ -25,6 +52,65 @@ pub async fn reset(reset: Reset, config: Config) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+fn dry_run(command: &ResetCommands, config: &Config) {
+    if let ResetCommands::Credentials {
+        server: Some(server_url),
+    } = command
+    {
+        println!(
+            "Would remove the credential for {server_url} from {}",
+            config.credentials_file_path().display()
+        );
+        return;
+    }
+
+    let paths: Vec<_> = deleted_paths(command, config)
+        .into_iter()
+        .filter(|val| val.exists())
+        .collect();
+    if paths.is_empty() {
+        println!("Nothing to delete");
+        return;
+    }
+
+    println!("Would delete:");
+    for path in paths {
+        println!(
+            "{:>10}  {}",
+            HumanBytes(disk::path_size(&path)).to_string(),
+            path.display()
+        );
+    }
+}
+
+// Whole files and dirs a command deletes, a single server's credential is edited out instead
+fn deleted_paths(command: &ResetCommands, config: &Config) -> Vec<PathBuf> {
+    let mut ret = vec![];
+
+    match command {
+        ResetCommands::Credentials { server: None } => {
+            ret.push(config.credentials_file_path());
+        }
+        ResetCommands::Credentials { server: Some(_) } => {}
+        ResetCommands::Apps => {
+            ret.push(config.apps_dir());
+        }
+        ResetCommands::AppsData => {
+            ret.push(config.apps_data_dir());
+            ret.extend(vscode_data_dirs(config));
+        }
+        ResetCommands::All => {
+            ret.push(config.apps_dir());
+            ret.push(config.apps_data_dir());
+            ret.extend(vscode_data_dirs(config));
+            ret.extend(cache::file_paths(config));
+            ret.push(config.credentials_file_path());
+        }
+    }
+
+    ret
+}
+
 pub async fn clean_apps(apps_dir: &Path) -> Result<(), anyhow::Error> {
     if apps_dir.exists() {
         let _ = tokio::fs::remove_dir_all(apps_dir).await?;
@@ -41,3 +127,117 @@ pub async fn clean_apps_data(apps_data_dir: &Path) -> Result<(), anyhow::Error>
     tracing::info!(?apps_data_dir, "Apps data cleared");
     Ok(())
 }
+
+pub async fn remove_server_credential(
+    config: &Config,
+    server_url: &Url,
+) -> Result<(), anyhow::Error> {
+    if !CredManager::remove(config, server_url.as_str()).await? {
+        println!("No saved credential for {server_url}");
+    }
+
+    // Best effort, portalbox may not be running
+    if let Err(e) = signout_running(config, server_url).await {
+        tracing::debug!(?e, "No running portalbox signed out");
+    }
+
+    tracing::info!(%server_url, "Credential cleared");
+    Ok(())
+}
+
+// Stops the proxies a running portalbox keeps serving with the removed credential
+async fn signout_running(config: &Config, server_url: &Url) -> Result<(), anyhow::Error> {
+    let url = format!("{}/api/signout", browser::dashboard_url(config));
+    let http_client = reqwest::Client::builder()
+        .no_proxy()
+        // Plain HTTP, no need to load the system trust store
+        .tls_built_in_root_certs(false)
+        .timeout(SIGNOUT_TIMEOUT)
+        .build()?;
+
+    // Missing when portalbox never started, nothing to sign out then
+    let local_secret = tokio::fs::read_to_string(config.local_secret_file_path()).await?;
+
+    http_client
+        .post(url)
+        .header(crate::api::LOCAL_SECRET_HEADER, local_secret.trim())
+        .json(&serde_json::json!({ "server_url": server_url }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+fn vscode_data_dirs(config: &Config) -> Vec<PathBuf> {
+    match &config.vscode_data_root {
+        Some(vscode_data_root) => models::VSCODE_DATA_SUBDIRS
+            .iter()
+            .map(|val| vscode_data_root.join(val))
+            .collect(),
+        None => vec![],
+    }
+}
+
+// The root may be shared with other things, so only vscode's own subdirs are removed
+async fn clean_vscode_data_root(config: &Config) -> Result<(), anyhow::Error> {
+    let vscode_data_root = match &config.vscode_data_root {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    for dir in vscode_data_dirs(config) {
+        if dir.exists() {
+            let _ = tokio::fs::remove_dir_all(dir).await?;
+        }
+    }
+    tracing::info!(?vscode_data_root, "vscode data cleared");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            vscode_data_root: Some(home_dir.join("vscode-data")),
+            ..Default::default()
+        };
+
+        std::fs::create_dir_all(config.apps_dir().join("portalbox-vscode-1.2.3-linux-x64"))
+            .unwrap();
+        std::fs::create_dir_all(config.apps_data_dir()).unwrap();
+        std::fs::create_dir_all(
+            home_dir
+                .join("vscode-data")
+                .join(models::VSCODE_DATA_SUBDIRS[2]),
+        )
+        .unwrap();
+        std::fs::write(config.credentials_file_path(), "").unwrap();
+        std::fs::write(config.apps_cache_file_path(), "").unwrap();
+
+        let paths = deleted_paths(&ResetCommands::All, &config);
+        let existing: Vec<_> = paths.iter().filter(|val| val.exists()).collect();
+        assert_eq!(existing.len(), 5);
+
+        let dry_run = Reset {
+            command: ResetCommands::All,
+            dry_run: true,
+        };
+        reset(dry_run, config.clone()).await.unwrap();
+        assert!(existing.iter().all(|val| val.exists()));
+
+        // The listed paths are the ones an actual reset deletes
+        let reset_all = Reset {
+            command: ResetCommands::All,
+            dry_run: false,
+        };
+        reset(reset_all, config).await.unwrap();
+        assert!(paths.iter().all(|val| !val.exists()));
+    }
+}
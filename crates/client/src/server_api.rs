@@ -0,0 +1,395 @@
+use std::{sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use models::{AppsResult, Motd, SignIn, SignInResult, SigninGuestResult};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use semver::Version;
+use url::Url;
+
+use crate::{
+    config::Config,
+    failover::{Failover, ServerChoice},
+    telemetry::TraceContextExt,
+};
+
+const FETCH_APPS_TIMEOUT: Duration = Duration::from_secs(5);
+const FETCH_SERVER_NEWS_TIMEOUT: Duration = Duration::from_secs(3);
+const FETCH_MOTD_TIMEOUT: Duration = Duration::from_secs(3);
+
+// The PortalBox server endpoints, behind a trait so tests can swap in a mock
+#[async_trait]
+pub trait ServerApi: Send + Sync {
+    async fn apps(&self, os_arch: String) -> Result<AppsResult, anyhow::Error>;
+
+    async fn client_version(&self, current_version: Version) -> Result<Version, anyhow::Error>;
+
+    async fn server_news(&self) -> Result<String, anyhow::Error>;
+
+    // `None` when there's nothing to show, including servers without the endpoint
+    async fn motd(&self) -> Result<Option<Motd>, anyhow::Error>;
+
+    // Kept as `reqwest::Error` so callers can tell timeouts and connection errors apart
+    async fn signin(&self, form: &SignIn) -> Result<SignInResult, reqwest::Error>;
+
+    async fn signin_guest(&self) -> Result<SigninGuestResult, reqwest::Error>;
+}
+
+pub struct HttpServerApi {
+    config: Config,
+    fallback: Option<Config>,
+    // Shared by all requests, like the proxy connections they switch servers after
+    // `failover_retries` unreachable attempts in a row
+    failover: Mutex<Failover>,
+    http_client: reqwest::Client,
+}
+
+impl HttpServerApi {
+    pub fn new(config: Config, http_client: reqwest::Client) -> Self {
+        Self {
+            fallback: config.fallback_config(),
+            failover: Mutex::new(Failover::new(config.failover_retries)),
+            config,
+            http_client,
+        }
+    }
+
+    fn server_config(&self, choice: ServerChoice) -> &Config {
+        match (choice, &self.fallback) {
+            (ServerChoice::Fallback, Some(val)) => val,
+            _ => &self.config,
+        }
+    }
+
+    // Only a server that can't be reached at all counts as a failure, not one answering with an
+    // error. The request that uses up the retries is tried once more on the other server.
+    async fn send_with_fallback(
+        &self,
+        path: &str,
+        request: impl Fn(&reqwest::Client, Url) -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut switched = false;
+
+        loop {
+            let choice = self.failover.lock().unwrap().current();
+            let ret = request(
+                &self.http_client,
+                self.server_config(choice).server_url_with_path(path),
+            )
+            .with_trace_context()
+            .send()
+            .await;
+
+            let mut failover = self.failover.lock().unwrap();
+            match ret {
+                Err(e) if e.is_connect() || e.is_timeout() => {
+                    match failover.record_failure(self.fallback.is_some()) {
+                        Some(choice) if !switched => {
+                            tracing::warn!(?e, ?choice, "Server unreachable, failing over");
+                            switched = true;
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                ret => {
+                    if ret.is_ok() {
+                        failover.record_success();
+                    }
+                    return ret;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ServerApi for HttpServerApi {
+    async fn apps(&self, os_arch: String) -> Result<AppsResult, anyhow::Error> {
+        tracing::debug!(?os_arch, "Getting apps");
+
+        let apps_request = models::AppsRequest { os_arch };
+
+        let ret = self
+            .send_with_fallback("api/apps", |http_client, url| {
+                http_client
+                    .get(url)
+                    .timeout(FETCH_APPS_TIMEOUT)
+                    .json(&apps_request)
+            })
+            .await?
+            .json::<AppsResult>()
+            .await?;
+
+        Ok(ret)
+    }
+
+    async fn client_version(&self, current_version: Version) -> Result<Version, anyhow::Error> {
+        let request_form = models::ClientVersionRequest { current_version };
+
+        let response = self
+            .send_with_fallback("api/client-version", |http_client, url| {
+                http_client.get(url).json(&request_form)
+            })
+            .await?
+            .json::<models::ClientVersionResponse>()
+            .await?;
+
+        Ok(response.latest_version)
+    }
+
+    async fn server_news(&self) -> Result<String, anyhow::Error> {
+        let resp = self
+            .send_with_fallback("api/server_news", |http_client, url| {
+                http_client.get(url).timeout(FETCH_SERVER_NEWS_TIMEOUT)
+            })
+            .await?;
+
+        let resp = resp.error_for_status()?;
+        let ret = resp.text().await?;
+
+        Ok(ret)
+    }
+
+    async fn motd(&self) -> Result<Option<Motd>, anyhow::Error> {
+        let resp = self
+            .send_with_fallback("api/motd", |http_client, url| {
+                http_client.get(url).timeout(FETCH_MOTD_TIMEOUT)
+            })
+            .await?;
+
+        if matches!(
+            resp.status(),
+            StatusCode::NOT_FOUND | StatusCode::NO_CONTENT
+        ) {
+            return Ok(None);
+        }
+
+        let ret = resp.error_for_status()?.json::<Option<Motd>>().await?;
+
+        Ok(ret)
+    }
+
+    // Never falls back, the session is saved for the primary server
+    async fn signin(&self, form: &SignIn) -> Result<SignInResult, reqwest::Error> {
+        let url = self.config.server_url_with_path("api/signin");
+
+        self.http_client
+            .post(url)
+            .json(form)
+            .with_trace_context()
+            .send()
+            .await?
+            .json::<SignInResult>()
+            .await
+    }
+
+    // Never falls back, like `signin`
+    async fn signin_guest(&self) -> Result<SigninGuestResult, reqwest::Error> {
+        let url = self.config.server_url_with_path("api/signin-guest");
+
+        self.http_client
+            .post(url)
+            .with_trace_context()
+            .send()
+            .await?
+            .json::<SigninGuestResult>()
+            .await
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use models::AppInfo;
+    use secrecy::SecretString;
+
+    use super::*;
+
+    // Answers with fixed versions and counts the calls
+    pub struct MockServerApi {
+        pub vscode_version: Version,
+        pub client_version: Version,
+        pub calls: AtomicUsize,
+        pub server_news_fails: AtomicBool,
+        pub motd: Mutex<Option<Motd>>,
+    }
+
+    impl MockServerApi {
+        pub fn new(vscode_version: &str, client_version: &str) -> Self {
+            Self {
+                vscode_version: Version::parse(vscode_version).unwrap(),
+                client_version: Version::parse(client_version).unwrap(),
+                calls: AtomicUsize::new(0),
+                server_news_fails: AtomicBool::new(false),
+                motd: Mutex::new(None),
+            }
+        }
+
+        pub fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl ServerApi for MockServerApi {
+        async fn apps(&self, os_arch: String) -> Result<AppsResult, anyhow::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(AppsResult {
+                vscode: AppInfo {
+                    os_arch,
+                    latest_version: self.vscode_version.clone(),
+                    download_link: "http://127.0.0.1:1/vscode.tar.gz".into(),
+                },
+            })
+        }
+
+        async fn client_version(
+            &self,
+            _current_version: Version,
+        ) -> Result<Version, anyhow::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.client_version.clone())
+        }
+
+        async fn server_news(&self) -> Result<String, anyhow::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.server_news_fails.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("Server news unavailable"));
+            }
+            Ok("News".into())
+        }
+
+        async fn motd(&self) -> Result<Option<Motd>, anyhow::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.motd.lock().unwrap().clone())
+        }
+
+        async fn signin(&self, form: &SignIn) -> Result<SignInResult, reqwest::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(SignInResult {
+                client_access_token: SecretString::new("token".into()),
+                base_sub_domain: form.base_sub_domain.clone().unwrap_or_default(),
+            })
+        }
+
+        async fn signin_guest(&self) -> Result<SigninGuestResult, reqwest::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(SigninGuestResult {
+                client_access_token: SecretString::new("guest-token".into()),
+                base_sub_domain: "guest".into(),
+                access_code: SecretString::new("123456".into()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    // Answers every request with `body`
+    async fn start_server(body: &'static str) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback() {
+        // Nothing listens on it
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+        };
+        let config = Config {
+            server_url: unreachable,
+            fallback_server_url: Some(start_server("fallback news").await),
+            failover_retries: 2,
+            ..Default::default()
+        };
+        let server_api = HttpServerApi::new(config, reqwest::Client::new());
+
+        // Within the retries the error is returned
+        assert!(server_api.server_news().await.is_err());
+
+        // The request using them up is tried on the fallback, which is kept using
+        assert_eq!(server_api.server_news().await.unwrap(), "fallback news");
+        assert_eq!(server_api.server_news().await.unwrap(), "fallback news");
+        assert_eq!(
+            server_api.failover.lock().unwrap().current(),
+            ServerChoice::Fallback
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signin_reuses_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let (accepted_sender, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = accepted_sender.send(());
+                tokio::spawn(async move {
+                    let body = r#"{"client_access_token":"token","base_sub_domain":"xyz"}"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let mut request = vec![];
+                    let mut buf = [0u8; 1024];
+                    // Keeps the connection open, answering each request once its body is in
+                    while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                        request.extend_from_slice(&buf[..len]);
+                        if request.ends_with(b"}") {
+                            request.clear();
+                            let _ = stream.write_all(response.as_bytes()).await;
+                        }
+                    }
+                });
+            }
+        });
+
+        let config = Config {
+            server_url: url,
+            ..Default::default()
+        };
+        let server_api =
+            HttpServerApi::new(config.clone(), crate::utils::http_client(&config).unwrap());
+        let form = SignIn {
+            email: "x@y.z".into(),
+            password: secrecy::SecretString::new("password".into()),
+            remember_me: false,
+            base_sub_domain: None,
+        };
+
+        for _ in 0..3 {
+            let ret = server_api.signin(&form).await.unwrap();
+            assert_eq!(ret.base_sub_domain, "xyz");
+        }
+
+        // One connection for all of them, later ones skip the connect
+        accepted.recv().await.unwrap();
+        assert!(accepted.try_recv().is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    io::{BufRead, IsTerminal},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use models::{SignIn, SignInResult};
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::{
+    config::Config,
+    credentials::{CredManager, Credential, GuestCredential, UserCredential},
+    server_api::{HttpServerApi, ServerApi},
+    utils,
+};
+
+const SIGNIN_MAX_RETRIES: usize = 3;
+const PASSWORD_ENV_VAR: &str = "PORTALBOX_PASSWORD";
+
+// Signs in without the dashboard, e.g. when provisioning a box, always remembering the credential
+pub async fn signin(config: &Config, email: String) -> anyhow::Result<()> {
+    if config.offline {
+        return Err(anyhow::anyhow!("Can't sign in while offline"));
+    }
+
+    let form = SignIn {
+        email,
+        password: read_password()?,
+        remember_me: true,
+        base_sub_domain: None,
+    };
+    let server_api = HttpServerApi::new(config.clone(), utils::http_client(config)?);
+    signin_and_save(&server_api, config, &form).await?;
+
+    println!("Signed in to {} as {}", config.server_url(), form.email);
+    Ok(())
+}
+
+// A throwaway guest box, e.g. for a demo, printing what's needed to reach it
+pub async fn signin_guest(config: &Config) -> anyhow::Result<()> {
+    if config.offline {
+        return Err(anyhow::anyhow!("Can't sign in while offline"));
+    }
+
+    let server_api = HttpServerApi::new(config.clone(), utils::http_client(config)?);
+    let credential = signin_guest_and_save(&server_api, config).await?;
+
+    if let Credential::Guest(val) = &credential {
+        println!("access_code = {}", val.access_code.expose_secret());
+    }
+    println!("home_url    = {}", credential.home_url());
+    if let Some(expires_at) = credential.expires_at() {
+        println!("expires_at  = {expires_at}");
+    }
+    Ok(())
+}
+
+async fn signin_and_save(
+    server_api: &dyn ServerApi,
+    config: &Config,
+    form: &SignIn,
+) -> anyhow::Result<Credential> {
+    let credential = user_credential(server_api, config, form)
+        .await
+        .with_context(|| format!("Can't sign in to {}", config.server_url()))?;
+    save_credential(config, credential.clone()).await?;
+
+    Ok(credential)
+}
+
+async fn signin_guest_and_save(
+    server_api: &dyn ServerApi,
+    config: &Config,
+) -> anyhow::Result<Credential> {
+    let credential = guest_credential(server_api, config)
+        .await
+        .with_context(|| format!("Can't sign in as guest to {}", config.server_url()))?;
+    save_credential(config, credential.clone()).await?;
+
+    Ok(credential)
+}
+
+pub async fn user_credential(
+    server_api: &dyn ServerApi,
+    config: &Config,
+    form: &SignIn,
+) -> Result<Credential, reqwest::Error> {
+    // The shared HTTP client keeps the connection and the trust store, so only the first sign in
+    // pays for them
+    let started = Instant::now();
+    let res = post_signin(server_api, form).await?;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    tracing::debug!(?res, elapsed_ms, "logged in");
+
+    let cred = UserCredential::new(
+        form.email.clone(),
+        res.client_access_token,
+        res.base_sub_domain,
+        config.server_url().into(),
+    );
+    Ok(Credential::new_user(cred))
+}
+
+// Expires after `guest_session_secs`, if set
+pub async fn guest_credential(
+    server_api: &dyn ServerApi,
+    config: &Config,
+) -> Result<Credential, reqwest::Error> {
+    let res = server_api.signin_guest().await?;
+
+    let expires_at = config
+        .guest_session_secs
+        .map(|val| chrono::Utc::now() + chrono::Duration::seconds(val as i64));
+
+    let cred = GuestCredential::new(
+        res.base_sub_domain,
+        res.client_access_token,
+        res.access_code,
+        expires_at,
+        config.server_url().into(),
+    );
+    Ok(Credential::new_guest(cred))
+}
+
+pub async fn save_credential(config: &Config, credential: Credential) -> anyhow::Result<()> {
+    CredManager::insert(config, config.server_url().as_str(), credential).await
+}
+
+// Retries connection errors and timeouts, anything else is returned as is
+async fn post_signin(
+    server_api: &dyn ServerApi,
+    form: &SignIn,
+) -> Result<SignInResult, reqwest::Error> {
+    let mut backoff = ExponentialBackoff {
+        initial_interval: Duration::from_millis(500),
+        max_interval: Duration::from_secs(2),
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+    let mut retries = 0;
+
+    loop {
+        let ret = server_api.signin(form).await;
+
+        match ret {
+            Err(e) if (e.is_connect() || e.is_timeout()) && retries < SIGNIN_MAX_RETRIES => {
+                retries += 1;
+                tracing::warn!(?e, retries, "Signin request failed, trying again");
+                if let Some(b) = backoff.next_backoff() {
+                    tokio::time::sleep(b).await;
+                }
+            }
+            ret => return ret,
+        }
+    }
+}
+
+// From the env var, a hidden prompt, or the first line of piped stdin, never echoed
+fn read_password() -> anyhow::Result<SecretString> {
+    if let Ok(val) = std::env::var(PASSWORD_ENV_VAR) {
+        return Ok(SecretString::new(val));
+    }
+
+    if std::io::stdin().is_terminal() {
+        let term = console::Term::stderr();
+        term.write_str("Password: ")?;
+        let ret = term.read_secure_line()?;
+        return Ok(SecretString::new(ret));
+    }
+
+    password_from_reader(std::io::stdin().lock())
+}
+
+fn password_from_reader(mut reader: impl BufRead) -> anyhow::Result<SecretString> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("Can't read the password from stdin")?;
+
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No password on stdin, pipe it in or set {PASSWORD_ENV_VAR}"
+        ));
+    }
+
+    Ok(SecretString::new(password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_api::mock::MockServerApi;
+
+    #[tokio::test]
+    async fn test_headless_signin() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            ..Default::default()
+        };
+
+        let password = password_from_reader("hunter2\n".as_bytes()).unwrap();
+        assert_eq!(password.expose_secret(), "hunter2");
+        assert!(password_from_reader("".as_bytes()).is_err());
+
+        let server_api = MockServerApi::new("1.66.0", "0.1.0");
+        let form = SignIn {
+            email: "dev@example.com".into(),
+            password,
+            remember_me: true,
+            base_sub_domain: Some("dev".into()),
+        };
+        signin_and_save(&server_api, &config, &form).await.unwrap();
+        assert_eq!(server_api.calls(), 1);
+
+        let cred_manager = CredManager::load(&config).await.unwrap();
+        let credential = &cred_manager.credentials[config.server_url().as_str()];
+        assert_eq!(credential.base_sub_domain(), "dev");
+        assert!(matches!(credential, Credential::User(val) if val.email == "dev@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_headless_signin_guest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home_dir = temp_dir.path().to_path_buf();
+        let config = Config {
+            home_dir: home_dir.clone(),
+            guest_session_secs: Some(3600),
+            ..Default::default()
+        };
+
+        let server_api = MockServerApi::new("1.66.0", "0.1.0");
+        let credential = signin_guest_and_save(&server_api, &config).await.unwrap();
+        assert_eq!(server_api.calls(), 1);
+        assert_eq!(credential.home_url(), "https://guest-home.portalbox.app");
+        assert!(credential.expires_at().is_some());
+
+        let cred_manager = CredManager::load(&config).await.unwrap();
+        match &cred_manager.credentials[config.server_url().as_str()] {
+            Credential::Guest(val) => assert_eq!(val.access_code.expose_secret(), "123456"),
+            Credential::User(_) => panic!("expected a guest credential"),
+        }
+    }
+}
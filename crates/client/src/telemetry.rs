@@ -1,12 +1,21 @@
 use std::collections::HashMap;
 use std::env;
+use std::time::Duration;
 
+use opentelemetry::{
+    propagation::{Injector, TextMapPropagator},
+    sdk::propagation::TraceContextPropagator,
+};
 use opentelemetry_otlp::WithExportConfig;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 use crate::config::Config;
 
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub fn init_subscriber(config: &Config) {
     let local_layer = {
         let default = format!("{}=info", env!("CARGO_CRATE_NAME"));
@@ -14,13 +23,20 @@ pub fn init_subscriber(config: &Config) {
             .parse()
             .expect("hard-coded default directive should be valid");
 
-        let local_filter = EnvFilter::builder()
+        let mut local_filter = EnvFilter::builder()
             .with_default_directive(default)
             .parse_lossy(&config.log);
+        if config.trace_protocol {
+            let directive = "models::proxy_connection=debug"
+                .parse()
+                .expect("hard-coded directive should be valid");
+            local_filter = local_filter.add_directive(directive);
+        }
         tracing_subscriber::fmt::layer().with_filter(local_filter)
     };
 
-    let telemetry = {
+    // The exporter isn't even set up without the user's consent
+    let telemetry = config.telemetry_enabled().then(|| {
         let trace_config = {
             let default_resource = opentelemetry::sdk::Resource::default();
             let custom_resource =
@@ -48,20 +64,112 @@ pub fn init_subscriber(config: &Config) {
             .install_batch(opentelemetry::runtime::Tokio)
             .unwrap();
 
-        let filter = if config.telemetry {
-            tracing_subscriber::filter::Targets::new()
-                .with_target(env!("CARGO_CRATE_NAME"), tracing::Level::INFO)
-        } else {
-            tracing_subscriber::filter::Targets::new()
-        };
+        // Dashboard request spans are debug level, exported so the server calls they make link
+        // back to them. Static files and status polls are trace level and left out.
+        let filter = tracing_subscriber::filter::Targets::new()
+            .with_target(env!("CARGO_CRATE_NAME"), tracing::Level::INFO)
+            .with_target(
+                concat!(env!("CARGO_CRATE_NAME"), "::request_id"),
+                tracing::Level::DEBUG,
+            );
 
         tracing_opentelemetry::layer()
             .with_tracer(tracer)
             .with_filter(filter)
-    };
+    });
 
     tracing_subscriber::registry()
         .with(telemetry)
         .with(local_layer)
         .init();
 }
+
+// Sends the buffered spans, or drops them if `flush` is false
+pub async fn shutdown(flush: bool) {
+    if flush {
+        // Blocks until the batch exporter is done. On a detached thread, unlike `spawn_blocking`
+        // the runtime doesn't wait for it on exit once the timeout is up.
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            opentelemetry::global::shutdown_tracer_provider();
+            let _ = sender.send(());
+        });
+        if tokio::time::timeout(FLUSH_TIMEOUT, receiver).await.is_err() {
+            tracing::warn!("Sending telemetry didn't finish in time, dropping it");
+        }
+    } else {
+        // Dropping the provider would flush it, leak it instead
+        let provider = opentelemetry::global::set_tracer_provider(
+            opentelemetry::trace::noop::NoopTracerProvider::new(),
+        );
+        std::mem::forget(provider);
+        tracing::debug!("Dropped buffered telemetry");
+    }
+}
+
+// Continues the current trace on the server with a W3C `traceparent` header. Nothing is added
+// outside a span exported to telemetry, e.g. when telemetry is off
+pub trait TraceContextExt {
+    fn with_trace_context(self) -> Self;
+}
+
+impl TraceContextExt for reqwest::RequestBuilder {
+    fn with_trace_context(self) -> Self {
+        let context = tracing::Span::current().context();
+        let mut headers = HeaderMap::new();
+        TraceContextPropagator::new().inject_context(&context, &mut HeaderInjector(&mut headers));
+        self.headers(headers)
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider;
+
+    #[test]
+    fn test_trace_context_header() {
+        // Tracers only hold a weak reference to their provider
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let request = || {
+            reqwest::Client::new()
+                .get("http://localhost/api/apps")
+                .with_trace_context()
+                .build()
+                .unwrap()
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(request().headers().get("traceparent").is_none());
+
+            let span = tracing::info_span!("dashboard_action");
+            let _guard = span.enter();
+            let request = request();
+            let traceparent = request.headers()["traceparent"].to_str().unwrap();
+            // version-trace_id-span_id-flags
+            let parts: Vec<_> = traceparent.split('-').collect();
+            assert_eq!(parts.len(), 4);
+            assert_eq!(parts[0], "00");
+            assert_eq!(parts[1].len(), 32);
+        });
+
+        // Without the telemetry layer
+        assert!(request().headers().get("traceparent").is_none());
+    }
+}
@@ -1,21 +1,29 @@
-use tokio::net::TcpStream;
-
-use crate::utils::get_tls_connector;
+use crate::{
+    config::Config,
+    outbound_proxy,
+    relay::relay,
+    utils::{get_tls_connector, set_tcp_keepalive},
+};
 
 const SSH_TLS_PORT: u16 = 22857;
 
-pub async fn connect(host: &str) -> anyhow::Result<()> {
-    let tls_connector = get_tls_connector()?;
+pub async fn connect(config: &Config, host: &str) -> anyhow::Result<()> {
+    let tls_connector = get_tls_connector(config)?;
 
     let host_port = format!("{host}-ssh.portalbox.app:{SSH_TLS_PORT}");
 
-    let mut socket_addrs = tokio::net::lookup_host(host_port).await?;
-    let first = socket_addrs
-        .next()
-        .ok_or(anyhow::anyhow!("Failed to resolve ip"))?;
+    if let Some(ip) = config.outbound_bind_ip {
+        outbound_proxy::ensure_local_ip(ip)?;
+    }
 
-    let tcp_stream = TcpStream::connect(&first).await?;
+    let tcp_stream = outbound_proxy::connect(
+        config.outbound_proxy.as_ref(),
+        config.outbound_bind_ip,
+        &host_port,
+    )
+    .await?;
     let _ = tcp_stream.set_nodelay(true);
+    set_tcp_keepalive(&tcp_stream, &config.ssh_tunnel_keepalive());
 
     let domain = format!("{host}-ssh.portalbox.app");
 
@@ -23,20 +31,18 @@ pub async fn connect(host: &str) -> anyhow::Result<()> {
         .connect(domain.as_str().try_into()?, tcp_stream)
         .await?;
 
-    let (mut read, mut write) = tokio::io::split(tls_stream);
-
-    let mut std_in = tokio::io::stdin();
-    let mut std_out = tokio::io::stdout();
-
-    let in_to_write = tokio::io::copy(&mut std_in, &mut write);
-    let read_to_out = tokio::io::copy(&mut read, &mut std_out);
-
-    tokio::select! {
-        _ = in_to_write => {
-            Ok(())
-        },
-        _ = read_to_out => {
-            Ok(())
-        }
+    // ssh ends the session when either direction does
+    let relayed = relay(
+        (tokio::io::stdin(), tokio::io::stdout()),
+        tokio::io::split(tls_stream),
+        false,
+    )
+    .await;
+    if relayed.reason.error.is_some() {
+        tracing::warn!(reason = %relayed.reason, "Tunnel failed");
+    } else {
+        tracing::debug!(reason = %relayed.reason, "Tunnel closed");
     }
+
+    Ok(())
 }
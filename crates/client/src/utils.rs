@@ -1,21 +1,595 @@
-use std::sync::Arc;
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use tokio_rustls::TlsConnector;
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use tokio::io::AsyncWriteExt;
+use tokio_rustls::{rustls::RootCertStore, TlsConnector};
 
-pub fn get_tls_connector() -> anyhow::Result<TlsConnector> {
-    let mut root_cert_store = tokio_rustls::rustls::RootCertStore::empty();
+use crate::config::Config;
+
+// The vscode download link redirects to a CDN, more than this is a loop or a misconfiguration
+const MAX_REDIRECTS: usize = 5;
+
+pub fn default_user_agent() -> String {
+    let version = crate::version::VERSION;
+    let os_arch = models::utils::get_os_arch();
+
+    format!("portalbox/{version} ({os_arch})")
+}
+
+pub fn http_client_builder(config: &Config) -> Result<reqwest::ClientBuilder, anyhow::Error> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(config.user_agent())
+        .timeout(config.http_timeout())
+        .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS));
+
+    if let Some(outbound_proxy) = &config.outbound_proxy {
+        builder = builder.proxy(reqwest::Proxy::all(outbound_proxy.as_str())?);
+    }
+
+    if let Some(ip) = config.outbound_bind_ip {
+        builder = builder.local_address(ip);
+    }
+
+    // The same roots as the proxy connections, the webpki fallback and configured CAs included
+    builder = builder.use_preconfigured_tls(tls_client_config(root_cert_store(config)?));
+
+    Ok(builder)
+}
+
+pub fn http_client(config: &Config) -> Result<reqwest::Client, anyhow::Error> {
+    let ret = http_client_builder(config)?.build()?;
+    Ok(ret)
+}
+
+// Writes to a temp file next to `path` then renames it over, so a crash never leaves a partial file
+pub async fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(
+    path: P,
+    contents: C,
+) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let temp_path = atomic_temp_path(path)?;
+
+    let ret = async {
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        file.write_all(contents.as_ref()).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if let Err(e) = ret {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
+
+    // Persist the rename itself
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+fn atomic_temp_path(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("Invalid file path {}", path.display()))?;
+
+    // Unique, so concurrent writers, even from other processes, never share a temp file
+    let temp_file_name = format!(
+        ".{}.{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        uuid::Uuid::new_v4().simple()
+    );
+    Ok(path.with_file_name(temp_file_name))
+}
+
+// Coarse, for display only, e.g. "3 days ago"
+pub fn time_ago(at: chrono::DateTime<chrono::Utc>) -> String {
+    let elapsed = chrono::Utc::now() - at;
+
+    let (value, unit) = if elapsed.num_days() > 0 {
+        (elapsed.num_days(), "day")
+    } else if elapsed.num_hours() > 0 {
+        (elapsed.num_hours(), "hour")
+    } else if elapsed.num_minutes() > 0 {
+        (elapsed.num_minutes(), "minute")
+    } else {
+        return "just now".into();
+    };
+
+    let plural = if value == 1 { "" } else { "s" };
+    format!("{value} {unit}{plural} ago")
+}
+
+// `port` if nothing listens on it yet, otherwise a free one picked by the OS if `fallback` is set
+pub fn free_port(port: u16, fallback: bool) -> std::io::Result<u16> {
+    match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)) {
+        Ok(_) => Ok(port),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && fallback => {
+            let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            Ok(listener.local_addr()?.port())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn set_tcp_keepalive(stream: &tokio::net::TcpStream, keepalive: &socket2::TcpKeepalive) {
+    let sock_ref = socket2::SockRef::from(stream);
+    if let Err(e) = sock_ref.set_tcp_keepalive(keepalive) {
+        tracing::warn!("Failed to set TCP keepalive: {e}");
+    }
+}
+
+// Loading the system trust store is slow on machines with many certs, it's only done once,
+// along with the webpki fallback so a missing system store isn't retried on every connection
+static ROOT_CERT_STORE: OnceCell<RootCertStore> = OnceCell::new();
+
+// A freshly provisioned machine can still be populating its trust store when portalbox starts
+const NATIVE_CERTS_ATTEMPTS: u32 = 3;
+const NATIVE_CERTS_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootCertSource {
+    System,
+    WebpkiRoots,
+}
+
+// Loads the root certs on a blocking thread, waiting for the system trust store to be populated.
+// Connections made before this, or after it failed, try the system store once.
+pub async fn preload_root_certs(config: &Config) -> anyhow::Result<()> {
+    let webpki_roots_fallback = config.use_webpki_roots_fallback;
+    tokio::task::spawn_blocking(move || {
+        ROOT_CERT_STORE
+            .get_or_try_init(|| init_root_cert_store(NATIVE_CERTS_ATTEMPTS, webpki_roots_fallback))
+            .map(|_| ())
+    })
+    .await?
+}
+
+fn init_root_cert_store(
+    attempts: u32,
+    webpki_roots_fallback: bool,
+) -> anyhow::Result<RootCertStore> {
+    let (ret, source) = load_root_cert_store(
+        native_root_cert_store,
+        attempts,
+        NATIVE_CERTS_RETRY_DELAY,
+        webpki_roots_fallback,
+    )?;
+    tracing::info!(?source, certs = ret.len(), "Loaded root certs");
+    Ok(ret)
+}
+
+// The shared root certs, with the configured CAs added to a copy
+fn root_cert_store(config: &Config) -> anyhow::Result<RootCertStore> {
+    let mut ret = ROOT_CERT_STORE
+        .get_or_try_init(|| init_root_cert_store(1, config.use_webpki_roots_fallback))?
+        .clone();
+    add_ca_certs(&mut ret, &config.server_ca_cert_path)?;
+    Ok(ret)
+}
+
+pub fn get_tls_connector(config: &Config) -> anyhow::Result<TlsConnector> {
+    Ok(tls_connector_with_roots(root_cert_store(config)?))
+}
+
+// Unlike the system trust store, these were configured on purpose, so a bad file is an error
+fn add_ca_certs(root_cert_store: &mut RootCertStore, paths: &[PathBuf]) -> anyhow::Result<()> {
+    for path in paths {
+        for cert in read_ca_certs(path)? {
+            root_cert_store
+                .add(&tokio_rustls::rustls::Certificate(cert))
+                .with_context(|| format!("Invalid CA cert in {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// The DER of each cert in a PEM file
+fn read_ca_certs(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let content = std::fs::read(path)
+        .with_context(|| format!("Can't read CA cert file {}", path.display()))?;
+    let ret = rustls_pemfile::certs(&mut content.as_slice())
+        .with_context(|| format!("Invalid PEM in CA cert file {}", path.display()))?;
+
+    if ret.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificate in CA cert file {}",
+            path.display()
+        ));
+    }
+
+    Ok(ret)
+}
+
+// Failures aren't cached, without the fallback the next connection tries the system store again
+fn load_root_cert_store(
+    load_native: impl Fn() -> anyhow::Result<RootCertStore>,
+    attempts: u32,
+    retry_delay: Duration,
+    webpki_roots_fallback: bool,
+) -> anyhow::Result<(RootCertStore, RootCertSource)> {
+    let mut attempt = 1;
+    let e = loop {
+        match load_native() {
+            Ok(val) => return Ok((val, RootCertSource::System)),
+            Err(e) if attempt >= attempts => break e,
+            Err(e) => {
+                tracing::warn!(
+                    attempt,
+                    "Can't load the system trust store, retrying: {e:#}"
+                );
+            }
+        }
+        // Only retried on the blocking thread of `preload_root_certs`
+        std::thread::sleep(retry_delay);
+        attempt += 1;
+    };
+
+    if !webpki_roots_fallback {
+        return Err(e);
+    }
+
+    tracing::warn!("Can't load the system trust store, using the bundled webpki roots: {e:#}");
+    Ok((webpki_root_cert_store(), RootCertSource::WebpkiRoots))
+}
+
+fn webpki_root_cert_store() -> RootCertStore {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|val| {
+        tokio_rustls::rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            val.subject,
+            val.spki,
+            val.name_constraints,
+        )
+    }));
+
+    root_cert_store
+}
+
+pub fn native_root_cert_store() -> anyhow::Result<RootCertStore> {
     let native_certs = rustls_native_certs::load_native_certs()?;
-    for cert in native_certs {
-        root_cert_store
-            .add(&tokio_rustls::rustls::Certificate(cert.0))
-            .unwrap();
+    let native_certs = native_certs.into_iter().map(|val| val.0);
+
+    root_cert_store_from_ders(native_certs)
+}
+
+// A malformed cert in the system trust store shouldn't take the whole client down
+fn root_cert_store_from_ders<I>(certs: I) -> anyhow::Result<RootCertStore>
+where
+    I: IntoIterator<Item = Vec<u8>>,
+{
+    let mut root_cert_store = RootCertStore::empty();
+    let mut skipped = 0;
+
+    for cert in certs {
+        if let Err(e) = root_cert_store.add(&tokio_rustls::rustls::Certificate(cert)) {
+            tracing::warn!(?e, "Skipping invalid system root cert");
+            skipped += 1;
+        }
     }
 
-    let config = tokio_rustls::rustls::ClientConfig::builder()
+    if root_cert_store.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No usable root certs in the system trust store ({skipped} invalid), please check the CA bundle"
+        ));
+    }
+
+    Ok(root_cert_store)
+}
+
+// Only trusts `root_cert_store`, e.g. a self signed cert in tests
+fn tls_client_config(root_cert_store: RootCertStore) -> tokio_rustls::rustls::ClientConfig {
+    tokio_rustls::rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    let connector = TlsConnector::from(Arc::new(config));
+        .with_no_client_auth()
+}
+
+pub fn tls_connector_with_roots(root_cert_store: RootCertStore) -> TlsConnector {
+    TlsConnector::from(Arc::new(tls_client_config(root_cert_store)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The user agent header of a request sent with `config`'s client
+    async fn sent_user_agent(config: &Config) -> Option<String> {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await;
+            String::from_utf8_lossy(&buf[..len]).to_string()
+        });
+
+        http_client(config).unwrap().get(url).send().await.unwrap();
+        let request = server.await.unwrap();
+        request.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("user-agent")
+                .then(|| value.trim().to_string())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_sent() {
+        let user_agent = sent_user_agent(&Config::default()).await.unwrap();
+        let (name, platform) = user_agent.split_once(' ').unwrap();
+        assert_eq!(name, format!("portalbox/{}", crate::version::VERSION));
+        assert_eq!(platform, format!("({})", models::utils::get_os_arch()));
+
+        let config = Config {
+            user_agent: Some("custom/1.0".into()),
+            ..Default::default()
+        };
+        assert_eq!(sent_user_agent(&config).await.unwrap(), "custom/1.0");
+    }
+
+    #[test]
+    fn test_root_cert_store_skips_invalid() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let valid = cert.serialize_der().unwrap();
+
+        let ret = root_cert_store_from_ders(vec![b"garbage".to_vec(), valid]).unwrap();
+        assert_eq!(ret.len(), 1);
+
+        assert!(root_cert_store_from_ders(vec![b"garbage".to_vec()]).is_err());
+        assert!(root_cert_store_from_ders(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_add_ca_certs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+
+        let mut paths = vec![];
+        for name in ["a", "b"] {
+            let cert =
+                rcgen::generate_simple_self_signed(vec![format!("{name}.internal")]).unwrap();
+            let path = dir.join(format!("{name}.pem"));
+            std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+            paths.push(path);
+        }
+
+        // Single file
+        let mut root_cert_store = RootCertStore::empty();
+        add_ca_certs(&mut root_cert_store, &paths[..1]).unwrap();
+        assert_eq!(root_cert_store.len(), 1);
+
+        let mut root_cert_store = RootCertStore::empty();
+        add_ca_certs(&mut root_cert_store, &paths).unwrap();
+        assert_eq!(root_cert_store.len(), 2);
+
+        let config = Config {
+            server_ca_cert_path: paths.clone(),
+            ..Default::default()
+        };
+        http_client(&config).unwrap();
+
+        // Not skipped, the error names the file
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let missing = dir.join("missing.pem");
+        for path in [empty, missing] {
+            let mut root_cert_store = RootCertStore::empty();
+            let e =
+                add_ca_certs(&mut root_cert_store, &[paths[0].clone(), path.clone()]).unwrap_err();
+            assert!(e.to_string().contains(&path.display().to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_client_trusts_ca_certs() {
+        use tokio::io::AsyncReadExt;
+        use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
 
-    Ok(connector)
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                .await;
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let path = dir.join("localhost.pem");
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+
+        let config = Config {
+            server_ca_cert_path: vec![path],
+            ..Default::default()
+        };
+        let body = http_client(&config)
+            .unwrap()
+            .get(format!("https://localhost:{port}/"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[test]
+    fn test_native_root_cert_store_cached() {
+        get_tls_connector(&Config::default()).unwrap();
+        let native = ROOT_CERT_STORE.get().unwrap().len();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let cert = rcgen::generate_simple_self_signed(vec!["a.internal".into()]).unwrap();
+        let path = dir.join("a.pem");
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+
+        // Configured CAs go into a copy, the next connector doesn't trust them
+        let config = Config {
+            server_ca_cert_path: vec![path],
+            ..Default::default()
+        };
+        get_tls_connector(&config).unwrap();
+        assert_eq!(ROOT_CERT_STORE.get().unwrap().len(), native);
+    }
+
+    #[test]
+    fn test_root_cert_store_fallback() {
+        let attempts = std::cell::Cell::new(0);
+        let empty_store = || {
+            attempts.set(attempts.get() + 1);
+            root_cert_store_from_ders(vec![])
+        };
+
+        let e = load_root_cert_store(empty_store, 3, Duration::ZERO, false).unwrap_err();
+        assert!(e.to_string().contains("No usable root certs"));
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let (ret, source) = load_root_cert_store(empty_store, 3, Duration::ZERO, true).unwrap();
+        assert_eq!(source, RootCertSource::WebpkiRoots);
+        assert!(!ret.is_empty());
+        assert_eq!(attempts.get(), 3);
+
+        // Populated by the second attempt
+        attempts.set(0);
+        let (_, source) = load_root_cert_store(
+            || match attempts.replace(attempts.get() + 1) {
+                0 => root_cert_store_from_ders(vec![]),
+                _ => Ok(webpki_root_cert_store()),
+            },
+            3,
+            Duration::ZERO,
+            true,
+        )
+        .unwrap();
+        assert_eq!(source, RootCertSource::System);
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_set_tcp_keepalive() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let config = Config {
+            tcp_keepalive_idle_secs: 42,
+            ..Default::default()
+        };
+        set_tcp_keepalive(&stream, &config.tcp_keepalive());
+
+        let sock_ref = socket2::SockRef::from(&stream);
+        assert!(sock_ref.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                sock_ref.keepalive_time().unwrap(),
+                std::time::Duration::from_secs(42)
+            );
+            assert_eq!(sock_ref.keepalive_retries().unwrap(), 5);
+        }
+
+        let config = Config {
+            ssh_keepalive_secs: Some(30),
+            ..config
+        };
+        set_tcp_keepalive(&stream, &config.ssh_tunnel_keepalive());
+        #[cfg(target_os = "linux")]
+        {
+            let secs = std::time::Duration::from_secs(30);
+            assert_eq!(sock_ref.keepalive_time().unwrap(), secs);
+            assert_eq!(sock_ref.keepalive_interval().unwrap(), secs);
+        }
+    }
+
+    #[test]
+    fn test_time_ago() {
+        let now = chrono::Utc::now();
+        assert_eq!(time_ago(now), "just now");
+        assert_eq!(time_ago(now - chrono::Duration::minutes(1)), "1 minute ago");
+        assert_eq!(time_ago(now - chrono::Duration::hours(5)), "5 hours ago");
+        assert_eq!(time_ago(now - chrono::Duration::days(3)), "3 days ago");
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic_interrupted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let path = dir.join("credentials.toml");
+
+        write_atomic(&path, "old = true\n").await.unwrap();
+
+        // A crash mid-write only ever leaves a partial temp file behind
+        let temp_path = atomic_temp_path(&path).unwrap();
+        std::fs::write(&temp_path, "new = tr").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old = true\n");
+
+        write_atomic(&path, "new = true\n").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new = true\n");
+        assert_ne!(atomic_temp_path(&path).unwrap(), temp_path);
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic_failed_cleans_up() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        // A file can't be renamed over a non-empty directory
+        let path = dir.join("credentials.toml");
+        std::fs::create_dir_all(path.join("taken")).unwrap();
+
+        assert!(write_atomic(&path, "new = true\n").await.is_err());
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|val| val.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["credentials.toml"]);
+    }
+
+    #[test]
+    fn test_free_port() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let taken = listener.local_addr().unwrap().port();
+
+        let e = free_port(taken, false).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+
+        let port = free_port(taken, true).unwrap();
+        assert_ne!(port, taken);
+        assert_eq!(free_port(port, false).unwrap(), port);
+    }
 }
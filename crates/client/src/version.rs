@@ -1,11 +1,52 @@
-use crate::config::Config;
+use std::{sync::Arc, time::Duration};
+
+use crate::{config::Config, server_api::ServerApi};
+use backoff::ExponentialBackoff;
 use semver::Version;
+use tokio::sync::Mutex;
+
+// Retries of a single check give up after this long
+const CHECK_MAX_ELAPSED: Duration = Duration::from_secs(5 * 60);
 
 pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+static GIT_SHA: &str = env!("VERGEN_GIT_SHA");
+static BUILD_TIMESTAMP: &str = env!("VERGEN_BUILD_TIMESTAMP");
+static RUSTC_VERSION: &str = env!("VERGEN_RUSTC_SEMVER");
+
+// `quiet` prints the bare version, for scripts
+pub fn show(json: bool, quiet: bool) -> Result<(), anyhow::Error> {
+    if json {
+        let build_info = serde_json::json!({
+            "version": VERSION,
+            "git_sha": GIT_SHA,
+            "build_timestamp": BUILD_TIMESTAMP,
+            "rustc_version": RUSTC_VERSION,
+        });
+        println!("{}", serde_json::to_string_pretty(&build_info)?);
+    } else if quiet {
+        println!("{VERSION}");
+    } else {
+        let git_sha = &GIT_SHA[..7];
+        println!(
+            "portalbox {} ({}, built at {}, rustc {})",
+            VERSION, git_sha, BUILD_TIMESTAMP, RUSTC_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+// Returns the latest version if it's newer than this one
+pub async fn check(
+    config: &Config,
+    server_api: &dyn ServerApi,
+) -> Result<Option<Version>, anyhow::Error> {
+    if config.offline {
+        return Ok(None);
+    }
 
-pub async fn check(config: &Config) -> Result<(), anyhow::Error> {
     let current_version = Version::parse(VERSION)?;
-    let latest = get_latest_version(current_version.clone(), config).await?;
+    let latest = server_api.client_version(current_version.clone()).await?;
 
     if latest > current_version {
         tracing::warn!(
@@ -13,31 +54,107 @@ pub async fn check(config: &Config) -> Result<(), anyhow::Error> {
             VERSION,
             latest
         );
-        Ok(())
+        Ok(Some(latest))
     } else {
         tracing::info!("Already running the latest version {}", current_version);
-        Ok(())
+        Ok(None)
+    }
+}
+
+// Checks at start, then every `version_check_interval_secs`, keeping the newer version if any
+pub async fn run(
+    config: Arc<Config>,
+    server_api: Arc<dyn ServerApi>,
+    latest_version: Arc<Mutex<Option<Version>>>,
+) {
+    loop {
+        tracing::debug!("Checking for update...");
+        match check_with_retry(&config, server_api.as_ref()).await {
+            Ok(val) => *latest_version.lock().await = val,
+            Err(e) => tracing::error!(?e, "Error checking for update"),
+        }
+
+        match config.version_check_interval() {
+            Some(interval) => tokio::time::sleep(interval).await,
+            None => return,
+        }
     }
 }
 
-async fn get_latest_version(
-    current_version: Version,
+// Exponential backoff, randomized by `ExponentialBackoff`'s default randomization factor
+async fn check_with_retry(
     config: &Config,
-) -> Result<Version, anyhow::Error> {
-    let url = config.server_url_with_path("api/client-version");
+    server_api: &dyn ServerApi,
+) -> Result<Option<Version>, anyhow::Error> {
+    let backoff = ExponentialBackoff {
+        max_elapsed_time: Some(CHECK_MAX_ELAPSED),
+        ..Default::default()
+    };
+
+    backoff::future::retry(backoff, || async {
+        check(config, server_api).await.map_err(|e| {
+            tracing::warn!(?e, "Version check failed, trying again");
+            backoff::Error::transient(e)
+        })
+    })
+    .await
+}
 
-    let request_form = models::ClientVersionRequest { current_version };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_api::mock::MockServerApi;
 
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .json(&request_form)
-        .send()
-        .await?
-        .json::<models::ClientVersionResponse>()
-        .await?;
+    #[tokio::test]
+    async fn test_check() {
+        let config = Config::default();
 
-    let latest_version = response.latest_version;
+        let server_api = MockServerApi::new("1.0.0", "999.0.0");
+        let ret = check(&config, &server_api).await.unwrap();
+        assert_eq!(ret, Some(Version::parse("999.0.0").unwrap()));
+
+        let server_api = MockServerApi::new("1.0.0", VERSION);
+        assert_eq!(check(&config, &server_api).await.unwrap(), None);
+
+        let config = Config {
+            offline: true,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, &server_api).await.unwrap(), None);
+        assert_eq!(server_api.calls(), 1);
+    }
 
-    Ok(latest_version)
+    #[tokio::test(start_paused = true)]
+    async fn test_run_interval() {
+        let server_api = Arc::new(MockServerApi::new("1.0.0", "999.0.0"));
+        let latest_version = Arc::new(Mutex::new(None));
+
+        let config = Arc::new(Config {
+            version_check_interval_secs: 60,
+            ..Default::default()
+        });
+        let handle = tokio::spawn(run(config, server_api.clone(), latest_version.clone()));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(server_api.calls(), 1);
+        assert_eq!(
+            *latest_version.lock().await,
+            Some(Version::parse("999.0.0").unwrap())
+        );
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(server_api.calls(), 2);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(server_api.calls(), 3);
+        handle.abort();
+
+        // Single shot
+        let server_api = Arc::new(MockServerApi::new("1.0.0", "999.0.0"));
+        let config = Arc::new(Config {
+            version_check_interval_secs: 0,
+            ..Default::default()
+        });
+        run(config, server_api.clone(), latest_version).await;
+        assert_eq!(server_api.calls(), 1);
+    }
 }
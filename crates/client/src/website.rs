@@ -1,44 +1,218 @@
-use std::time::Duration;
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use crate::{
+    cache_headers,
     config::Config,
-    credentials::{CredManager, Credential, GuestCredential, UserCredential},
+    credentials::{CredManager, Credential},
     error::ServerError,
-    Environment, ProxyRequest,
+    server_api::ServerApi,
+    signin,
+    telemetry::TraceContextExt,
+    Environment, ProxyRequest, ProxyTarget, RequestBody,
 };
 use axum::{
-    extract::{Extension, Form, Host},
-    response::{Html, Redirect},
+    body::{Body, Bytes},
+    error_handling::HandleError,
+    extract::{multipart::MultipartError, Extension, Form, Host, Multipart},
+    http::{Request, StatusCode},
+    middleware,
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
     Router,
 };
-use cached::{CachedAsync, TimedCache};
-use models::{Contact, SignIn, SignInResult, SigninGuestResult};
+use backoff::backoff::Backoff;
+use bytes::BytesMut;
+use cached::{Cached, TimedCache};
+use models::{Contact, Motd, MotdSeverity, ServiceApproval, SignIn};
 use pulldown_cmark::{html, Parser};
-use secrecy::SecretString;
-use serde::Serialize;
+use reqwest::multipart::Part;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sysinfo::{System, SystemExt};
-use tera::Context;
-use tokio::{fs::File, io::AsyncReadExt};
+use tera::{Context, Tera};
+use tokio::{fs::File, io::AsyncReadExt, sync::watch};
+use tower::{Service, ServiceBuilder};
+use tower_http::{limit::RequestBodyLimitLayer, services::ServeDir};
+
+// The pages `disabled_pages` can leave out, the dashboard itself always stays
+const OPTIONAL_PAGES: [&str; 7] = [
+    "signin",
+    "signin-guest",
+    "contact",
+    "about",
+    "privacy",
+    "terms",
+    "new-service",
+];
+
+// The contact form takes attachments, so it's limited separately from the other routes
+pub fn contact_routes(config: &Config) -> Router<Body> {
+    let router = Router::<RequestBody>::new();
+    let router = if config.page_enabled("contact") {
+        router.route("/contact", get(handle_contact).post(handle_post_contact))
+    } else {
+        router
+    };
+
+    router.layer(RequestBodyLimitLayer::new(
+        config.max_request_body_bytes + config.contact_attachment_max_bytes,
+    ))
+}
 
-const FETCH_SERVER_NEWS_TIMEOUT: Duration = Duration::from_secs(3);
+pub fn routes(config: &Config) -> Router<RequestBody> {
+    for page in &config.disabled_pages {
+        if !OPTIONAL_PAGES.contains(&page.as_str()) {
+            tracing::warn!(?page, "Unknown page in disabled_pages, ignoring");
+        }
+    }
 
-pub fn routes() -> Router {
-    Router::new()
+    let optional_routes = [
+        (
+            "signin",
+            "/signin",
+            get(handle_signin).post(handle_post_signin),
+        ),
+        (
+            "signin-guest",
+            "/signin-guest",
+            get(handle_signin_guest).post(handle_post_signin_guest),
+        ),
+        ("privacy", "/privacy", get(handle_privacy)),
+        ("terms", "/terms", get(handle_terms)),
+        (
+            "new-service",
+            "/services/new",
+            get(handle_new_service).post(handle_post_new_service),
+        ),
+        ("about", "/about", get(handle_about)),
+    ];
+
+    let router = Router::new()
         .route("/", get(handle_index))
-        .route("/signin", get(handle_signin))
-        .route("/signin", post(handle_post_signin))
-        .route("/signin-guest", get(handle_signin_guest))
-        .route("/signin-guest", post(handle_post_signin_guest))
-        .route("/terminal", get(handle_terminal))
-        .route("/privacy", get(handle_privacy))
-        .route("/terms", get(handle_terms))
-        .route("/contact", get(handle_contact))
-        .route("/contact", post(handle_post_contact))
-        .route("/services/new", get(handle_new_service))
-        .route("/services/new", post(handle_post_new_service))
-        .route("/about", get(handle_about))
+        .route("/telemetry", post(handle_post_telemetry));
+    let router = optional_routes
+        .into_iter()
+        .filter(|(page, _, _)| config.page_enabled(page))
+        .fold(router, |router, (_, path, method_router)| {
+            router.route(path, method_router)
+        });
+
+    if config.terminal_enabled {
+        router.route("/terminal", get(handle_terminal))
+    } else {
+        router
+    }
+}
+
+// The dashboard templates, reloaded from disk before each render with `templates_hot_reload`
+#[derive(Clone)]
+pub struct Templates {
+    tera: Arc<RwLock<Tera>>,
+    hot_reload: bool,
+    disabled_pages: Arc<Vec<String>>,
+}
+
+impl Templates {
+    pub fn new(tera: Tera, hot_reload: bool) -> Self {
+        Self {
+            tera: Arc::new(RwLock::new(tera)),
+            hot_reload,
+            disabled_pages: Default::default(),
+        }
+    }
+
+    // Every page gets them, so links to disabled pages can be left out
+    pub fn with_disabled_pages(self, disabled_pages: Vec<String>) -> Self {
+        Self {
+            disabled_pages: Arc::new(disabled_pages),
+            ..self
+        }
+    }
+
+    pub async fn render(&self, template_name: &str, context: &Context) -> tera::Result<String> {
+        let mut context = context.clone();
+        context.insert("disabled_pages", &*self.disabled_pages);
+
+        // Reading every template from disk, off the async workers
+        if self.hot_reload {
+            let tera = self.tera.clone();
+            let template_name = template_name.to_string();
+            return tokio::task::spawn_blocking(move || {
+                let mut tera = tera.write().expect("templates lock poisoned");
+                tera.full_reload()?;
+                tera.render(&template_name, &context)
+            })
+            .await
+            .map_err(|e| tera::Error::msg(e.to_string()))?;
+        }
+
+        let tera = self.tera.read().expect("templates lock poisoned");
+        tera.render(template_name, &context)
+    }
+}
+
+// Files under `wwwroot_dir`, with a rendered 404 page for everything else
+pub fn static_files_service(
+    wwwroot_dir: PathBuf,
+    templates: Templates,
+) -> impl Service<
+    Request<RequestBody>,
+    Response = Response,
+    Error = Infallible,
+    Future = impl Future<Output = Result<Response, Infallible>> + Send + 'static,
+> + Clone
+       + Send
+       + 'static {
+    let not_found_service = tower::service_fn(move |request: Request<RequestBody>| {
+        let templates = templates.clone();
+        async move {
+            let ret = render_not_found(&templates, request.uri().path()).await;
+            Ok::<_, std::io::Error>(ret)
+        }
+    });
+
+    ServiceBuilder::new()
+        .layer(middleware::from_fn(cache_headers::set_cache_headers))
+        .service(HandleError::new(
+            ServeDir::new(wwwroot_dir).not_found_service(not_found_service),
+            handle_serve_dir_error,
+        ))
+}
+
+async fn render_not_found(templates: &Templates, path: &str) -> Response {
+    let mut context = Context::new();
+    context.insert("path", path);
+
+    match templates.render("not_found.html", &context).await {
+        Ok(val) => (StatusCode::NOT_FOUND, Html(val)).into_response(),
+        Err(e) => {
+            tracing::error!(?e, "Error rendering not_found.html");
+            (StatusCode::NOT_FOUND, "Not found").into_response()
+        }
+    }
+}
+
+async fn handle_serve_dir_error(err: std::io::Error) -> (StatusCode, String) {
+    // e.g. `/custom.css/nested`, ServeDir handles plain missing files itself
+    if matches!(
+        err.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+    ) {
+        return (StatusCode::NOT_FOUND, "Not found".to_string());
+    }
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Something went wrong: {}", err),
+    )
 }
 
 async fn handle_index(
@@ -47,62 +221,162 @@ async fn handle_index(
 ) -> Result<Html<String>, ServerError> {
     tracing::debug!(?host, "handle_index");
 
-    let vscode_url = if host.ends_with("-home.portalbox.app") {
-        let sub = host.trim_end_matches("-home.portalbox.app");
-        format!("//{sub}-vscode.portalbox.app")
-    } else {
-        let vscode_port = env.config.vscode_port;
-        let host_port = host.rsplit_once(':');
+    let server_news =
+        fetch_server_news(&env.config, env.server_api.as_ref(), &env.server_news_cache).await;
+    let motd = fetch_motd(&env.config, env.server_api.as_ref(), &env.motd_cache).await;
 
-        if let Some((host, _port)) = host_port {
-            format!("//{host}:{vscode_port}")
-        } else {
-            format!("//{host}:{vscode_port}")
+    let services = dashboard_services(&env.config, &host, env.vscode_address);
+    tracing::debug!(?services, "handle_index - got services");
+
+    let credential = {
+        let mut guard = env.existing_credential.lock().await;
+        // The proxy tears itself down on expiry as well, this covers the window in between
+        if guard
+            .as_ref()
+            .map(|val| val.is_expired())
+            .unwrap_or_default()
+        {
+            *guard = None;
         }
+        guard.clone()
     };
 
-    let server_news = fetch_server_news(&env.config).await;
+    let signed_in_home_url = credential.as_ref().map(|val| val.home_url());
 
-    tracing::debug!(?vscode_url, "handle_index - got vscode_url");
-
-    let vscode = LocalService {
-        name: "Visual Studio Code".to_string(),
-        url: vscode_url,
-        icon_url: "/vscode_icon.png".to_string(),
-    };
-    let terminal = LocalService {
-        name: "Terminal".to_string(),
-        url: "/terminal".to_string(),
-        icon_url: "/terminal_icon.png".to_string(),
-    };
-    let ssh = LocalService {
-        name: "SSH".to_string(),
-        url: "https://github.com/portalbox-app/portalbox#ssh-jump-host".to_string(),
-        icon_url: "/terminal_icon.png".to_string(),
+    let auth_failure = {
+        let guard = env.proxy_auth_failure.lock().await;
+        guard.as_ref().map(|val| {
+            json!({
+                "message": val.to_string(),
+                "requires_signin": val.code.requires_signin(),
+            })
+        })
     };
-    let services = vec![vscode, terminal, ssh];
 
-    let credential = {
-        let guard = env.existing_credential.lock().await;
-        guard.clone()
+    let (proxy_status, proxy_auth, latency_ms) = {
+        let state = env.proxy_connection_state.lock().await;
+        let latency_ms = state.average_rtt().map(|val| val.as_millis() as u64);
+        (state.status(), state.auth(), latency_ms)
     };
 
-    let signed_in_home_url = credential
-        .as_ref()
-        .map(|val| format!("https://{}-home.portalbox.app", val.base_sub_domain()));
-
     let render = {
         let mut context = Context::new();
         context.insert("services", &services);
+        context.insert("proxy_status", &proxy_status);
+        context.insert("proxy_auth", &proxy_auth);
+        context.insert("latency_ms", &latency_ms);
         context.insert("signed_in_home_url", &signed_in_home_url);
         context.insert("credential", &credential);
+        context.insert("session", &credential.as_ref().map(session_info));
+        context.insert("auth_failure", &auth_failure);
+        context.insert("offline", &env.config.offline);
+        context.insert("latest_version", &*env.latest_version.lock().await);
+        context.insert("apps_status", &env.apps_status.get());
         context.insert("server_news", &server_news);
+        context.insert("motd", &motd);
+        context.insert(
+            "telemetry_pending",
+            &env.telemetry_consent.lock().await.is_none(),
+        );
         context.insert("active_item", "dashboard");
-        env.tera.render("index.html", &context)?
+        env.templates.render("index.html", &context).await?
     };
     Ok(Html(render))
 }
 
+#[derive(Debug, Deserialize)]
+struct TelemetryChoice {
+    enabled: bool,
+}
+
+// Takes effect on the next start, the exporter is set up once
+async fn handle_post_telemetry(
+    Extension(env): Extension<Environment>,
+    Form(form): Form<TelemetryChoice>,
+) -> Result<Redirect, ServerError> {
+    env.config.save_telemetry(form.enabled).await?;
+    *env.telemetry_consent.lock().await = Some(form.enabled);
+    tracing::info!(enabled = form.enabled, "Telemetry choice saved");
+
+    Ok(Redirect::to("/"))
+}
+
+// `{sub}-{name}.portalbox.app` when browsing online, the local port otherwise. `None` online for
+// services that aren't forwarded, they can only be reached locally.
+fn service_url(host: &str, name: &str, port: u16, forwarded: bool) -> Option<String> {
+    if let Some(sub) = host.strip_suffix("-home.portalbox.app") {
+        return forwarded.then(|| format!("//{sub}-{name}.portalbox.app"));
+    }
+
+    let host = host.rsplit_once(':').map(|val| val.0).unwrap_or(host);
+    Some(format!("//{host}:{port}"))
+}
+
+// Online through the proxy, otherwise where vscode listens, on the host the dashboard was opened
+// with when vscode listens on all interfaces
+fn vscode_url(host: &str, vscode_address: SocketAddr) -> String {
+    if host.ends_with("-home.portalbox.app") || vscode_address.ip().is_unspecified() {
+        service_url(host, "vscode", vscode_address.port(), true).unwrap_or_default()
+    } else {
+        format!("//{vscode_address}")
+    }
+}
+
+fn dashboard_services(
+    config: &Config,
+    host: &str,
+    vscode_address: SocketAddr,
+) -> Vec<LocalService> {
+    let vscode_url = vscode_url(host, vscode_address);
+
+    let tiles = config
+        .dashboard_tiles
+        .iter()
+        .filter(|tile| match tile.service.as_deref() {
+            Some("terminal") => config.terminal_enabled,
+            Some("vscode") if !config.start_vscode => false,
+            Some(service) => config.allowed_services.iter().any(|val| val == service),
+            None => true,
+        })
+        .filter_map(|tile| {
+            let url = match (&tile.url, tile.service.as_deref()) {
+                (Some(url), _) => url.clone(),
+                (None, Some("vscode")) => vscode_url.clone(),
+                (None, _) => {
+                    tracing::warn!(name = ?tile.name, "Dashboard tile without url, skipping");
+                    return None;
+                }
+            };
+
+            Some(LocalService {
+                name: tile.name.clone(),
+                url,
+                icon_url: tile.icon_url.clone(),
+            })
+        });
+
+    let services = config.services.iter().filter_map(|service| {
+        Some(LocalService {
+            name: service.name.clone(),
+            url: service_url(host, &service.name, service.port, service.forward)?,
+            icon_url: service
+                .icon_url
+                .clone()
+                .unwrap_or_else(|| "/portal.png".to_string()),
+        })
+    });
+
+    tiles.chain(services).collect()
+}
+
+fn session_info(credential: &Credential) -> serde_json::Value {
+    json!({
+        "server_url": credential.server_url(),
+        "email": credential.email(),
+        "signed_in_ago": credential.signed_in_at().map(crate::utils::time_ago),
+    })
+}
+
 async fn handle_signin(
     Extension(env): Extension<Environment>,
 ) -> Result<Html<String>, ServerError> {
@@ -111,18 +385,21 @@ async fn handle_signin(
         guard.clone()
     };
 
-    if credential.is_some() {
+    if let Some(credential) = &credential {
         let render = {
             let mut context = Context::new();
             context.insert("active_item", "signin");
-            env.tera.render("already_signed_in.html", &context)?
+            context.insert("session", &session_info(credential));
+            env.templates
+                .render("already_signed_in.html", &context)
+                .await?
         };
         Ok(Html(render))
     } else {
         let render = {
             let mut context = Context::new();
             context.insert("active_item", "signin");
-            env.tera.render("signin.html", &context)?
+            env.templates.render("signin.html", &context).await?
         };
         Ok(Html(render))
     }
@@ -134,35 +411,19 @@ async fn handle_post_signin(
 ) -> Result<Redirect, ServerError> {
     tracing::debug!(?form, "handle signin");
 
-    let url = env.config.server_url_with_path("api/signin");
-
-    let client = reqwest::Client::new();
-
-    let res = client
-        .post(url)
-        .json(&form)
-        .send()
-        .await?
-        .json::<SignInResult>()
-        .await?;
+    if env.config.offline {
+        return Err(ServerError::Offline);
+    }
 
-    tracing::debug!(?res, "logged in - starting home service");
-
-    let credential = {
-        let cred = UserCredential::new(form.email, res.client_access_token, res.base_sub_domain);
-        Credential::new_user(cred)
-    };
+    let credential = signin::user_credential(env.server_api.as_ref(), &env.config, &form).await?;
 
     // Request to create service on the server
     let _ = start_proxy_service(credential.clone(), &env).await;
 
     if form.remember_me {
-        let mut cred_manager = CredManager::load(&env.config).await.unwrap_or_default();
-        cred_manager
-            .credentials
-            .insert(env.config.server_url().into(), credential);
-
-        let _ = cred_manager.save(&env.config).await;
+        if let Err(e) = signin::save_credential(&env.config, credential).await {
+            tracing::error!(?e, "Error saving the credential");
+        }
     }
 
     Ok(Redirect::to("/"))
@@ -180,14 +441,16 @@ async fn handle_signin_guest(
         let render = {
             let mut context = Context::new();
             context.insert("active_item", "signin-guest");
-            env.tera.render("already_signed_in.html", &context)?
+            env.templates
+                .render("already_signed_in.html", &context)
+                .await?
         };
         Ok(Html(render))
     } else {
         let render = {
             let mut context = Context::new();
             context.insert("active_item", "signin-guest");
-            env.tera.render("signin_guest.html", &context)?
+            env.templates.render("signin_guest.html", &context).await?
         };
         Ok(Html(render))
     }
@@ -198,35 +461,16 @@ async fn handle_post_signin_guest(
 ) -> Result<Redirect, ServerError> {
     tracing::debug!("handle_post_signin_guest");
 
-    let url = env.config.server_url_with_path("api/signin-guest");
-
-    let client = reqwest::Client::new();
-
-    let res = client
-        .post(url)
-        .send()
-        .await?
-        .json::<SigninGuestResult>()
-        .await?;
+    if env.config.offline {
+        return Err(ServerError::Offline);
+    }
 
-    let credential = {
-        let cred = GuestCredential::new(
-            res.base_sub_domain,
-            res.client_access_token,
-            res.access_code,
-        );
-        Credential::new_guest(cred)
-    };
+    let credential = signin::guest_credential(env.server_api.as_ref(), &env.config).await?;
 
     // Request to create service on the server
     let _ = start_proxy_service(credential.clone(), &env).await;
 
-    let mut cred_manager = CredManager::load(&env.config).await.unwrap_or_default();
-    cred_manager
-        .credentials
-        .insert(env.config.server_url().into(), credential);
-
-    let _ = cred_manager.save(&env.config).await;
+    signin::save_credential(&env.config, credential).await?;
 
     Ok(Redirect::to("/"))
 }
@@ -235,16 +479,30 @@ pub async fn start_proxy_service(
     credential: Credential,
     env: &Environment,
 ) -> Result<(), anyhow::Error> {
+    if env.config.offline {
+        return Err(anyhow::anyhow!(
+            "Can't start the proxy service in offline mode"
+        ));
+    }
+
+    if credential.is_expired() {
+        return Err(anyhow::anyhow!("Guest session expired"));
+    }
+
     let _ = request_access_and_start_proxy_service(
         &env,
         credential.base_sub_domain(),
         credential.client_access_token().clone(),
+        credential.expires_at(),
     )
     .await?;
 
     let mut cred_guard = env.existing_credential.lock().await;
     *cred_guard = Some(credential);
 
+    let mut auth_failure_guard = env.proxy_auth_failure.lock().await;
+    *auth_failure_guard = None;
+
     Ok(())
 }
 
@@ -252,40 +510,217 @@ async fn request_access_and_start_proxy_service(
     env: &Environment,
     base_sub_domain: &str,
     client_access_token: SecretString,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
 ) -> Result<(), anyhow::Error> {
+    let primary = request_service_approval(
+        &env.config,
+        &env.http_client,
+        base_sub_domain,
+        client_access_token.clone(),
+    )
+    .await;
+
+    let (fallback_sender, fallback_approval) = watch::channel(None);
+    let req = match primary {
+        Ok(service) => {
+            // Not waited for, the fallback is only needed once the server can't be reached
+            let env = env.clone();
+            let hostname = service.hostname.clone();
+            tokio::spawn(async move {
+                if let Some(val) = approve_fallback(&env, &hostname).await {
+                    let _ = fallback_sender.send(Some(val));
+                }
+            });
+
+            ProxyRequest {
+                portalbox_inner_token: service.service_access_token,
+                base_sub_domain: service.base_sub_domain,
+                hostname: service.hostname,
+                expires_at,
+                proxy_address: None,
+                fallback: fallback_approval,
+            }
+        }
+        Err(e) => {
+            let (service, proxy_address) = match approve_fallback_service(env).await {
+                Some(val) => val,
+                None => return Err(e),
+            };
+            let target = ProxyTarget {
+                proxy_address,
+                portalbox_inner_token: service.service_access_token,
+            };
+            tracing::warn!(?e, "Server unavailable, starting the proxy on the fallback");
+
+            tokio::spawn(reprobe_primary(
+                env.clone(),
+                base_sub_domain.to_string(),
+                client_access_token,
+                expires_at,
+                target.clone(),
+            ));
+
+            ProxyRequest {
+                portalbox_inner_token: target.portalbox_inner_token,
+                base_sub_domain: service.base_sub_domain,
+                hostname: service.hostname,
+                expires_at,
+                proxy_address: Some(target.proxy_address),
+                fallback: fallback_approval,
+            }
+        }
+    };
+
+    let _ = env
+        .proxy_request_sender
+        .send(req)
+        .await
+        .map_err(|_e| anyhow::anyhow!("Send error"))?;
+
+    Ok(())
+}
+
+// A proxy started on the fallback moves back to the server once it approves the service again.
+// Gives up once signed out or signed in again.
+async fn reprobe_primary(
+    env: Environment,
+    base_sub_domain: String,
+    client_access_token: SecretString,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    fallback: ProxyTarget,
+) {
+    let mut backoff = env.config.reconnect_backoff();
+
+    loop {
+        if let Some(val) = backoff.next_backoff() {
+            tokio::time::sleep(val).await;
+        }
+
+        let signed_in = env
+            .existing_credential
+            .lock()
+            .await
+            .as_ref()
+            .map(|val| {
+                val.client_access_token().expose_secret() == client_access_token.expose_secret()
+            })
+            .unwrap_or_default();
+        if !signed_in {
+            return;
+        }
+
+        let service = match request_service_approval(
+            &env.config,
+            &env.http_client,
+            &base_sub_domain,
+            client_access_token.clone(),
+        )
+        .await
+        {
+            Ok(val) => val,
+            Err(e) => {
+                tracing::debug!(?e, "Server still unavailable");
+                continue;
+            }
+        };
+
+        tracing::info!("Server available again, moving the proxy back");
+        let req = ProxyRequest {
+            portalbox_inner_token: service.service_access_token,
+            base_sub_domain: service.base_sub_domain,
+            hostname: service.hostname,
+            expires_at,
+            proxy_address: None,
+            fallback: watch::channel(Some(fallback)).1,
+        };
+        let _ = env.proxy_request_sender.send(req).await;
+        return;
+    }
+}
+
+// The fallback to fail over to, connections fail over under the same hostname
+async fn approve_fallback(env: &Environment, hostname: &str) -> Option<ProxyTarget> {
+    approve_fallback_service(env)
+        .await
+        .filter(|(service, _)| service.hostname == hostname)
+        .map(|(service, proxy_address)| ProxyTarget {
+            proxy_address,
+            portalbox_inner_token: service.service_access_token,
+        })
+}
+
+// Approved with the fallback server's own saved session, `None` without one
+async fn approve_fallback_service(env: &Environment) -> Option<(ServiceApproval, SocketAddr)> {
+    let fallback = env.config.fallback_config()?;
+    let credential = CredManager::load(&fallback)
+        .await
+        .ok()?
+        .credentials
+        .get(fallback.server_url().as_str())
+        .cloned()
+        .filter(|val| !val.is_expired())?;
+
+    let ret = async {
+        let service = request_service_approval(
+            &fallback,
+            &env.http_client,
+            credential.base_sub_domain(),
+            credential.client_access_token().clone(),
+        )
+        .await?;
+        let proxy_address = tokio::net::lookup_host(fallback.server_proxy_url())
+            .await?
+            .next()
+            .ok_or(anyhow::anyhow!("Failed to resolve fallback proxy server"))?;
+        Ok::<_, anyhow::Error>((service, proxy_address))
+    };
+
+    match ret.await {
+        Ok(val) => Some(val),
+        Err(e) => {
+            tracing::warn!(?e, server_url = %fallback.server_url(), "Fallback server unavailable");
+            None
+        }
+    }
+}
+
+pub(crate) async fn request_service_approval(
+    config: &Config,
+    http_client: &reqwest::Client,
+    base_sub_domain: &str,
+    client_access_token: SecretString,
+) -> Result<ServiceApproval, anyhow::Error> {
     tracing::debug!(?base_sub_domain, "Requesting service");
 
-    let url = env.config.server_url_with_path("api/services");
+    let url = config.server_url_with_path("api/services");
 
     let service_form = models::ServiceRequest {
+        version: models::SERVICE_VERSION,
         base_sub_domain: base_sub_domain.to_string(),
         client_access_token,
     };
 
-    let client = reqwest::Client::new();
-    let service = client
+    let service = http_client
         .post(url)
         .json(&service_form)
+        .with_trace_context()
         .send()
         .await?
-        .json::<models::ServiceApproval>()
+        .json::<ServiceApproval>()
         .await?;
 
     tracing::debug!(?service.base_sub_domain, "Service approved");
 
-    let req = ProxyRequest {
-        portalbox_inner_token: service.service_access_token,
-        base_sub_domain: service.base_sub_domain,
-        hostname: service.hostname,
-    };
-
-    let _ = env
-        .proxy_request_sender
-        .send(req)
-        .await
-        .map_err(|_e| anyhow::anyhow!("Send error"))?;
+    // Fields this client doesn't know about are ignored, the ones it knows still work
+    if service.version > models::SERVICE_VERSION {
+        tracing::warn!(
+            version = service.version,
+            supported = models::SERVICE_VERSION,
+            "The server's service approval is newer than this client understands, please update portalbox"
+        );
+    }
 
-    Ok(())
+    Ok(service)
 }
 
 async fn handle_terminal(
@@ -293,7 +728,7 @@ async fn handle_terminal(
 ) -> Result<Html<String>, ServerError> {
     let render = {
         let context = Context::new();
-        env.tera.render("terminal.html", &context)?
+        env.templates.render("terminal.html", &context).await?
     };
     Ok(Html(render))
 }
@@ -301,7 +736,7 @@ async fn handle_terminal(
 async fn handle_terms(Extension(env): Extension<Environment>) -> Result<Html<String>, ServerError> {
     let content = get_markdown_content("terms", env.clone()).await?;
 
-    render_content_page(content, env)
+    render_content_page(content, env).await
 }
 
 async fn handle_contact(
@@ -310,35 +745,134 @@ async fn handle_contact(
     let render = {
         let mut context = Context::new();
         context.insert("active_item", "contact");
-        env.tera.render("contact.html", &context)?
+        context.insert(
+            "attachment_max_size",
+            &byte_unit::Byte::from_bytes(env.config.contact_attachment_max_bytes as u128)
+                .get_appropriate_unit(true)
+                .to_string(),
+        );
+        env.templates.render("contact.html", &context).await?
     };
     Ok(Html(render))
 }
 
 async fn handle_post_contact(
     Extension(env): Extension<Environment>,
-    Form(form): Form<Contact>,
+    multipart: Multipart,
 ) -> Result<Html<String>, ServerError> {
+    let (form, attachment) =
+        read_contact_form(multipart, env.config.contact_attachment_max_bytes).await?;
+
     let url = env.config.server_url_with_path("api/contact");
-    let client = reqwest::Client::new();
-    let response = client.post(url).json(&form).send().await?;
+    let client = &env.http_client;
+    let request = match attachment {
+        Some(attachment) => {
+            let contact_part =
+                Part::text(serde_json::to_string(&form).map_err(anyhow::Error::from)?)
+                    .mime_str("application/json")?;
+            let attachment_part = Part::bytes(attachment.data.to_vec())
+                .file_name(attachment.file_name)
+                .mime_str(&attachment.content_type)?;
+            let multipart_form = reqwest::multipart::Form::new()
+                .part(models::CONTACT_PART, contact_part)
+                .part(models::CONTACT_ATTACHMENT_PART, attachment_part);
+            client.post(url).multipart(multipart_form)
+        }
+        None => client.post(url).json(&form),
+    };
+    let response = request.with_trace_context().send().await?;
 
     response.error_for_status()?;
 
     let render = {
         let context = Context::new();
 
-        env.tera.render("contact_post.html", &context)?
+        env.templates.render("contact_post.html", &context).await?
     };
     Ok(Html(render))
 }
 
+#[derive(Debug)]
+struct ContactAttachment {
+    file_name: String,
+    content_type: String,
+    data: Bytes,
+}
+
+// The text fields as `Contact`, and the attachment unless the file input was left empty
+async fn read_contact_form(
+    mut multipart: Multipart,
+    attachment_max_bytes: usize,
+) -> Result<(Contact, Option<ContactAttachment>), ServerError> {
+    let invalid = |e: MultipartError| ServerError::InvalidContactForm(e.to_string());
+
+    let mut fields = serde_json::Map::new();
+    let mut attachment = None;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(invalid)? {
+        let name = field.name().unwrap_or_default().to_string();
+
+        if name != models::CONTACT_ATTACHMENT_PART {
+            fields.insert(name, field.text().await.map_err(invalid)?.into());
+            continue;
+        }
+
+        let file_name = field.file_name().unwrap_or_default().to_string();
+        let content_type = attachment_content_type(&file_name, field.content_type());
+
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.chunk().await.map_err(invalid)? {
+            data.extend_from_slice(&chunk);
+            if data.len() > attachment_max_bytes {
+                return Err(ServerError::InvalidContactForm(format!(
+                    "The attachment is too large, the limit is {}",
+                    byte_unit::Byte::from_bytes(attachment_max_bytes as u128)
+                        .get_appropriate_unit(true)
+                )));
+            }
+        }
+
+        if file_name.is_empty() && data.is_empty() {
+            continue;
+        }
+
+        let content_type = content_type.ok_or_else(|| {
+            ServerError::InvalidContactForm(
+                "Only text, log and image files can be attached".to_string(),
+            )
+        })?;
+        attachment = Some(ContactAttachment {
+            file_name,
+            content_type,
+            data: data.freeze(),
+        });
+    }
+
+    let form = serde_json::from_value(fields.into())
+        .map_err(|e| ServerError::InvalidContactForm(e.to_string()))?;
+
+    Ok((form, attachment))
+}
+
+// Browsers often don't know `.log` files and send them as `application/octet-stream`
+fn attachment_content_type(file_name: &str, content_type: Option<&str>) -> Option<String> {
+    let content_type = content_type.unwrap_or("application/octet-stream");
+    if models::is_allowed_attachment_type(content_type) {
+        return Some(content_type.to_string());
+    }
+
+    let is_log = [".log", ".txt"]
+        .iter()
+        .any(|val| file_name.to_ascii_lowercase().ends_with(val));
+    (content_type == "application/octet-stream" && is_log).then(|| "text/plain".to_string())
+}
+
 async fn handle_new_service(
     Extension(env): Extension<Environment>,
 ) -> Result<Html<String>, ServerError> {
     let render = {
         let context = Context::new();
-        env.tera.render("new_service.html", &context)?
+        env.templates.render("new_service.html", &context).await?
     };
     Ok(Html(render))
 }
@@ -350,7 +884,9 @@ async fn handle_post_new_service(
     let render = {
         let context = Context::new();
 
-        env.tera.render("new_service_post.html", &context)?
+        env.templates
+            .render("new_service_post.html", &context)
+            .await?
     };
     Ok(Html(render))
 }
@@ -364,34 +900,10 @@ async fn handle_about(Extension(env): Extension<Environment>) -> Result<Html<Str
     let mem_info = MemInfo::from_system(&system);
 
     let battery_info = {
-        let manager =
-            anyhow::Context::context(battery::Manager::new(), "Can't get battery manager")?;
-        let mut batteries = anyhow::Context::context(manager.batteries(), "Can't get batteries")?;
-        let battery = batteries.next();
-
-        match battery {
-            Some(Ok(battery)) => {
-                json!(
-                {
-                    "state": format!("{:?}", battery.state()),
-                    "percentage": format!("{:?}", battery.state_of_charge()),
-                })
-            }
-            Some(Err(e)) => {
-                json!(
-                {
-                    "state": format!("Error getting battery state: {:?}", e),
-                    "percentage": "unknown",
-                })
-            }
-            None => {
-                json!(
-                {
-                    "state": "No battery detected",
-                    "percentage": "unknown",
-                })
-            }
-        }
+        let battery = battery::Manager::new()
+            .and_then(|manager| manager.batteries())
+            .map(|mut batteries| batteries.next());
+        battery_info(battery)
     };
 
     let render = {
@@ -402,17 +914,54 @@ async fn handle_about(Extension(env): Extension<Environment>) -> Result<Html<Str
         context.insert("battery_info", &battery_info);
         context.insert("active_item", "about");
 
-        env.tera.render("about.html", &context)?
+        env.templates.render("about.html", &context).await?
     };
     Ok(Html(render))
 }
 
+// The battery subsystem fails on some platforms and VMs, that only hides the battery info
+fn battery_info<E: std::fmt::Debug>(
+    battery: Result<Option<Result<battery::Battery, E>>, E>,
+) -> serde_json::Value {
+    match battery {
+        Ok(Some(Ok(battery))) => {
+            json!(
+            {
+                "state": format!("{:?}", battery.state()),
+                "percentage": format!("{:?}", battery.state_of_charge()),
+            })
+        }
+        Ok(Some(Err(e))) => {
+            json!(
+            {
+                "state": format!("Error getting battery state: {:?}", e),
+                "percentage": "unknown",
+            })
+        }
+        Ok(None) => {
+            json!(
+            {
+                "state": "No battery detected",
+                "percentage": "unknown",
+            })
+        }
+        Err(e) => {
+            tracing::debug!(?e, "Can't get batteries");
+            json!(
+            {
+                "state": "Battery info unavailable",
+                "percentage": "unknown",
+            })
+        }
+    }
+}
+
 async fn handle_privacy(
     Extension(env): Extension<Environment>,
 ) -> Result<Html<String>, ServerError> {
     let content = get_markdown_content("privacy", env.clone()).await?;
 
-    render_content_page(content, env)
+    render_content_page(content, env).await
 }
 
 #[tracing::instrument(skip(_env))]
@@ -442,7 +991,7 @@ async fn get_markdown_content(
     Ok(ret)
 }
 
-fn render_content_page(
+async fn render_content_page(
     content_page: ContentPage,
     env: Environment,
 ) -> Result<Html<String>, ServerError> {
@@ -451,46 +1000,153 @@ fn render_content_page(
         context.insert("title", &content_page.title);
         context.insert("content_html", &content_page.content_html);
 
-        env.tera.render("content_page.html", &context)?
+        env.templates.render("content_page.html", &context).await?
     };
 
     Ok(Html(render))
 }
 
-pub(crate) async fn fetch_server_news(config: &Config) -> String {
-    lazy_static::lazy_static! {
-        static ref CACHE: tokio::sync::Mutex<TimedCache<String, String>> = {
-            let ret = TimedCache::with_lifespan(60 * 60);
-            tokio::sync::Mutex::new(ret)
-        };
-    }
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", content = "html", rename_all = "snake_case")]
+pub(crate) enum ServerNews {
+    Loaded(String),
+    Offline,
+    Failed,
+}
 
-    let mut cache = CACHE.lock().await;
+// How long a failed fetch is remembered, so a down server isn't asked again on every page load
+const FAILED_FETCH_LIFESPAN: Duration = Duration::from_secs(30);
 
-    let ret = cache
-        .get_or_set_with("server_news".into(), || async move {
-            let ret = fetch_server_news_impl(&config).await.unwrap_or_default();
-            ret
+// Successful fetches are kept for the cache's lifespan, failures for `FAILED_FETCH_LIFESPAN`
+pub(crate) struct FetchCache<T> {
+    loaded: TimedCache<(), T>,
+    failed_at: Option<tokio::time::Instant>,
+}
+
+impl<T> FetchCache<T> {
+    fn new(lifespan_secs: u64) -> Mutex<Self> {
+        Mutex::new(Self {
+            loaded: TimedCache::with_lifespan(lifespan_secs),
+            failed_at: None,
         })
-        .await
-        .clone();
+    }
+}
 
+// `None` when the fetch failed, now or recently. The lock isn't held while fetching, so page
+// loads don't queue up behind a slow server, concurrent misses may both fetch.
+async fn fetch_cached<T, F, Fut>(cache: &Mutex<FetchCache<T>>, fetch: F) -> Option<T>
+where
+    T: Clone,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    {
+        let mut cache = cache.lock().unwrap();
+        if let Some(val) = cache.loaded.cache_get(&()) {
+            return Some(val.clone());
+        }
+        if let Some(failed_at) = cache.failed_at {
+            if failed_at.elapsed() < FAILED_FETCH_LIFESPAN {
+                return None;
+            }
+        }
+    }
+
+    let ret = fetch().await;
+
+    let mut cache = cache.lock().unwrap();
+    match &ret {
+        Some(val) => {
+            cache.loaded.cache_set((), val.clone());
+            cache.failed_at = None;
+        }
+        None => cache.failed_at = Some(tokio::time::Instant::now()),
+    }
     ret
 }
 
-async fn fetch_server_news_impl(config: &Config) -> anyhow::Result<String> {
-    tracing::debug!("fetch_server_news_impl");
+pub(crate) type ServerNewsCache = Mutex<FetchCache<String>>;
 
-    let url = config.server_url_with_path("api/server_news");
-    let client = reqwest::Client::new();
-    // let response = client.get(url).send().await?;
+pub(crate) fn server_news_cache(config: &Config) -> ServerNewsCache {
+    FetchCache::new(config.server_news_cache_secs)
+}
 
-    let resp = tokio::time::timeout(FETCH_SERVER_NEWS_TIMEOUT, client.get(url).send()).await??;
+pub(crate) async fn fetch_server_news(
+    config: &Config,
+    server_api: &dyn ServerApi,
+    cache: &ServerNewsCache,
+) -> ServerNews {
+    if config.offline {
+        return ServerNews::Offline;
+    }
 
-    let resp = resp.error_for_status()?;
-    let ret = resp.text().await?;
+    let ret = fetch_cached(cache, || async move {
+        tracing::debug!("fetch_server_news");
+        server_api
+            .server_news()
+            .await
+            .map_err(|e| tracing::warn!(?e, "Error fetching server news"))
+            .ok()
+    })
+    .await;
+
+    match ret {
+        Some(val) => ServerNews::Loaded(val),
+        None => ServerNews::Failed,
+    }
+}
 
-    Ok(ret)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct DashboardMotd {
+    severity: MotdSeverity,
+    message: String,
+    // Dismissals are remembered in the browser under this id, so a new message shows up again
+    id: String,
+}
+
+pub(crate) type MotdCache = Mutex<FetchCache<Option<Motd>>>;
+
+pub(crate) fn motd_cache(config: &Config) -> MotdCache {
+    FetchCache::new(config.motd_cache_secs)
+}
+
+// Failures only hide the banner, the news already reports an unreachable server
+pub(crate) async fn fetch_motd(
+    config: &Config,
+    server_api: &dyn ServerApi,
+    cache: &MotdCache,
+) -> Option<DashboardMotd> {
+    if config.offline {
+        return None;
+    }
+
+    let motd = fetch_cached(cache, || async move {
+        tracing::debug!("fetch_motd");
+        server_api
+            .motd()
+            .await
+            .map_err(|e| tracing::warn!(?e, "Error fetching motd"))
+            .ok()
+    })
+    .await??;
+
+    if motd.is_expired() {
+        return None;
+    }
+
+    Some(DashboardMotd {
+        severity: motd.severity,
+        id: message_hash(&motd.message),
+        message: motd.message,
+    })
+}
+
+// FNV-1a, stable across builds unlike `DefaultHasher`
+fn message_hash(message: &str) -> String {
+    let hash = message.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
 }
 
 struct ContentPage {
@@ -572,3 +1228,402 @@ impl SystemInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{DashboardTile, ServiceConfig},
+        server_api::mock::MockServerApi,
+    };
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    fn test_templates() -> Templates {
+        let dir_glob = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../website/templates/**/*.html"
+        );
+        Templates::new(Tera::new(dir_glob).unwrap(), false)
+    }
+
+    #[test]
+    fn test_battery_info_unavailable() {
+        let manager_error = std::io::Error::new(std::io::ErrorKind::NotFound, "no power supply");
+        let ret = battery_info::<std::io::Error>(Err(manager_error));
+        assert_eq!(ret["state"], "Battery info unavailable");
+        assert_eq!(ret["percentage"], "unknown");
+
+        let ret = battery_info::<std::io::Error>(Ok(None));
+        assert_eq!(ret["state"], "No battery detected");
+    }
+
+    #[tokio::test]
+    async fn test_templates_hot_reload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        let template = dir.join("page.html");
+        std::fs::write(&template, "before").unwrap();
+
+        let load = || Tera::new(&format!("{}/**/*.html", dir.display())).unwrap();
+        let reloading = Templates::new(load(), true);
+        let fixed = Templates::new(load(), false);
+        let render = |templates: Templates| async move {
+            templates
+                .render("page.html", &Context::new())
+                .await
+                .unwrap()
+        };
+        assert_eq!(render(reloading.clone()).await, "before");
+        assert_eq!(render(fixed.clone()).await, "before");
+
+        std::fs::write(&template, "after").unwrap();
+        assert_eq!(render(reloading).await, "after");
+        assert_eq!(render(fixed).await, "before");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_pages() {
+        let config = Config {
+            disabled_pages: vec!["about".into(), "contact".into()],
+            ..Default::default()
+        };
+
+        let app = routes(&config);
+        let (status, _) = get_static(app.clone(), "/about").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let request = Request::get("/contact").body(Body::empty()).unwrap();
+        let response = contact_routes(&config).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // Routed, but without the environment extension the handler can't run
+        let (status, _) = get_static(app, "/privacy").await;
+        assert_ne!(status, StatusCode::NOT_FOUND);
+
+        let templates = test_templates().with_disabled_pages(config.disabled_pages.clone());
+        let side_bar = templates
+            .render("partials/side_bar.html", &Context::new())
+            .await
+            .unwrap();
+        assert!(!side_bar.contains(r#"href="/about""#));
+        assert!(!side_bar.contains(r#"href="/contact""#));
+        assert!(side_bar.contains(r#"href="/signin""#));
+    }
+
+    async fn get_static(
+        service: impl Service<Request<RequestBody>, Response = Response, Error = Infallible>,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let body = http_body::Limited::new(Body::empty(), 1024);
+        let request = Request::get(path).body(body).unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[tokio::test]
+    async fn test_static_files_not_found() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_path_buf();
+        std::fs::write(dir.join("custom.css"), "body {}").unwrap();
+
+        let service = static_files_service(dir.clone(), test_templates());
+
+        let (status, body) = get_static(service.clone(), "/custom.css").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "body {}");
+
+        let (status, body) = get_static(service.clone(), "/missing.css").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Page Not Found"));
+        assert!(body.contains("missing.css"));
+
+        let (status, _) = get_static(service, "/custom.css/nested").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        async fn handle_form(Form(form): Form<HashMap<String, String>>) -> String {
+            form["message"].clone()
+        }
+
+        let app: Router<Body> = Router::<RequestBody>::new()
+            .route("/form", post(handle_form))
+            .layer(tower_http::limit::RequestBodyLimitLayer::new(32));
+
+        let post_form = |body: Body| {
+            Request::post("/form")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(body)
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post_form("message=hi".into()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let large = format!("message={}", "a".repeat(64));
+        let response = app
+            .clone()
+            .oneshot(post_form(large.clone().into()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without a content-length the limit applies while reading
+        let chunks = vec![Ok::<_, std::io::Error>(large)];
+        let body = Body::wrap_stream(futures::stream::iter(chunks));
+        let response = app.oneshot(post_form(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir_error_status() {
+        let err = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(handle_serve_dir_error(err).await.0, StatusCode::NOT_FOUND);
+
+        let err = std::io::Error::from(std::io::ErrorKind::Other);
+        assert_eq!(
+            handle_serve_dir_error(err).await.0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_server_news() {
+        use std::sync::atomic::Ordering;
+
+        let config = Config::default();
+        let server_api = MockServerApi::new("1.0.0", "1.0.0");
+        let cache = server_news_cache(&config);
+
+        server_api.server_news_fails.store(true, Ordering::SeqCst);
+        let ret = fetch_server_news(&config, &server_api, &cache).await;
+        assert_eq!(ret, ServerNews::Failed);
+
+        // The failure is remembered for a while
+        server_api.server_news_fails.store(false, Ordering::SeqCst);
+        let ret = fetch_server_news(&config, &server_api, &cache).await;
+        assert_eq!(ret, ServerNews::Failed);
+        assert_eq!(server_api.calls(), 1);
+
+        tokio::time::advance(FAILED_FETCH_LIFESPAN).await;
+        let ret = fetch_server_news(&config, &server_api, &cache).await;
+        assert_eq!(ret, ServerNews::Loaded("News".into()));
+
+        // Served from the cache
+        server_api.server_news_fails.store(true, Ordering::SeqCst);
+        let ret = fetch_server_news(&config, &server_api, &cache).await;
+        assert_eq!(ret, ServerNews::Loaded("News".into()));
+        assert_eq!(server_api.calls(), 2);
+
+        let config = Config {
+            offline: true,
+            ..Default::default()
+        };
+        let ret = fetch_server_news(&config, &server_api, &cache).await;
+        assert_eq!(ret, ServerNews::Offline);
+    }
+
+    #[tokio::test]
+    async fn test_read_contact_form() {
+        async fn handle_contact_form(multipart: Multipart) -> Result<String, ServerError> {
+            let (form, attachment) = read_contact_form(multipart, 16).await?;
+            let attachment = attachment.map(|val| {
+                format!(
+                    "{} {} {}",
+                    val.file_name,
+                    val.content_type,
+                    String::from_utf8_lossy(&val.data)
+                )
+            });
+            Ok(format!("{} {:?}", form.subject, attachment))
+        }
+
+        let app: Router<Body> = Router::new().route("/contact", post(handle_contact_form));
+
+        let post_contact = |file_name: &str, content_type: &str, data: &str| {
+            let body = format!(
+                "--X\r\nContent-Disposition: form-data; name=\"email\"\r\n\r\nx@y.z\r\n\
+                 --X\r\nContent-Disposition: form-data; name=\"subject\"\r\n\r\nHelp\r\n\
+                 --X\r\nContent-Disposition: form-data; name=\"message\"\r\n\r\nIt broke\r\n\
+                 --X\r\nContent-Disposition: form-data; name=\"attachment\"; filename=\"{file_name}\"\r\n\
+                 Content-Type: {content_type}\r\n\r\n{data}\r\n--X--\r\n"
+            );
+            Request::post("/contact")
+                .header("content-type", "multipart/form-data; boundary=X")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let send = |request| async {
+            let response = app.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, String::from_utf8_lossy(&body).to_string())
+        };
+
+        let (status, body) =
+            send(post_contact("app.log", "application/octet-stream", "error")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"Help Some("app.log text/plain error")"#);
+
+        // The file input left empty
+        let (status, body) = send(post_contact("", "application/octet-stream", "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "Help None");
+
+        let (status, _) = send(post_contact("app.zip", "application/zip", "PK")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(post_contact("app.log", "text/plain", &"a".repeat(17))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_motd() {
+        let config = Config::default();
+        let server_api = MockServerApi::new("1.0.0", "1.0.0");
+
+        // Servers without the endpoint have nothing to show
+        let ret = fetch_motd(&config, &server_api, &motd_cache(&config)).await;
+        assert_eq!(ret, None);
+
+        let motd = Motd {
+            severity: MotdSeverity::Critical,
+            message: "Maintenance at 2am".into(),
+            expires_at: None,
+        };
+        *server_api.motd.lock().unwrap() = Some(motd.clone());
+        let ret = fetch_motd(&config, &server_api, &motd_cache(&config))
+            .await
+            .unwrap();
+        assert_eq!(ret.severity, MotdSeverity::Critical);
+        assert_eq!(ret.message, "Maintenance at 2am");
+        assert_eq!(ret.id, message_hash("Maintenance at 2am"));
+        assert_ne!(ret.id, message_hash("Maintenance at 3am"));
+
+        *server_api.motd.lock().unwrap() = Some(Motd {
+            expires_at: Some(chrono::Utc::now() - chrono::Duration::seconds(1)),
+            ..motd
+        });
+        let ret = fetch_motd(&config, &server_api, &motd_cache(&config)).await;
+        assert_eq!(ret, None);
+    }
+
+    fn vscode_address(config: &Config) -> SocketAddr {
+        SocketAddr::new(config.vscode_host, config.vscode_port)
+    }
+
+    #[test]
+    fn test_vscode_url() {
+        let all_interfaces: SocketAddr = "0.0.0.0:3000".parse().unwrap();
+        assert_eq!(
+            vscode_url("localhost:3030", all_interfaces),
+            "//localhost:3000"
+        );
+        assert_eq!(
+            vscode_url("192.168.1.2:3030", all_interfaces),
+            "//192.168.1.2:3000"
+        );
+        assert_eq!(
+            vscode_url("box-home.portalbox.app", all_interfaces),
+            "//box-vscode.portalbox.app"
+        );
+
+        // Fallback port, and vscode bound to a single address
+        let fallback: SocketAddr = "0.0.0.0:41234".parse().unwrap();
+        assert_eq!(vscode_url("localhost:3030", fallback), "//localhost:41234");
+        let loopback: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        assert_eq!(vscode_url("localhost:3030", loopback), "//127.0.0.1:3000");
+        let ipv6: SocketAddr = "[::1]:3000".parse().unwrap();
+        assert_eq!(vscode_url("[::1]:3030", ipv6), "//[::1]:3000");
+        assert_eq!(
+            vscode_url("box-home.portalbox.app", loopback),
+            "//box-vscode.portalbox.app"
+        );
+    }
+
+    #[test]
+    fn test_dashboard_services() {
+        let config = Config::default();
+        let services = dashboard_services(&config, "localhost:3030", vscode_address(&config));
+        let names: Vec<_> = services.iter().map(|val| val.name.as_str()).collect();
+        assert_eq!(names, ["Visual Studio Code", "Terminal", "SSH"]);
+        assert_eq!(services[0].url, "//localhost:3000");
+
+        let services =
+            dashboard_services(&config, "box-home.portalbox.app", vscode_address(&config));
+        assert_eq!(services[0].url, "//box-vscode.portalbox.app");
+
+        let mut config = Config {
+            allowed_services: vec!["home".into(), "ssh".into()],
+            ..Default::default()
+        };
+        config.dashboard_tiles.push(DashboardTile {
+            name: "Grafana".into(),
+            url: Some("http://localhost:3001".into()),
+            icon_url: "/grafana.png".into(),
+            service: None,
+        });
+        config.dashboard_tiles.push(DashboardTile {
+            name: "No url".into(),
+            url: None,
+            icon_url: "/none.png".into(),
+            service: None,
+        });
+
+        let services = dashboard_services(&config, "localhost:3030", vscode_address(&config));
+        let names: Vec<_> = services.iter().map(|val| val.name.as_str()).collect();
+        assert_eq!(names, ["Terminal", "SSH", "Grafana"]);
+
+        config.terminal_enabled = false;
+        let services = dashboard_services(&config, "localhost:3030", vscode_address(&config));
+        let names: Vec<_> = services.iter().map(|val| val.name.as_str()).collect();
+        assert_eq!(names, ["SSH", "Grafana"]);
+    }
+
+    #[test]
+    fn test_dashboard_services_without_vscode() {
+        let config = Config {
+            start_vscode: false,
+            ..Default::default()
+        };
+        let services = dashboard_services(&config, "localhost:3030", vscode_address(&config));
+        assert!(services.iter().all(|val| val.name != "Visual Studio Code"));
+        assert!(services.iter().any(|val| val.name == "Terminal"));
+    }
+
+    #[test]
+    fn test_dashboard_user_services() {
+        let mut config = Config::default();
+        config.services.push(ServiceConfig {
+            name: "grafana".into(),
+            port: 3001,
+            icon_url: Some("/grafana.png".into()),
+            forward: true,
+        });
+        config.services.push(ServiceConfig {
+            name: "notes".into(),
+            port: 3002,
+            icon_url: None,
+            forward: false,
+        });
+
+        let services = dashboard_services(&config, "192.168.1.2:3030", vscode_address(&config));
+        assert_eq!(services[3].url, "//192.168.1.2:3001");
+        assert_eq!(services[3].icon_url, "/grafana.png");
+        assert_eq!(services[4].url, "//192.168.1.2:3002");
+        assert_eq!(services[4].icon_url, "/portal.png");
+
+        let services =
+            dashboard_services(&config, "box-home.portalbox.app", vscode_address(&config));
+        assert_eq!(services[3].url, "//box-grafana.portalbox.app");
+        // Not forwarded, so not reachable online
+        assert_eq!(services.len(), 4);
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use crate::{
+    browser,
+    config::Config,
+    credentials::{CredManager, Credential},
+};
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Which account this box is bound to, the token is never printed
+pub async fn whoami(config: &Config) -> anyhow::Result<()> {
+    let credential = CredManager::load(config)
+        .await
+        .ok()
+        .and_then(|val| val.credentials.get(config.server_url().as_str()).cloned())
+        .ok_or(anyhow::anyhow!(
+            "No saved credential for {}",
+            config.server_url()
+        ))?;
+
+    let session = session_status(config).await;
+    for line in describe(&credential, session.as_deref()) {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+fn describe(credential: &Credential, session: Option<&str>) -> Vec<String> {
+    let mut ret = vec![];
+    let mut push =
+        |key: &str, value: &dyn std::fmt::Display| ret.push(format!("{key:<15} = {value}"));
+
+    match credential {
+        Credential::User(val) => {
+            push("type", &"user");
+            push("email", &val.email);
+        }
+        Credential::Guest(_) => push("type", &"guest"),
+    }
+    push("base_sub_domain", credential.base_sub_domain());
+    if let Some(server_url) = credential.server_url() {
+        push("server_url", &server_url);
+    }
+    if let Some(expires_at) = credential.expires_at() {
+        push("expires_at", &expires_at);
+    }
+
+    let session = match session {
+        Some("connected") => "active",
+        Some(_) => "inactive, portalbox is running but not connected",
+        None => "inactive, portalbox isn't running",
+    };
+    push("session", &session);
+
+    ret
+}
+
+// The proxy status reported by a running portalbox's dashboard
+async fn session_status(config: &Config) -> Option<String> {
+    let status = dashboard_status(config).await?;
+    status["proxy_status"].as_str().map(String::from)
+}
+
+// A running portalbox's `api/status`, `None` when it isn't running
+pub(crate) async fn dashboard_status(config: &Config) -> Option<serde_json::Value> {
+    let url = format!("{}/api/status", browser::dashboard_url(config));
+    let http_client = reqwest::Client::builder()
+        .no_proxy()
+        // Plain HTTP, no need to load the system trust store
+        .tls_built_in_root_certs(false)
+        .timeout(STATUS_TIMEOUT)
+        .build()
+        .ok()?;
+
+    http_client
+        .get(url)
+        .send()
+        .await
+        .ok()?
+        .json::<serde_json::Value>()
+        .await
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::{GuestCredential, UserCredential};
+    use secrecy::SecretString;
+
+    #[test]
+    fn test_describe() {
+        let user = Credential::new_user(UserCredential::new(
+            "x@y.z".into(),
+            SecretString::new("secret-token".into()),
+            "xyz".into(),
+            "https://www.portalbox.app/".into(),
+        ));
+        let ret = describe(&user, Some("connected")).join("\n");
+        assert!(ret.contains("type            = user"));
+        assert!(ret.contains("email           = x@y.z"));
+        assert!(ret.contains("base_sub_domain = xyz"));
+        assert!(ret.contains("session         = active"));
+        assert!(!ret.contains("secret-token"));
+
+        let guest = Credential::new_guest(GuestCredential::new(
+            "guest".into(),
+            SecretString::new("secret-token".into()),
+            SecretString::new("secret-code".into()),
+            None,
+            "https://www.portalbox.app/".into(),
+        ));
+        let ret = describe(&guest, None).join("\n");
+        assert!(ret.contains("type            = guest"));
+        assert!(!ret.contains("email"));
+        assert!(ret.contains("session         = inactive, portalbox isn't running"));
+        assert!(!ret.contains("secret"));
+    }
+}
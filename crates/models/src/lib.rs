@@ -1,11 +1,13 @@
 pub mod consts;
 pub mod protocol;
+pub mod proxy_connection;
 pub mod utils;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub use crate::utils::serialize_secret_string;
 
+use chrono::{DateTime, Utc};
 use secrecy::SecretString;
 use semver::Version;
 use serde::{Deserialize, Serialize};
@@ -112,8 +114,33 @@ pub struct Contact {
     pub message: String,
 }
 
+// With an attachment `api/contact` gets a multipart form instead of json: the `Contact` as json in
+// the `contact` part and the file in the `attachment` part
+pub const CONTACT_PART: &str = "contact";
+pub const CONTACT_ATTACHMENT_PART: &str = "attachment";
+
+// Logs and screenshots, svg and html are left out as they can carry scripts
+pub fn is_allowed_attachment_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    matches!(
+        essence.as_str(),
+        "text/plain" | "text/x-log" | "image/png" | "image/jpeg" | "image/gif" | "image/webp"
+    )
+}
+
+// The shape of `ServiceRequest` and `ServiceApproval`, 0 when the peer predates the field
+pub const SERVICE_VERSION: u16 = 1;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceRequest {
+    #[serde(default)]
+    pub version: u16,
     pub base_sub_domain: String,
     #[serde(serialize_with = "serialize_secret_string")]
     pub client_access_token: SecretString,
@@ -121,18 +148,53 @@ pub struct ServiceRequest {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceApproval {
+    #[serde(default)]
+    pub version: u16,
     pub base_sub_domain: String,
     pub hostname: String,
     #[serde(serialize_with = "serialize_secret_string")]
     pub service_access_token: SecretString,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MotdSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+// An urgent notice from the server operators, e.g. upcoming maintenance, unlike the routine news
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Motd {
+    pub severity: MotdSeverity,
+    pub message: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Motd {
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(val) => val <= Utc::now(),
+            None => false,
+        }
+    }
+}
+
+const SERVER_DATA_SUBDIR: &str = "vscode-server-data";
+const USER_DATA_SUBDIR: &str = "vscode-user-data";
+const EXTENSIONS_SUBDIR: &str = "vscode-extensions";
+// Everything vscode keeps under its data root
+pub const VSCODE_DATA_SUBDIRS: [&str; 3] =
+    [SERVER_DATA_SUBDIR, USER_DATA_SUBDIR, EXTENSIONS_SUBDIR];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppsRequest {
     pub os_arch: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppsResult {
     pub vscode: AppInfo,
 }
@@ -167,30 +229,30 @@ impl AppInfo {
         dir
     }
 
-    pub fn server_data_dir<P: Into<PathBuf>>(&self, apps_data_dir: P) -> PathBuf {
-        self.apps_data_subdir(apps_data_dir, "vscode-server-data")
+    // The data dir helpers only build paths, `create_data_dirs` creates them without blocking
+    pub fn server_data_dir<P: AsRef<Path>>(&self, data_root: P) -> PathBuf {
+        data_root.as_ref().join(SERVER_DATA_SUBDIR)
     }
 
-    pub fn user_data_dir<P: Into<PathBuf>>(&self, apps_data_dir: P) -> PathBuf {
-        self.apps_data_subdir(apps_data_dir, "vscode-user-data")
+    pub fn user_data_dir<P: AsRef<Path>>(&self, data_root: P) -> PathBuf {
+        data_root.as_ref().join(USER_DATA_SUBDIR)
     }
 
-    pub fn extensions_dir<P: Into<PathBuf>>(&self, apps_data_dir: P) -> PathBuf {
-        self.apps_data_subdir(apps_data_dir, "vscode-extensions")
+    pub fn extensions_dir<P: AsRef<Path>>(&self, data_root: P) -> PathBuf {
+        data_root.as_ref().join(EXTENSIONS_SUBDIR)
     }
 
-    pub fn output_file<P: Into<PathBuf>>(&self, apps_data_dir: P) -> PathBuf {
-        let apps_data_dir = apps_data_dir.into();
-        apps_data_dir.join("vscode.log")
+    // For async callers, e.g. before starting vscode, as a slow filesystem would stall the runtime
+    pub async fn create_data_dirs<P: AsRef<Path>>(&self, data_root: P) -> std::io::Result<()> {
+        for subdir in VSCODE_DATA_SUBDIRS {
+            tokio::fs::create_dir_all(data_root.as_ref().join(subdir)).await?;
+        }
+        Ok(())
     }
 
-    fn apps_data_subdir<P: Into<PathBuf>>(&self, apps_data_dir: P, subdir: &str) -> PathBuf {
+    pub fn output_file<P: Into<PathBuf>>(&self, apps_data_dir: P) -> PathBuf {
         let apps_data_dir = apps_data_dir.into();
-        let ret = apps_data_dir.join(subdir);
-
-        let _ = std::fs::create_dir_all(&ret);
-
-        ret
+        apps_data_dir.join("vscode.log")
     }
 }
 
@@ -206,11 +268,107 @@ pub struct ClientVersionResponse {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::get_os_arch;
+    use crate::{
+        is_allowed_attachment_type, utils::get_os_arch, AppInfo, ServiceApproval, ServiceRequest,
+        SERVICE_VERSION, VSCODE_DATA_SUBDIRS,
+    };
+    use secrecy::{ExposeSecret, SecretString};
+
+    #[test]
+    fn test_service_request_version() {
+        let request = ServiceRequest {
+            version: SERVICE_VERSION,
+            base_sub_domain: "xyz".into(),
+            client_access_token: SecretString::new("token".into()),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        let ret: ServiceRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(ret.version, SERVICE_VERSION);
+        assert_eq!(ret.client_access_token.expose_secret(), "token");
+
+        let ret: ServiceRequest =
+            serde_json::from_str(r#"{"base_sub_domain":"xyz","client_access_token":"token"}"#)
+                .unwrap();
+        assert_eq!(ret.version, 0);
+    }
+
+    #[test]
+    fn test_service_approval_version() {
+        let approval = ServiceApproval {
+            version: SERVICE_VERSION + 1,
+            base_sub_domain: "xyz".into(),
+            hostname: "xyz.portalbox.app".into(),
+            service_access_token: SecretString::new("token".into()),
+        };
+        let json = serde_json::to_string(&approval).unwrap();
+        let ret: ServiceApproval = serde_json::from_str(&json).unwrap();
+        assert_eq!(ret.version, SERVICE_VERSION + 1);
+        assert_eq!(ret.hostname, "xyz.portalbox.app");
+
+        let ret: ServiceApproval = serde_json::from_str(
+            r#"{"base_sub_domain":"xyz","hostname":"xyz.portalbox.app","service_access_token":"token"}"#,
+        )
+        .unwrap();
+        assert_eq!(ret.version, 0);
+        assert_eq!(ret.service_access_token.expose_secret(), "token");
+    }
+
+    #[test]
+    fn test_is_allowed_attachment_type() {
+        assert!(is_allowed_attachment_type("text/plain"));
+        assert!(is_allowed_attachment_type("text/x-log; charset=utf-8"));
+        assert!(is_allowed_attachment_type("IMAGE/PNG"));
+        assert!(!is_allowed_attachment_type("image/svg+xml"));
+        assert!(!is_allowed_attachment_type("text/html"));
+        assert!(!is_allowed_attachment_type("text/javascript"));
+        assert!(!is_allowed_attachment_type("application/octet-stream"));
+        assert!(!is_allowed_attachment_type("application/zip"));
+    }
 
     #[test]
     fn get_out_platform_arch() {
         let val = get_os_arch();
         dbg!(val);
     }
+
+    #[tokio::test]
+    async fn test_data_dirs_under_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data_root = temp_dir.path().to_path_buf();
+
+        let app_info = AppInfo {
+            os_arch: get_os_arch(),
+            latest_version: semver::Version::new(1, 0, 0),
+            download_link: "".into(),
+        };
+
+        let dirs = [
+            app_info.server_data_dir(&data_root),
+            app_info.user_data_dir(&data_root),
+            app_info.extensions_dir(&data_root),
+        ];
+        for (dir, subdir) in dirs.iter().zip(VSCODE_DATA_SUBDIRS) {
+            assert_eq!(dir, &data_root.join(subdir));
+            assert!(!dir.exists());
+        }
+
+        app_info.create_data_dirs(&data_root).await.unwrap();
+        assert!(dirs.iter().all(|val| val.is_dir()));
+    }
+
+    #[tokio::test]
+    async fn test_create_data_dirs_unwritable() {
+        // A regular file can't have subdirs, regardless of permissions
+        let temp_dir = tempfile::tempdir().unwrap();
+        let not_a_dir = temp_dir.path().join("not-a-dir");
+        std::fs::write(&not_a_dir, "").unwrap();
+
+        let app_info = AppInfo {
+            os_arch: get_os_arch(),
+            latest_version: semver::Version::new(1, 0, 0),
+            download_link: "".into(),
+        };
+
+        assert!(app_info.create_data_dirs(&not_a_dir).await.is_err());
+    }
 }
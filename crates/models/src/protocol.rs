@@ -1,10 +1,66 @@
 use std::str::FromStr;
 
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use secrecy::{ExposeSecret, SecretString};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const AUTH_TOKEN_LENGTH: usize = 80;
+pub const PROTOCOL_VERSION: u16 = 7;
+// First protocol version where `AuthFailed` is followed by a reason payload
+pub const AUTH_FAILED_REASON_VERSION: u16 = 2;
+// First protocol version understanding `TokenRotate`, servers mustn't send it to older clients
+pub const TOKEN_ROTATE_VERSION: u16 = 3;
+// First protocol version understanding `DataForward`
+pub const DATA_FORWARD_VERSION: u16 = 4;
+// First protocol version where the client may answer a data message with `ServiceUnavailable`
+pub const SERVICE_UNAVAILABLE_VERSION: u16 = 5;
+// First protocol version where the server answers the hello with `ServerVersion`
+pub const SERVER_VERSION_MESSAGE_VERSION: u16 = 6;
+// First protocol version where the client pings right after `AuthOk`, servers read that ping and
+// answer `Pong` before sending anything else
+pub const CLIENT_PING_VERSION: u16 = 7;
+// Older servers don't report their version, so clients assume none of the later features
+pub const UNREPORTED_SERVER_VERSION: u16 = 1;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("Connection closed before the message was complete")]
+    Truncated,
+    #[error("IO error {0}")]
+    Io(std::io::Error),
+    #[error("Invalid UTF-8 {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("Unknown message code {0:#06x}")]
+    UnknownMessage(u16),
+    #[error("Invalid length {0}")]
+    InvalidLength(usize),
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Unexpected message {0:?}")]
+    UnexpectedMessage(ProxyConnectionMessage),
+}
+
+impl ProtocolError {
+    // Malformed data means the peer speaks a different protocol, retrying won't help.
+    // A well formed message at the wrong time may just be the server's state.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::Truncated | ProtocolError::Io(_) | ProtocolError::UnexpectedMessage(_)
+        )
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            ProtocolError::Truncated
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ProxyConnectionHello {
@@ -15,6 +71,8 @@ pub struct ProxyConnectionHello {
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u16)]
 pub enum ProxyConnectionMessage {
+    // Followed by the server's protocol version, right before `AuthOk` or `AuthFailed`
+    ServerVersion = 0x1000u16,
     AuthOk = 0x1111u16,
     AuthFailed = 0x2222u16,
     Ping = 0x3333,
@@ -22,22 +80,84 @@ pub enum ProxyConnectionMessage {
     DataHome = 0x5555,
     DataVscode = 0x5556,
     DataSsh = 0x5557,
+    // Followed by the name of a user configured service to forward data to
+    DataForward = 0x5558,
+    // Followed by a new connection token, used for subsequent connections
+    TokenRotate = 0x6666,
+    // Sent by the client instead of any data when the local service isn't listening yet, right
+    // before closing. Servers relay data as it comes, so only these two bytes followed by the
+    // close mean the service is starting up.
+    ServiceUnavailable = 0x7777,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, FromPrimitive, IntoPrimitive)]
+#[repr(u8)]
+pub enum AuthFailedCode {
+    #[num_enum(default)]
+    Unknown = 0,
+    TokenExpired = 1,
+    TokenRevoked = 2,
+    BoxSuspended = 3,
+    // The token isn't one a server would issue, only a new sign in gets a usable one
+    InvalidToken = 4,
+}
+
+impl AuthFailedCode {
+    pub fn requires_signin(&self) -> bool {
+        matches!(
+            self,
+            AuthFailedCode::TokenExpired
+                | AuthFailedCode::TokenRevoked
+                | AuthFailedCode::InvalidToken
+        )
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            AuthFailedCode::Unknown => "Authentication failed",
+            AuthFailedCode::TokenExpired => "Session expired, please sign in again",
+            AuthFailedCode::TokenRevoked => "Session revoked, please sign in again",
+            AuthFailedCode::BoxSuspended => "This box has been suspended",
+            AuthFailedCode::InvalidToken => "Invalid session, please sign in again",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AuthFailedReason {
+    pub code: AuthFailedCode,
+    pub message: Option<String>,
+}
+
+impl std::fmt::Display for AuthFailedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.code.description(), message),
+            None => write!(f, "{}", self.code.description()),
+        }
+    }
+}
+
+// Tokens are sent as is in the hello, so only `AUTH_TOKEN_LENGTH` printable ASCII characters work
+pub fn is_valid_token(token: &SecretString) -> bool {
+    let token = token.expose_secret();
+    token.len() == AUTH_TOKEN_LENGTH && token.chars().all(|val| val.is_ascii_graphic())
 }
 
 pub async fn read_hello_message<S: AsyncRead + Unpin>(
     stream: &mut S,
-) -> Result<ProxyConnectionHello, anyhow::Error> {
+) -> Result<ProxyConnectionHello, ProtocolError> {
     const BUF_LEN: usize = 2 + AUTH_TOKEN_LENGTH;
 
     let mut buf = vec![0u8; BUF_LEN];
 
     stream.read_exact(&mut buf).await?;
 
-    let version = u16::from_be_bytes(buf[..2].try_into()?);
+    let version = u16::from_be_bytes([buf[0], buf[1]]);
     let auth_token_bytes = &buf[2..2 + AUTH_TOKEN_LENGTH];
     let auth_token_str = std::str::from_utf8(auth_token_bytes)?;
 
-    let connection_token = SecretString::from_str(auth_token_str)?;
+    let connection_token = SecretString::from_str(auth_token_str).unwrap();
 
     let ret = ProxyConnectionHello {
         version,
@@ -50,10 +170,13 @@ pub async fn read_hello_message<S: AsyncRead + Unpin>(
 pub async fn write_hello_message<S: AsyncWrite + Unpin>(
     connection_token: SecretString,
     stream: &mut S,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), ProtocolError> {
     let auth_token = connection_token.expose_secret().as_bytes();
+    if auth_token.len() != AUTH_TOKEN_LENGTH {
+        return Err(ProtocolError::InvalidLength(auth_token.len()));
+    }
 
-    let version = 1u16;
+    let version = PROTOCOL_VERSION;
     let version_bytes = version.to_be_bytes();
 
     // Write hello message
@@ -67,14 +190,15 @@ pub async fn write_hello_message<S: AsyncWrite + Unpin>(
 
 pub async fn read_proxy_message<S: AsyncRead + Unpin>(
     stream: &mut S,
-) -> Result<ProxyConnectionMessage, anyhow::Error> {
+) -> Result<ProxyConnectionMessage, ProtocolError> {
     let mut buf = [0u8; 2];
 
     stream.read_exact(&mut buf).await?;
 
-    let code = u16::from_be_bytes(buf[..].try_into()?);
+    let code = u16::from_be_bytes(buf);
 
-    let msg = ProxyConnectionMessage::try_from(code)?;
+    let msg =
+        ProxyConnectionMessage::try_from(code).map_err(|_| ProtocolError::UnknownMessage(code))?;
 
     Ok(msg)
 }
@@ -82,7 +206,7 @@ pub async fn read_proxy_message<S: AsyncRead + Unpin>(
 pub async fn write_proxy_message<S: AsyncWrite + Unpin>(
     stream: &mut S,
     message: ProxyConnectionMessage,
-) -> Result<(), anyhow::Error> {
+) -> Result<(), ProtocolError> {
     let code: u16 = message.into();
 
     let code_bytes = code.to_be_bytes();
@@ -92,3 +216,307 @@ pub async fn write_proxy_message<S: AsyncWrite + Unpin>(
 
     Ok(())
 }
+
+// Reads the u16 version following a `ServerVersion` message
+pub async fn read_server_version<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<u16, ProtocolError> {
+    Ok(stream.read_u16().await?)
+}
+
+pub async fn write_server_version_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(), ProtocolError> {
+    let code: u16 = ProxyConnectionMessage::ServerVersion.into();
+    stream.write_all(&code.to_be_bytes()).await?;
+    stream.write_all(&PROTOCOL_VERSION.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+// Reads the payload following an `AuthFailed` message:
+// 1 byte reason code, then a u16 length prefixed UTF-8 reason string (may be empty)
+pub async fn read_auth_failed_reason<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    version: u16,
+) -> Result<Option<AuthFailedReason>, ProtocolError> {
+    if version < AUTH_FAILED_REASON_VERSION {
+        return Ok(None);
+    }
+
+    let code = AuthFailedCode::from(stream.read_u8().await?);
+
+    let message_len = stream.read_u16().await? as usize;
+    let message = if message_len > 0 {
+        let mut buf = vec![0u8; message_len];
+        stream.read_exact(&mut buf).await?;
+        Some(std::str::from_utf8(&buf)?.to_string())
+    } else {
+        None
+    };
+
+    let ret = AuthFailedReason { code, message };
+
+    Ok(Some(ret))
+}
+
+pub async fn write_auth_failed_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: u16,
+    reason: &AuthFailedReason,
+) -> Result<(), ProtocolError> {
+    let code: u16 = ProxyConnectionMessage::AuthFailed.into();
+    stream.write_all(&code.to_be_bytes()).await?;
+
+    if version >= AUTH_FAILED_REASON_VERSION {
+        let message = reason.message.as_deref().unwrap_or_default().as_bytes();
+        let message_len = u16::try_from(message.len())
+            .map_err(|_| ProtocolError::InvalidLength(message.len()))?;
+
+        stream.write_u8(reason.code.into()).await?;
+        stream.write_all(&message_len.to_be_bytes()).await?;
+        stream.write_all(message).await?;
+    }
+
+    stream.flush().await?;
+
+    Ok(())
+}
+
+// Reads the new token following a `TokenRotate` message
+pub async fn read_token_rotate_token<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<SecretString, ProtocolError> {
+    let mut buf = vec![0u8; AUTH_TOKEN_LENGTH];
+    stream.read_exact(&mut buf).await?;
+
+    let token_str = std::str::from_utf8(&buf).map_err(|_| ProtocolError::InvalidToken)?;
+    let token = SecretString::from_str(token_str).unwrap();
+    if !is_valid_token(&token) {
+        return Err(ProtocolError::InvalidToken);
+    }
+
+    Ok(token)
+}
+
+pub async fn write_token_rotate_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    connection_token: &SecretString,
+) -> Result<(), ProtocolError> {
+    let token = connection_token.expose_secret().as_bytes();
+    if token.len() != AUTH_TOKEN_LENGTH {
+        return Err(ProtocolError::InvalidLength(token.len()));
+    }
+
+    let code: u16 = ProxyConnectionMessage::TokenRotate.into();
+    stream.write_all(&code.to_be_bytes()).await?;
+    stream.write_all(token).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+// Reads the service name following a `DataForward` message:
+// u8 length prefixed UTF-8 name
+pub async fn read_data_forward_service<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<String, ProtocolError> {
+    let name_len = stream.read_u8().await? as usize;
+    if name_len == 0 {
+        return Err(ProtocolError::InvalidLength(name_len));
+    }
+
+    let mut buf = vec![0u8; name_len];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(std::str::from_utf8(&buf)?.to_string())
+}
+
+pub async fn write_data_forward_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    service: &str,
+) -> Result<(), ProtocolError> {
+    let name = service.as_bytes();
+    let name_len = match u8::try_from(name.len()) {
+        Ok(0) | Err(_) => return Err(ProtocolError::InvalidLength(name.len())),
+        Ok(val) => val,
+    };
+
+    let code: u16 = ProxyConnectionMessage::DataForward.into();
+    stream.write_all(&code.to_be_bytes()).await?;
+    stream.write_u8(name_len).await?;
+    stream.write_all(name).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(reason: &AuthFailedReason, version: u16) -> Option<AuthFailedReason> {
+        let mut buf = vec![];
+        write_auth_failed_message(&mut buf, version, reason)
+            .await
+            .unwrap();
+
+        let mut stream = buf.as_slice();
+        let msg = read_proxy_message(&mut stream).await.unwrap();
+        assert_eq!(msg, ProxyConnectionMessage::AuthFailed);
+
+        let ret = read_auth_failed_reason(&mut stream, version).await.unwrap();
+        assert!(stream.is_empty());
+
+        ret
+    }
+
+    #[tokio::test]
+    async fn test_auth_failed_reason_round_trip() {
+        let expired = AuthFailedReason {
+            code: AuthFailedCode::TokenExpired,
+            message: None,
+        };
+        assert_eq!(round_trip(&expired, PROTOCOL_VERSION).await, Some(expired));
+
+        let suspended = AuthFailedReason {
+            code: AuthFailedCode::BoxSuspended,
+            message: Some("Payment overdue".into()),
+        };
+        assert_eq!(
+            round_trip(&suspended, PROTOCOL_VERSION).await,
+            Some(suspended)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_failed_reason_old_version() {
+        let revoked = AuthFailedReason {
+            code: AuthFailedCode::TokenRevoked,
+            message: Some("Revoked".into()),
+        };
+        assert_eq!(round_trip(&revoked, 1).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let buf = [0x11u8];
+        let ret = read_proxy_message(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::Truncated)));
+
+        let buf = [0x12u8, 0x34];
+        let ret = read_proxy_message(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::UnknownMessage(0x1234))));
+
+        let mut buf = vec![0u8, 2];
+        buf.extend([0xffu8; AUTH_TOKEN_LENGTH]);
+        let ret = read_hello_message(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidUtf8(_))));
+
+        let token = SecretString::new("short".into());
+        let ret = write_hello_message(token, &mut vec![]).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidLength(5))));
+    }
+
+    #[tokio::test]
+    async fn test_server_version_round_trip() {
+        let mut buf = vec![];
+        write_server_version_message(&mut buf).await.unwrap();
+
+        let mut stream = buf.as_slice();
+        let msg = read_proxy_message(&mut stream).await.unwrap();
+        assert_eq!(msg, ProxyConnectionMessage::ServerVersion);
+        assert_eq!(
+            read_server_version(&mut stream).await.unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert!(stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_token_rotate_round_trip() {
+        let token = SecretString::new("t".repeat(AUTH_TOKEN_LENGTH));
+
+        let mut buf = vec![];
+        write_token_rotate_message(&mut buf, &token).await.unwrap();
+
+        let mut stream = buf.as_slice();
+        let msg = read_proxy_message(&mut stream).await.unwrap();
+        assert_eq!(msg, ProxyConnectionMessage::TokenRotate);
+
+        let ret = read_token_rotate_token(&mut stream).await.unwrap();
+        assert_eq!(ret.expose_secret(), token.expose_secret());
+        assert!(stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_token_rotate_invalid() {
+        let token = SecretString::new("short".into());
+        let ret = write_token_rotate_message(&mut vec![], &token).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidLength(5))));
+
+        let buf = [b'a'; AUTH_TOKEN_LENGTH - 1];
+        let ret = read_token_rotate_token(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::Truncated)));
+
+        let buf = [b' '; AUTH_TOKEN_LENGTH];
+        let ret = read_token_rotate_token(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidToken)));
+
+        let buf = [0xffu8; AUTH_TOKEN_LENGTH];
+        let ret = read_token_rotate_token(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_is_valid_token() {
+        assert!(is_valid_token(&SecretString::new(
+            "t".repeat(AUTH_TOKEN_LENGTH)
+        )));
+        assert!(!is_valid_token(&SecretString::new("short".into())));
+        assert!(!is_valid_token(&SecretString::new(
+            "t".repeat(AUTH_TOKEN_LENGTH + 1)
+        )));
+        assert!(!is_valid_token(&SecretString::new(format!(
+            "{} ",
+            "t".repeat(AUTH_TOKEN_LENGTH - 1)
+        ))));
+    }
+
+    #[tokio::test]
+    async fn test_data_forward_round_trip() {
+        let mut buf = vec![];
+        write_data_forward_message(&mut buf, "grafana")
+            .await
+            .unwrap();
+
+        let mut stream = buf.as_slice();
+        let msg = read_proxy_message(&mut stream).await.unwrap();
+        assert_eq!(msg, ProxyConnectionMessage::DataForward);
+
+        let ret = read_data_forward_service(&mut stream).await.unwrap();
+        assert_eq!(ret, "grafana");
+        assert!(stream.is_empty());
+
+        let ret = write_data_forward_message(&mut vec![], "").await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidLength(0))));
+        let ret = write_data_forward_message(&mut vec![], &"a".repeat(256)).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidLength(256))));
+
+        let buf = [0u8];
+        let ret = read_data_forward_service(&mut buf.as_slice()).await;
+        assert!(matches!(ret, Err(ProtocolError::InvalidLength(0))));
+    }
+
+    #[tokio::test]
+    async fn test_auth_failed_unknown_code() {
+        let buf = [42u8, 0, 0];
+        let ret = read_auth_failed_reason(&mut buf.as_slice(), PROTOCOL_VERSION)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ret.code, AuthFailedCode::Unknown);
+        assert_eq!(ret.message, None);
+    }
+}
@@ -0,0 +1,521 @@
+//! A higher level view of a proxy connection, on top of the framing in [`crate::protocol`].
+//!
+//! The client side connects, calls [`ProxyConnection::do_hello`] and [`ProxyConnection::ping`]
+//! then waits in [`ProxyConnection::await_data`] until the server hands it a data stream. The server side
+//! uses [`ProxyConnection::read_hello`] and the `write_*` methods.
+//!
+//! [`ProxyConnection::with_protocol_trace`] logs every message at debug level, only the message
+//! type, never tokens or payloads.
+
+use std::time::{Duration, Instant};
+
+use secrecy::SecretString;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::protocol::{
+    self, AuthFailedReason, ProtocolError, ProxyConnectionHello, ProxyConnectionMessage,
+    CLIENT_PING_VERSION, PROTOCOL_VERSION, SERVER_VERSION_MESSAGE_VERSION,
+    UNREPORTED_SERVER_VERSION,
+};
+
+/// Outcome of the hello handshake, as seen by the client
+#[derive(Debug)]
+pub enum HelloResult {
+    Accepted,
+    /// The reason is `None` unless the server reported `AUTH_FAILED_REASON_VERSION` or later
+    Rejected(Option<AuthFailedReason>),
+}
+
+/// What [`ProxyConnection::await_data`] returned on
+#[derive(Debug)]
+pub enum ProxyEvent {
+    /// One of the `Data*` messages, the stream now carries data for that service
+    Data(ProxyConnectionMessage),
+    /// `DataForward`, the stream now carries data for the named user service
+    Forward(String),
+    /// The server rotated the connection token, it should be used for new connections
+    TokenRotated(SecretString),
+}
+
+/// Wraps any stream speaking the proxy protocol
+#[derive(Debug)]
+pub struct ProxyConnection<S> {
+    stream: S,
+    version: u16,
+    // When the connection was created, only set when tracing the protocol
+    trace_started: Option<Instant>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ProxyConnection<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            version: PROTOCOL_VERSION,
+            trace_started: None,
+        }
+    }
+
+    /// Logs the direction, type and timing of every message, for debugging protocol issues
+    pub fn with_protocol_trace(mut self, enabled: bool) -> Self {
+        self.trace_started = enabled.then(Instant::now);
+        self
+    }
+
+    // `message` is a type name, callers never pass payloads
+    fn trace(&self, direction: &str, message: &dyn std::fmt::Debug) {
+        if let Some(started) = self.trace_started {
+            tracing::debug!(
+                direction,
+                kind = ?message,
+                elapsed_ms = started.elapsed().as_millis() as u64,
+                "Proxy protocol"
+            );
+        }
+    }
+
+    /// The protocol version of the peer, known once the hello is done on either side
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub async fn read_message(&mut self) -> Result<ProxyConnectionMessage, ProtocolError> {
+        let ret = protocol::read_proxy_message(&mut self.stream).await?;
+        self.trace("read", &ret);
+        Ok(ret)
+    }
+
+    pub async fn write_message(
+        &mut self,
+        message: ProxyConnectionMessage,
+    ) -> Result<(), ProtocolError> {
+        self.trace("write", &message);
+        protocol::write_proxy_message(&mut self.stream, message).await
+    }
+
+    /// Client side: sends the hello and waits for the server to accept or reject it
+    pub async fn do_hello(
+        &mut self,
+        connection_token: SecretString,
+    ) -> Result<HelloResult, ProtocolError> {
+        self.trace("write", &"Hello");
+        protocol::write_hello_message(connection_token, &mut self.stream).await?;
+
+        let mut message = self.read_message().await?;
+        self.version = UNREPORTED_SERVER_VERSION;
+        if message == ProxyConnectionMessage::ServerVersion {
+            self.version = protocol::read_server_version(&mut self.stream).await?;
+            message = self.read_message().await?;
+        }
+
+        match message {
+            ProxyConnectionMessage::AuthOk => Ok(HelloResult::Accepted),
+            ProxyConnectionMessage::AuthFailed => {
+                let reason =
+                    protocol::read_auth_failed_reason(&mut self.stream, self.version).await?;
+                Ok(HelloResult::Rejected(reason))
+            }
+            val => Err(ProtocolError::UnexpectedMessage(val)),
+        }
+    }
+
+    /// Client side: right after the hello was accepted, times a ping to the server. `None` when
+    /// the server is older than `CLIENT_PING_VERSION` and wouldn't expect it.
+    pub async fn ping(&mut self) -> Result<Option<Duration>, ProtocolError> {
+        if self.version < CLIENT_PING_VERSION {
+            return Ok(None);
+        }
+
+        let started = Instant::now();
+        self.write_message(ProxyConnectionMessage::Ping).await?;
+        match self.read_message().await? {
+            ProxyConnectionMessage::Pong => Ok(Some(started.elapsed())),
+            val => Err(ProtocolError::UnexpectedMessage(val)),
+        }
+    }
+
+    /// Client side: answers pings until the server sends data or rotates the token.
+    /// Errors out if nothing arrives within `ping_timeout`.
+    pub async fn await_data(
+        &mut self,
+        ping_timeout: Duration,
+    ) -> Result<ProxyEvent, ProtocolError> {
+        loop {
+            let message = tokio::time::timeout(ping_timeout, self.read_message())
+                .await
+                .map_err(|_| {
+                    ProtocolError::Io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "No ping within timeout",
+                    ))
+                })??;
+
+            match message {
+                ProxyConnectionMessage::Ping => {
+                    self.write_message(ProxyConnectionMessage::Pong).await?;
+                }
+                ProxyConnectionMessage::TokenRotate => {
+                    let token = protocol::read_token_rotate_token(&mut self.stream).await?;
+                    return Ok(ProxyEvent::TokenRotated(token));
+                }
+                ProxyConnectionMessage::DataForward => {
+                    let service = protocol::read_data_forward_service(&mut self.stream).await?;
+                    return Ok(ProxyEvent::Forward(service));
+                }
+                val @ (ProxyConnectionMessage::DataHome
+                | ProxyConnectionMessage::DataVscode
+                | ProxyConnectionMessage::DataSsh) => return Ok(ProxyEvent::Data(val)),
+                val => return Err(ProtocolError::UnexpectedMessage(val)),
+            }
+        }
+    }
+
+    /// Server side: reads the client's hello, remembering its protocol version
+    pub async fn read_hello(&mut self) -> Result<ProxyConnectionHello, ProtocolError> {
+        let hello = protocol::read_hello_message(&mut self.stream).await?;
+        self.trace("read", &"Hello");
+        self.version = hello.version;
+        Ok(hello)
+    }
+
+    // Clients before `SERVER_VERSION_MESSAGE_VERSION` would take it for the hello reply
+    async fn write_server_version(&mut self) -> Result<(), ProtocolError> {
+        if self.version >= SERVER_VERSION_MESSAGE_VERSION {
+            self.trace("write", &ProxyConnectionMessage::ServerVersion);
+            protocol::write_server_version_message(&mut self.stream).await?;
+        }
+        Ok(())
+    }
+
+    /// Server side: accepts the hello, telling clients that understand it our version
+    pub async fn write_auth_ok(&mut self) -> Result<(), ProtocolError> {
+        self.write_server_version().await?;
+        self.write_message(ProxyConnectionMessage::AuthOk).await
+    }
+
+    /// Server side: answers the ping clients at `CLIENT_PING_VERSION` or later send right after
+    /// `AuthOk`, before anything else is sent
+    pub async fn answer_ping(&mut self) -> Result<(), ProtocolError> {
+        if self.version < CLIENT_PING_VERSION {
+            return Ok(());
+        }
+
+        match self.read_message().await? {
+            ProxyConnectionMessage::Ping => self.write_message(ProxyConnectionMessage::Pong).await,
+            val => Err(ProtocolError::UnexpectedMessage(val)),
+        }
+    }
+
+    /// Server side: rejects the hello, the reason is only sent to clients understanding it
+    pub async fn write_auth_failed(
+        &mut self,
+        reason: &AuthFailedReason,
+    ) -> Result<(), ProtocolError> {
+        self.write_server_version().await?;
+        self.trace("write", &ProxyConnectionMessage::AuthFailed);
+        protocol::write_auth_failed_message(&mut self.stream, self.version, reason).await
+    }
+
+    /// Server side: only call this for clients at `TOKEN_ROTATE_VERSION` or later
+    pub async fn write_token_rotate(
+        &mut self,
+        connection_token: &SecretString,
+    ) -> Result<(), ProtocolError> {
+        self.trace("write", &ProxyConnectionMessage::TokenRotate);
+        protocol::write_token_rotate_message(&mut self.stream, connection_token).await
+    }
+
+    /// Server side: only call this for clients at `DATA_FORWARD_VERSION` or later
+    pub async fn write_data_forward(&mut self, service: &str) -> Result<(), ProtocolError> {
+        self.trace("write", &ProxyConnectionMessage::DataForward);
+        protocol::write_data_forward_message(&mut self.stream, service).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use secrecy::ExposeSecret;
+
+    use super::*;
+    use crate::protocol::{AuthFailedCode, AUTH_TOKEN_LENGTH};
+
+    fn token(c: char) -> SecretString {
+        SecretString::new(c.to_string().repeat(AUTH_TOKEN_LENGTH))
+    }
+
+    #[tokio::test]
+    async fn test_hello_accepted() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        let server_fut = async {
+            let hello = server.read_hello().await.unwrap();
+            assert_eq!(hello.version, PROTOCOL_VERSION);
+            assert_eq!(
+                hello.connection_token.expose_secret(),
+                token('a').expose_secret()
+            );
+            server.write_auth_ok().await.unwrap();
+        };
+
+        let (ret, _) = tokio::join!(client.do_hello(token('a')), server_fut);
+        assert!(matches!(ret, Ok(HelloResult::Accepted)));
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        let server_fut = async {
+            server.read_hello().await.unwrap();
+            server.write_auth_ok().await.unwrap();
+            server.answer_ping().await.unwrap();
+            server
+                .write_message(ProxyConnectionMessage::DataVscode)
+                .await
+                .unwrap();
+        };
+
+        let client_fut = async {
+            client.do_hello(token('a')).await.unwrap();
+            let rtt = client.ping().await.unwrap().unwrap();
+            let event = client.await_data(Duration::from_secs(5)).await.unwrap();
+            (rtt, event)
+        };
+
+        let ((rtt, event), _) = tokio::join!(client_fut, server_fut);
+        assert!(rtt < Duration::from_secs(5));
+        assert!(matches!(
+            event,
+            ProxyEvent::Data(ProxyConnectionMessage::DataVscode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ping_old_server() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        // Doesn't report its version, so it isn't pinged
+        let server_fut = async {
+            server.read_hello().await.unwrap();
+            server
+                .write_message(ProxyConnectionMessage::AuthOk)
+                .await
+                .unwrap();
+            server
+                .write_message(ProxyConnectionMessage::DataVscode)
+                .await
+                .unwrap();
+        };
+
+        let client_fut = async {
+            client.do_hello(token('a')).await.unwrap();
+            assert_eq!(client.ping().await.unwrap(), None);
+            client.await_data(Duration::from_secs(5)).await.unwrap()
+        };
+
+        let (event, _) = tokio::join!(client_fut, server_fut);
+        assert!(matches!(
+            event,
+            ProxyEvent::Data(ProxyConnectionMessage::DataVscode)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hello_old_server() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        let reason = AuthFailedReason {
+            code: AuthFailedCode::TokenExpired,
+            message: None,
+        };
+
+        // Doesn't report its version, the reason payload is left unread
+        let server_fut = async {
+            server.read_hello().await.unwrap();
+            protocol::write_auth_failed_message(server.get_mut(), 5, &reason)
+                .await
+                .unwrap();
+        };
+
+        let (ret, _) = tokio::join!(client.do_hello(token('a')), server_fut);
+        assert!(matches!(ret, Ok(HelloResult::Rejected(None))));
+        assert_eq!(client.version(), UNREPORTED_SERVER_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_trace() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client).with_protocol_trace(true);
+        let mut server = ProxyConnection::new(server).with_protocol_trace(false);
+        assert!(client.trace_started.is_some());
+        assert!(server.trace_started.is_none());
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || LogWriter(logs.clone())
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server_fut = async {
+            server.read_hello().await.unwrap();
+            server.write_auth_ok().await.unwrap();
+            server.answer_ping().await.unwrap();
+        };
+
+        let client_fut = async {
+            let ret = client.do_hello(token('a')).await.unwrap();
+            client.ping().await.unwrap();
+            ret
+        };
+
+        let (ret, _) = tokio::join!(client_fut, server_fut);
+        assert!(matches!(ret, HelloResult::Accepted));
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = logs.lines().collect();
+        // Only the client traces
+        assert_eq!(lines.len(), 5, "{logs}");
+        for (line, expected) in lines.iter().zip([
+            r#"direction="write" kind="Hello""#,
+            r#"direction="read" kind=ServerVersion"#,
+            r#"direction="read" kind=AuthOk"#,
+            r#"direction="write" kind=Ping"#,
+            r#"direction="read" kind=Pong"#,
+        ]) {
+            assert!(line.contains("Proxy protocol"), "{line}");
+            assert!(line.contains(expected), "{line}");
+        }
+        assert!(!logs.contains(token('a').expose_secret().as_str()));
+    }
+
+    struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_rejected() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        let reason = AuthFailedReason {
+            code: AuthFailedCode::TokenExpired,
+            message: Some("Expired yesterday".into()),
+        };
+
+        let server_fut = async {
+            server.read_hello().await.unwrap();
+            server.write_auth_failed(&reason).await.unwrap();
+        };
+
+        let (ret, _) = tokio::join!(client.do_hello(token('a')), server_fut);
+        match ret {
+            Ok(HelloResult::Rejected(Some(val))) => assert_eq!(val, reason),
+            val => panic!("Unexpected hello result {val:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_await_data() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        let server_fut = async {
+            server
+                .write_message(ProxyConnectionMessage::Ping)
+                .await
+                .unwrap();
+            assert_eq!(
+                server.read_message().await.unwrap(),
+                ProxyConnectionMessage::Pong
+            );
+            server.write_token_rotate(&token('b')).await.unwrap();
+            server
+                .write_message(ProxyConnectionMessage::DataVscode)
+                .await
+                .unwrap();
+            server.write_data_forward("grafana").await.unwrap();
+        };
+
+        let client_fut = async {
+            let first = client.await_data(Duration::from_secs(5)).await.unwrap();
+            let second = client.await_data(Duration::from_secs(5)).await.unwrap();
+            let third = client.await_data(Duration::from_secs(5)).await.unwrap();
+            (first, second, third)
+        };
+
+        let ((first, second, third), _) = tokio::join!(client_fut, server_fut);
+
+        match first {
+            ProxyEvent::TokenRotated(val) => {
+                assert_eq!(val.expose_secret(), token('b').expose_secret())
+            }
+            val => panic!("Unexpected event {val:?}"),
+        }
+        assert!(matches!(
+            second,
+            ProxyEvent::Data(ProxyConnectionMessage::DataVscode)
+        ));
+        assert!(matches!(third, ProxyEvent::Forward(val) if val == "grafana"));
+    }
+
+    #[tokio::test]
+    async fn test_await_data_errors() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProxyConnection::new(client);
+        let mut server = ProxyConnection::new(server);
+
+        // Nothing arrives
+        let ret = client.await_data(Duration::from_millis(50)).await;
+        assert!(matches!(ret, Err(ProtocolError::Io(_))));
+
+        server
+            .write_message(ProxyConnectionMessage::AuthOk)
+            .await
+            .unwrap();
+        let ret = client.await_data(Duration::from_secs(5)).await;
+        assert!(matches!(
+            ret,
+            Err(ProtocolError::UnexpectedMessage(
+                ProxyConnectionMessage::AuthOk
+            ))
+        ));
+
+        drop(server);
+        let ret = client.await_data(Duration::from_secs(5)).await;
+        assert!(matches!(ret, Err(ProtocolError::Truncated)));
+    }
+}